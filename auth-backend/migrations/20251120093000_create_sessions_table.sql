@@ -0,0 +1,20 @@
+-- Add migration script here
+
+-- 每签发一个 refresh token 就记录一条 session
+-- family_id 把同一次登录派生出来的所有 refresh token 串在一起，
+-- 一旦发现已轮换的 token 被再次使用，就吊销整个 family
+CREATE TABLE sessions (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  family_id UUID NOT NULL,
+  jti UUID NOT NULL UNIQUE,
+  token_hash TEXT NOT NULL,
+  device_info TEXT,
+  expires_at TIMESTAMPTZ NOT NULL,
+  rotated_at TIMESTAMPTZ,
+  revoked_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX idx_sessions_user_id ON sessions (user_id);
+CREATE INDEX idx_sessions_family_id ON sessions (family_id);
@@ -0,0 +1,6 @@
+-- Add migration script here
+
+-- access token 中携带签发时的 token_version，
+-- "登出所有设备" 时把它加一，之前签发的 access token 立即失效
+ALTER TABLE users
+ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0;
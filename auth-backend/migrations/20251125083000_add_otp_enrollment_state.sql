@@ -0,0 +1,13 @@
+-- Add migration script here
+
+-- OTP 注册状态机：pending → verified → enabled
+-- 新生成的密钥先放在 pending 列中，只有 /auth/otp/verify 成功后才会替换当前生效的密钥
+ALTER TABLE users
+ADD COLUMN otp_pending_base32 TEXT,
+ADD COLUMN otp_pending_auth_url TEXT,
+ADD COLUMN otp_pending_expires_at TIMESTAMPTZ;
+
+-- 旧逻辑在生成密钥时就把 otp_enabled 置为 true，未验证的密钥不能算作已启用
+UPDATE users
+SET otp_enabled = FALSE, otp_base32 = NULL, otp_auth_url = NULL
+WHERE otp_enabled = TRUE AND otp_verified IS NOT TRUE;
@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- 记录会话建立时使用的认证方式，轮换 refresh token 时原样带到新的 access token 中
+ALTER TABLE sessions
+ADD COLUMN amr TEXT[] NOT NULL DEFAULT ARRAY['pwd', 'mfa'];
@@ -0,0 +1,12 @@
+-- Add migration script here
+
+-- MFA 恢复码：OTP 验证成功时生成一组，只保存哈希，每个只能使用一次
+CREATE TABLE mfa_recovery_codes (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  code_hash TEXT NOT NULL,
+  used_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX idx_mfa_recovery_codes_user_id ON mfa_recovery_codes (user_id);
@@ -0,0 +1,26 @@
+-- Add migration script here
+
+-- 用户注册的 WebAuthn 凭证（平台验证器 / 漫游验证器）
+-- passkey 为 webauthn-rs 序列化后的凭证，包含公钥和签名计数器
+CREATE TABLE webauthn_credentials (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  credential_id TEXT NOT NULL UNIQUE,
+  passkey JSONB NOT NULL,
+  name TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_webauthn_credentials_user_id ON webauthn_credentials (user_id);
+
+-- 注册 / 认证仪式的服务端状态（challenge），finish 时取出并删除
+-- 无密码登录开始时还不知道用户是谁，所以 user_id 可以为空
+CREATE TABLE webauthn_ceremonies (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+  kind TEXT NOT NULL,
+  state JSONB NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
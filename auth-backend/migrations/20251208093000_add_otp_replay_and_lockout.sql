@@ -0,0 +1,16 @@
+-- Add migration script here
+
+-- 记录最近一次被接受的 TOTP 时间步，同一个（或更早的）验证码不能再次使用
+ALTER TABLE users ADD COLUMN otp_last_used_step BIGINT;
+
+-- 按用户统计连续失败的 OTP 次数，超过阈值后按指数退避锁定
+ALTER TABLE users ADD COLUMN failed_otp_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN otp_locked_until TIMESTAMPTZ;
+
+-- 按 mfa token (jti) 统计失败次数，单个 mfa token 的猜测次数有上限
+CREATE TABLE mfa_token_attempts (
+  jti UUID PRIMARY KEY,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  expires_at TIMESTAMPTZ NOT NULL
+);
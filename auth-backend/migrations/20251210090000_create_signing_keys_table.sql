@@ -0,0 +1,13 @@
+-- Add migration script here
+
+-- JWT 签名密钥（JWT_KEY_SOURCE=database 时使用）
+-- activated_at 之后成为当前签名密钥；在此之前已经通过 JWKS 发布，方便下游提前缓存
+-- 被新密钥取代后仍可用于验证，直到 retired_at
+CREATE TABLE signing_keys (
+  kid TEXT PRIMARY KEY,
+  algorithm TEXT NOT NULL,
+  private_key_pem TEXT NOT NULL,
+  activated_at TIMESTAMPTZ NOT NULL,
+  retired_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- 用户实际完成认证的时间，轮换 refresh token 时保持不变，对应 access token 中的 auth_time
+ALTER TABLE sessions ADD COLUMN auth_time TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- 已有的会话按同一 family 中最早的记录近似
+UPDATE sessions s
+SET auth_time = f.first_created_at
+FROM (
+  SELECT family_id, MIN(created_at) AS first_created_at
+  FROM sessions
+  GROUP BY family_id
+) f
+WHERE s.family_id = f.family_id AND f.first_created_at IS NOT NULL;
@@ -0,0 +1,47 @@
+-- Add migration script here
+
+-- 注册的 OAuth 客户端（第三方应用）
+-- redirect_uris 必须与授权请求中的 redirect_uri 完全一致
+CREATE TABLE oauth_clients (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  client_id TEXT NOT NULL UNIQUE,
+  name TEXT NOT NULL,
+  redirect_uris TEXT[] NOT NULL DEFAULT '{}',
+  allowed_scopes TEXT[] NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- /oauth/authorize 收到的授权请求，等待用户在登录页完成认证并同意
+CREATE TABLE oauth_authorization_requests (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  client_id TEXT NOT NULL REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  state TEXT,
+  code_challenge TEXT NOT NULL,
+  code_challenge_method TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- 授权码只保存哈希，只能使用一次
+-- family_id 为用授权码换取的会话标识，授权码被重复使用时据此吊销已签发的令牌
+CREATE TABLE oauth_authorization_codes (
+  code_hash TEXT PRIMARY KEY,
+  client_id TEXT NOT NULL REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  code_challenge TEXT NOT NULL,
+  code_challenge_method TEXT NOT NULL,
+  amr TEXT[] NOT NULL,
+  auth_time TIMESTAMPTZ NOT NULL,
+  family_id UUID NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- 通过 OAuth 签发的会话记录所属客户端和授权范围，刷新时保持不变
+ALTER TABLE sessions ADD COLUMN client_id TEXT;
+ALTER TABLE sessions ADD COLUMN scope TEXT;
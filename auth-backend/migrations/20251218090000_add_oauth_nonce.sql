@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- OpenID Connect：授权请求中的 nonce 原样写入 id_token，客户端据此防止重放
+ALTER TABLE oauth_authorization_requests ADD COLUMN nonce TEXT;
+ALTER TABLE oauth_authorization_codes ADD COLUMN nonce TEXT;
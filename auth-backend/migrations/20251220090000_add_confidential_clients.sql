@@ -0,0 +1,22 @@
+-- Add migration script here
+
+-- 客户端在令牌端点的认证方式：
+-- none（公开客户端，只能使用 PKCE）、client_secret_basic / client_secret_post、private_key_jwt
+ALTER TABLE oauth_clients ADD COLUMN token_endpoint_auth_method TEXT NOT NULL DEFAULT 'none';
+-- 客户端密钥只保存 SHA-256 摘要（十六进制），例如：
+-- UPDATE oauth_clients SET client_secret_hash = encode(sha256('<secret>'), 'hex') WHERE ...
+ALTER TABLE oauth_clients ADD COLUMN client_secret_hash TEXT;
+-- private_key_jwt 使用的客户端公钥，JWKS 格式
+ALTER TABLE oauth_clients ADD COLUMN jwks JSONB;
+-- 客户端可以使用的授权类型，client_credentials 需要显式开启
+ALTER TABLE oauth_clients ADD COLUMN grant_types TEXT[] NOT NULL DEFAULT '{authorization_code,refresh_token}';
+-- client_credentials 令牌可以使用的 aud（通过 resource 参数选择，RFC 8707）
+ALTER TABLE oauth_clients ADD COLUMN allowed_audiences TEXT[] NOT NULL DEFAULT '{}';
+
+-- 已使用过的 private_key_jwt 断言，防止断言被截获后重放，过期后可以删除
+CREATE TABLE oauth_client_assertions (
+  client_id TEXT NOT NULL REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+  jti TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY (client_id, jti)
+);
@@ -0,0 +1,21 @@
+-- Add migration script here
+
+-- RFC 8628 设备授权：CLI 轮询 device_code，用户在浏览器中输入 user_code 并同意
+-- device_code 只保存哈希；user_code 保存去掉分隔符的大写形式
+CREATE TABLE oauth_device_codes (
+  device_code_hash TEXT PRIMARY KEY,
+  user_code TEXT NOT NULL UNIQUE,
+  client_id TEXT NOT NULL REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+  scope TEXT NOT NULL,
+  -- pending / approved / denied
+  status TEXT NOT NULL DEFAULT 'pending',
+  -- 用户同意后记录用户和本次登录的认证方式
+  user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+  amr TEXT[],
+  auth_time TIMESTAMPTZ,
+  -- 最小轮询间隔（秒），客户端轮询过快时增加
+  interval_seconds INT NOT NULL,
+  last_polled_at TIMESTAMPTZ,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
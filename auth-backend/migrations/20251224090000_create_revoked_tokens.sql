@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- 通过 /oauth/revoke 吊销的 access token（按 jti），令牌过期后即可删除
+-- refresh token 的吊销直接记录在 sessions 表中
+CREATE TABLE revoked_tokens (
+  jti TEXT PRIMARY KEY,
+  expires_at TIMESTAMPTZ NOT NULL,
+  revoked_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
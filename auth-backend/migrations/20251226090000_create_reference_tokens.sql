@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- 引用令牌（不透明令牌）：令牌本身是随机字符串，声明保存在服务端，只保存令牌哈希
+-- access token 和 refresh token 共用这张表，按 claims 中的 aud 区分
+CREATE TABLE reference_tokens (
+  token_hash TEXT PRIMARY KEY,
+  claims JSONB NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- 客户端单独选择令牌格式：jwt 或 reference，为空时使用全局配置 TOKEN_FORMAT
+ALTER TABLE oauth_clients ADD COLUMN token_format TEXT
+  CHECK (token_format IN ('jwt', 'reference'));
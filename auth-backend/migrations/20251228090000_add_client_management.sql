@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- 客户端管理：每个客户端可以单独配置令牌有效期、要求的认证强度和允许的 CORS 来源
+-- 有效期为空时使用全局配置（ACCESS_TOKEN_TTL_SECONDS 等）
+ALTER TABLE oauth_clients ADD COLUMN access_token_ttl_seconds INTEGER
+  CHECK (access_token_ttl_seconds > 0);
+ALTER TABLE oauth_clients ADD COLUMN refresh_token_ttl_seconds INTEGER
+  CHECK (refresh_token_ttl_seconds > 0);
+-- 同意授权时登录的 acr 至少达到该级别：pwd < mfa < phr < phrh，为空时不额外要求
+ALTER TABLE oauth_clients ADD COLUMN required_acr TEXT
+  CHECK (required_acr IN ('pwd', 'mfa', 'phr', 'phrh'));
+-- 浏览器中的客户端直接调用令牌端点等接口时需要的 CORS 来源，如 https://app.example.com
+ALTER TABLE oauth_clients ADD COLUMN allowed_origins TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE oauth_clients ADD COLUMN updated_at TIMESTAMPTZ DEFAULT NOW();
@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- 注册时 attestation 经受信任的厂商 CA 验证过的安全密钥，私钥确认封存在硬件中
+ALTER TABLE webauthn_credentials ADD COLUMN attested BOOLEAN NOT NULL DEFAULT false;
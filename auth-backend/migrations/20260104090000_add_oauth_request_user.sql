@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- 授权请求创建时用户还没有登录，第一个同意或拒绝它的登录用户即为请求的所有者
+ALTER TABLE oauth_authorization_requests ADD COLUMN user_id UUID REFERENCES users(id) ON DELETE CASCADE;
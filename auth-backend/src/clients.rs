@@ -0,0 +1,657 @@
+use std::{
+    collections::HashSet,
+    env,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, delete, get,
+    http::{StatusCode, header},
+    post, put, rt, web,
+};
+use jsonwebtoken::jwk::JwkSet;
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    extractors::bearer_token,
+    introspection::INTROSPECT_SCOPE,
+    models::{ApiResponse, OAuthClient, OAuthClientData, OAuthClientMetadata},
+    oauth::{OAuthError, SUPPORTED_GRANT_TYPES},
+    oidc::bearer_error,
+    repositories::UserRepository,
+    utils::{ACR_LEVELS, TokenFormat, constant_time_eq, generate_random_token, hash_token},
+};
+
+const TOKEN_ENDPOINT_AUTH_METHODS: [&str; 4] = [
+    "none",
+    "client_secret_basic",
+    "client_secret_post",
+    "private_key_jwt",
+];
+// 多实例部署时，其他实例修改的 CORS 来源最迟在这段时间后生效
+const ORIGIN_REFRESH_MINUTES: u64 = 5;
+
+// 所有客户端登记的 CORS 来源；CORS 检查是同步的，不能每次查询数据库
+static CLIENT_ORIGINS: OnceLock<RwLock<HashSet<String>>> = OnceLock::new();
+
+fn client_origins() -> &'static RwLock<HashSet<String>> {
+    CLIENT_ORIGINS.get_or_init(Default::default)
+}
+
+pub async fn reload_origins(repo: &dyn UserRepository) -> anyhow::Result<()> {
+    let origins = repo.list_oauth_client_origins().await?;
+    *client_origins().write().unwrap() = origins.into_iter().collect();
+    Ok(())
+}
+
+pub fn is_allowed_origin(origin: &header::HeaderValue) -> bool {
+    origin
+        .to_str()
+        .is_ok_and(|origin| client_origins().read().unwrap().contains(origin))
+}
+
+pub fn spawn_origin_refresh(repo: Arc<dyn UserRepository>) {
+    rt::spawn(async move {
+        let mut interval =
+            rt::time::interval(std::time::Duration::from_secs(ORIGIN_REFRESH_MINUTES * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = reload_origins(repo.as_ref()).await {
+                println!("⚠️ 刷新客户端 CORS 来源失败: {e:#}");
+            }
+        }
+    });
+}
+
+// 客户端变更后立即更新本实例的 CORS 来源
+async fn origins_changed(repo: &dyn UserRepository) {
+    if let Err(e) = reload_origins(repo).await {
+        println!("⚠️ 刷新客户端 CORS 来源失败: {e:#}");
+    }
+}
+
+// CORS 来源只能是 scheme://host[:port]，不能带路径
+fn valid_origin(origin: &str) -> bool {
+    Url::parse(origin).is_ok_and(|url| {
+        let serialized = url.origin().ascii_serialization();
+        url.origin().is_tuple() && serialized == origin
+    })
+}
+
+// redirect_uri 必须是不带 fragment 的绝对地址（RFC 6749 3.1.2）
+// 原生应用可以使用自定义 scheme
+fn valid_redirect_uri(uri: &str) -> bool {
+    Url::parse(uri).is_ok_and(|url| url.fragment().is_none() && !url.cannot_be_a_base())
+}
+
+fn dedup(values: Vec<String>) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for value in values {
+        if !result.contains(&value) {
+            result.push(value);
+        }
+    }
+    result
+}
+
+// 校验元数据并构造客户端；existing 为更新前的客户端
+// 使用共享密钥的客户端沿用原来的密钥，新建或从其他认证方式切换过来时生成新的 client_secret
+fn build_client(
+    client_id: String,
+    metadata: OAuthClientMetadata,
+    existing: Option<&OAuthClient>,
+) -> Result<(OAuthClient, Option<String>), OAuthError> {
+    let name = metadata.client_name.trim().to_string();
+    if name.is_empty() {
+        return Err(OAuthError::InvalidClientMetadata("client_name is required"));
+    }
+
+    // 默认值与 RFC 7591 2 一致
+    let grant_types = dedup(metadata.grant_types.unwrap_or_else(|| {
+        vec![
+            "authorization_code".to_string(),
+            "refresh_token".to_string(),
+        ]
+    }));
+    if grant_types.is_empty()
+        || grant_types
+            .iter()
+            .any(|grant_type| !SUPPORTED_GRANT_TYPES.contains(&grant_type.as_str()))
+    {
+        return Err(OAuthError::InvalidClientMetadata("Unsupported grant_types"));
+    }
+    let auth_method = metadata
+        .token_endpoint_auth_method
+        .unwrap_or_else(|| "client_secret_basic".to_string());
+    if !TOKEN_ENDPOINT_AUTH_METHODS.contains(&auth_method.as_str()) {
+        return Err(OAuthError::InvalidClientMetadata(
+            "Unsupported token_endpoint_auth_method",
+        ));
+    }
+
+    let redirect_uris = dedup(metadata.redirect_uris);
+    if !redirect_uris.iter().all(|uri| valid_redirect_uri(uri)) {
+        return Err(OAuthError::InvalidRedirectUri("Invalid redirect_uri"));
+    }
+    let has_grant = |value: &str| grant_types.iter().any(|grant_type| grant_type == value);
+    if has_grant("authorization_code") && redirect_uris.is_empty() {
+        return Err(OAuthError::InvalidRedirectUri(
+            "authorization_code requires at least one redirect_uri",
+        ));
+    }
+    if has_grant("client_credentials") {
+        if auth_method == "none" {
+            return Err(OAuthError::InvalidClientMetadata(
+                "client_credentials requires client authentication",
+            ));
+        }
+        if metadata.allowed_audiences.is_empty() {
+            return Err(OAuthError::InvalidClientMetadata(
+                "client_credentials requires allowed_audiences",
+            ));
+        }
+    }
+
+    let valid_jwks = metadata
+        .jwks
+        .as_ref()
+        .is_some_and(|jwks| serde_json::from_value::<JwkSet>(jwks.clone()).is_ok());
+    if auth_method == "private_key_jwt" && !valid_jwks {
+        return Err(OAuthError::InvalidClientMetadata(
+            "private_key_jwt requires a valid jwks",
+        ));
+    }
+    if metadata
+        .token_format
+        .as_deref()
+        .is_some_and(|format| TokenFormat::parse(format).is_none())
+    {
+        return Err(OAuthError::InvalidClientMetadata(
+            "token_format must be jwt or reference",
+        ));
+    }
+    let positive = |seconds: Option<i32>| seconds.is_none_or(|seconds| seconds > 0);
+    if !positive(metadata.access_token_ttl_seconds) || !positive(metadata.refresh_token_ttl_seconds)
+    {
+        return Err(OAuthError::InvalidClientMetadata(
+            "Token lifetimes must be positive",
+        ));
+    }
+    if metadata
+        .required_acr
+        .as_deref()
+        .is_some_and(|acr| !ACR_LEVELS.contains(&acr))
+    {
+        return Err(OAuthError::InvalidClientMetadata(
+            "required_acr must be one of pwd, mfa, phr, phrh",
+        ));
+    }
+    let allowed_origins = dedup(metadata.allowed_origins);
+    if !allowed_origins.iter().all(|origin| valid_origin(origin)) {
+        return Err(OAuthError::InvalidClientMetadata(
+            "allowed_origins must be origins like https://app.example.com",
+        ));
+    }
+
+    let uses_secret = matches!(
+        auth_method.as_str(),
+        "client_secret_basic" | "client_secret_post"
+    );
+    let (client_secret, client_secret_hash) = match existing {
+        _ if !uses_secret => (None, None),
+        Some(existing) if existing.client_secret_hash.is_some() => {
+            (None, existing.client_secret_hash.clone())
+        }
+        _ => {
+            let secret = generate_random_token();
+            let hash = hash_token(&secret);
+            (Some(secret), Some(hash))
+        }
+    };
+
+    let client = OAuthClient {
+        client_id,
+        name,
+        redirect_uris,
+        allowed_scopes: dedup(
+            metadata
+                .scope
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        ),
+        token_endpoint_auth_method: auth_method,
+        client_secret_hash,
+        jwks: metadata.jwks,
+        grant_types,
+        allowed_audiences: dedup(metadata.allowed_audiences),
+        token_format: metadata.token_format,
+        access_token_ttl_seconds: metadata.access_token_ttl_seconds,
+        refresh_token_ttl_seconds: metadata.refresh_token_ttl_seconds,
+        required_acr: metadata.required_acr,
+        allowed_origins,
+        created_at: existing.and_then(|existing| existing.created_at),
+    };
+    Ok((client, client_secret))
+}
+
+// CORS 来源、令牌格式、有效期、可访问的 audience 和 required_acr 只能通过管理接口设置：
+// 动态注册的客户端提交这些字段时忽略，使用服务器默认值（返回的元数据反映实际登记的值，RFC 7591 3.2.1）
+// client_credentials 令牌不经过用户授权、introspect scope 可以查看其他客户端的令牌，都只能由管理员登记
+fn without_admin_fields(metadata: OAuthClientMetadata) -> Result<OAuthClientMetadata, OAuthError> {
+    if metadata
+        .grant_types
+        .iter()
+        .flatten()
+        .any(|grant_type| grant_type == "client_credentials")
+    {
+        return Err(OAuthError::InvalidClientMetadata(
+            "client_credentials cannot be registered dynamically",
+        ));
+    }
+
+    let scope = metadata.scope.as_deref().map(|scope| {
+        scope
+            .split_whitespace()
+            .filter(|scope| *scope != INTROSPECT_SCOPE)
+            .collect::<Vec<_>>()
+            .join(" ")
+    });
+    Ok(OAuthClientMetadata {
+        scope,
+        allowed_audiences: Vec::new(),
+        required_acr: None,
+        allowed_origins: Vec::new(),
+        token_format: None,
+        access_token_ttl_seconds: None,
+        refresh_token_ttl_seconds: None,
+        ..metadata
+    })
+}
+
+fn client_data(client: OAuthClient, client_secret: Option<String>) -> OAuthClientData {
+    OAuthClientData {
+        client_id: client.client_id,
+        client_secret_expires_at: client_secret.as_ref().map(|_| 0),
+        client_secret,
+        client_id_issued_at: client.created_at.map(|created_at| created_at.timestamp()),
+        client_name: client.name,
+        redirect_uris: client.redirect_uris,
+        grant_types: client.grant_types,
+        token_endpoint_auth_method: client.token_endpoint_auth_method,
+        scope: client.allowed_scopes.join(" "),
+        jwks: client.jwks,
+        allowed_audiences: client.allowed_audiences,
+        token_format: client.token_format,
+        access_token_ttl_seconds: client.access_token_ttl_seconds,
+        refresh_token_ttl_seconds: client.refresh_token_ttl_seconds,
+        required_acr: client.required_acr,
+        allowed_origins: client.allowed_origins,
+    }
+}
+
+async fn create_client(
+    repo: &dyn UserRepository,
+    metadata: OAuthClientMetadata,
+) -> Result<OAuthClientData, OAuthError> {
+    let client_id = Uuid::new_v4().simple().to_string();
+    let (client, client_secret) = build_client(client_id, metadata, None)?;
+    repo.create_oauth_client(&client)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to create client"))?;
+    origins_changed(repo).await;
+
+    // 重新读取以带上数据库生成的 created_at
+    let client = match repo.get_oauth_client(&client.client_id).await {
+        Ok(Some(created)) => created,
+        _ => client,
+    };
+    Ok(client_data(client, client_secret))
+}
+
+// 管理接口使用 ADMIN_API_TOKEN 认证（Authorization: Bearer ...），未配置时关闭
+fn require_admin(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let Ok(expected) = env::var("ADMIN_API_TOKEN") else {
+        return Err(HttpResponse::Forbidden().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Admin API is disabled".to_string(),
+            data: None,
+        }));
+    };
+    match bearer_token(req) {
+        Ok(token) if !expected.is_empty() && constant_time_eq(&token, &expected) => Ok(()),
+        _ => Err(HttpResponse::Unauthorized().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Invalid admin token".to_string(),
+            data: None,
+        })),
+    }
+}
+
+fn metadata_error(e: OAuthError) -> HttpResponse {
+    let status = match e {
+        OAuthError::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    HttpResponse::build(status).json(ApiResponse::<()> {
+        status: "error".to_string(),
+        message: e.to_string(),
+        data: None,
+    })
+}
+
+fn client_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse::<()> {
+        status: "error".to_string(),
+        message: "Client not found".to_string(),
+        data: None,
+    })
+}
+
+async fn load_client(
+    repo: &dyn UserRepository,
+    client_id: &str,
+) -> Result<OAuthClient, HttpResponse> {
+    match repo.get_oauth_client(client_id).await {
+        Ok(Some(client)) => Ok(client),
+        Ok(None) => Err(client_not_found()),
+        Err(_) => Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to load client".to_string(),
+            data: None,
+        })),
+    }
+}
+
+// client_secret 只在这次响应中返回，之后只能轮换
+#[post("/admin/clients")]
+async fn admin_create_client(
+    req: HttpRequest,
+    data: web::Json<OAuthClientMetadata>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&req) {
+        return resp;
+    }
+    match create_client(repo.get_ref(), data.into_inner()).await {
+        Ok(client) => HttpResponse::Created().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Client created".to_string(),
+            data: Some(client),
+        }),
+        Err(e) => metadata_error(e),
+    }
+}
+
+#[get("/admin/clients")]
+async fn admin_list_clients(
+    req: HttpRequest,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&req) {
+        return resp;
+    }
+    match repo.list_oauth_clients().await {
+        Ok(clients) => HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Clients fetched".to_string(),
+            data: Some(json!({
+                "clients": clients
+                    .into_iter()
+                    .map(|client| client_data(client, None))
+                    .collect::<Vec<_>>(),
+            })),
+        }),
+        Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to list clients".to_string(),
+            data: None,
+        }),
+    }
+}
+
+#[get("/admin/clients/{client_id}")]
+async fn admin_get_client(
+    req: HttpRequest,
+    path: web::Path<String>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&req) {
+        return resp;
+    }
+    match load_client(repo.get_ref(), &path).await {
+        Ok(client) => HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Client fetched".to_string(),
+            data: Some(client_data(client, None)),
+        }),
+        Err(resp) => resp,
+    }
+}
+
+// 整体替换客户端元数据；切换为共享密钥认证时会生成新的 client_secret
+#[put("/admin/clients/{client_id}")]
+async fn admin_update_client(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Json<OAuthClientMetadata>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&req) {
+        return resp;
+    }
+    let existing = match load_client(repo.get_ref(), &path).await {
+        Ok(client) => client,
+        Err(resp) => return resp,
+    };
+    let (client, client_secret) = match build_client(
+        existing.client_id.clone(),
+        data.into_inner(),
+        Some(&existing),
+    ) {
+        Ok(built) => built,
+        Err(e) => return metadata_error(e),
+    };
+
+    match repo.update_oauth_client(&client).await {
+        Ok(true) => {
+            origins_changed(repo.get_ref()).await;
+            HttpResponse::Ok().json(ApiResponse {
+                status: "success".to_string(),
+                message: "Client updated".to_string(),
+                data: Some(client_data(client, client_secret)),
+            })
+        }
+        Ok(false) => client_not_found(),
+        Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to update client".to_string(),
+            data: None,
+        }),
+    }
+}
+
+// 生成新的 client_secret，旧的立即失效
+#[post("/admin/clients/{client_id}/secret")]
+async fn admin_rotate_client_secret(
+    req: HttpRequest,
+    path: web::Path<String>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&req) {
+        return resp;
+    }
+    let mut client = match load_client(repo.get_ref(), &path).await {
+        Ok(client) => client,
+        Err(resp) => return resp,
+    };
+    if client.client_secret_hash.is_none() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Client does not use a client secret".to_string(),
+            data: None,
+        });
+    }
+
+    let client_secret = generate_random_token();
+    client.client_secret_hash = Some(hash_token(&client_secret));
+    match repo.update_oauth_client(&client).await {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Client secret rotated".to_string(),
+            data: Some(client_data(client, Some(client_secret))),
+        }),
+        Ok(false) => client_not_found(),
+        Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to rotate client secret".to_string(),
+            data: None,
+        }),
+    }
+}
+
+// 删除客户端，通过它签发的会话一并吊销
+#[delete("/admin/clients/{client_id}")]
+async fn admin_delete_client(
+    req: HttpRequest,
+    path: web::Path<String>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_admin(&req) {
+        return resp;
+    }
+    match repo.delete_oauth_client(&path).await {
+        Ok(true) => {
+            origins_changed(repo.get_ref()).await;
+            HttpResponse::Ok().json(ApiResponse::<()> {
+                status: "success".to_string(),
+                message: "Client deleted".to_string(),
+                data: None,
+            })
+        }
+        Ok(false) => client_not_found(),
+        Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to delete client".to_string(),
+            data: None,
+        }),
+    }
+}
+
+// 动态客户端注册（RFC 7591），需要 OAUTH_INITIAL_ACCESS_TOKEN 作为初始访问令牌
+// 未配置时不开放注册
+#[post("/oauth/register")]
+async fn register_client(
+    req: HttpRequest,
+    data: web::Json<OAuthClientMetadata>,
+    repo: web::Data<dyn UserRepository>,
+) -> Result<HttpResponse, OAuthError> {
+    let authorized = match (env::var("OAUTH_INITIAL_ACCESS_TOKEN"), bearer_token(&req)) {
+        (Ok(expected), Ok(token)) => !expected.is_empty() && constant_time_eq(&token, &expected),
+        _ => false,
+    };
+    if !authorized {
+        return Ok(bearer_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_token",
+            "A valid initial access token is required",
+        ));
+    }
+
+    let client = create_client(repo.get_ref(), without_admin_fields(data.into_inner())?).await?;
+    Ok(HttpResponse::Created()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(client))
+}
+
+// /oauth/register 挂在根路径下
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(register_client);
+}
+
+// 管理接口挂在 /api 下
+pub fn api_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(admin_create_client)
+        .service(admin_list_clients)
+        .service(admin_get_client)
+        .service(admin_update_client)
+        .service(admin_rotate_client_secret)
+        .service(admin_delete_client);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::App;
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::test_support::repository;
+
+    const INITIAL_ACCESS_TOKEN: &str = "initial-access-token";
+
+    #[test]
+    fn dynamic_registration_ignores_admin_only_fields() {
+        let metadata: OAuthClientMetadata = serde_json::from_value(json!({
+            "client_name": "Third party",
+            "redirect_uris": ["https://app.example.com/callback"],
+            "token_endpoint_auth_method": "none",
+            "allowed_origins": ["https://app.example.com"],
+            "token_format": "reference",
+            "access_token_ttl_seconds": 31536000,
+            "refresh_token_ttl_seconds": 31536000,
+            "allowed_audiences": ["https://api.example.com"],
+            "required_acr": "pwd",
+            "scope": "read introspect",
+        }))
+        .unwrap();
+
+        let metadata = without_admin_fields(metadata).unwrap();
+        let (client, _) = build_client("client".to_string(), metadata, None).unwrap();
+        assert!(client.allowed_origins.is_empty());
+        assert!(client.allowed_audiences.is_empty());
+        assert!(client.required_acr.is_none());
+        assert!(client.token_format.is_none());
+        assert!(client.access_token_ttl_seconds.is_none());
+        assert!(client.refresh_token_ttl_seconds.is_none());
+        assert_eq!(client.allowed_scopes, ["read"]);
+        assert_eq!(client.redirect_uris, ["https://app.example.com/callback"]);
+    }
+
+    #[sqlx::test]
+    async fn dynamic_registration_rejects_client_credentials(pool: PgPool) {
+        // SAFETY: 测试中只有这里读写该变量，且每次写入的值相同
+        unsafe { env::set_var("OAUTH_INITIAL_ACCESS_TOKEN", INITIAL_ACCESS_TOKEN) };
+        let repo = repository(pool.clone());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/oauth/register")
+            .insert_header(("Authorization", format!("Bearer {INITIAL_ACCESS_TOKEN}")))
+            .set_json(json!({
+                "client_name": "Backend",
+                "grant_types": ["client_credentials"],
+                "token_endpoint_auth_method": "client_secret_basic",
+                "allowed_audiences": ["https://api.example.com"],
+            }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_client_metadata");
+
+        let clients: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM oauth_clients")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(clients, 0);
+    }
+}
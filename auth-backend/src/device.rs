@@ -0,0 +1,469 @@
+use std::env;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, ResponseError, get, http::header, post, web,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+
+use crate::{
+    extractors::{AuthError, AuthenticatedUser},
+    models::{
+        ApiResponse, OAuthClient, OAuthDeviceAuthorizationRequest,
+        OAuthDeviceAuthorizationResponse, OAuthDeviceCode, OAuthDeviceVerifySchema,
+        OAuthRequestData, OAuthTokenRequest, SessionGrant, User,
+    },
+    oauth::{OAuthError, authenticate_client, check_required_acr, frontend_url, resolve_scope},
+    repositories::UserRepository,
+    utils::{generate_random_token, hash_token},
+};
+
+pub const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+// 用户需要在这段时间内完成授权
+const DEVICE_CODE_MINUTES: i64 = 10;
+// 客户端两次轮询之间的最小间隔，slow_down 时每次增加 5 秒
+const DEVICE_POLL_INTERVAL_SECONDS: i32 = 5;
+// user_code 只使用不易混淆的大写辅音字母（RFC 8628 6.1）
+const USER_CODE_CHARSET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+fn generate_user_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..8)
+        .map(|_| USER_CODE_CHARSET[rng.gen_range(0..USER_CODE_CHARSET.len())] as char)
+        .collect()
+}
+
+// 用户输入时可能带分隔符、空格或小写，统一成数据库中保存的形式
+fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// 展示给用户的形式：XXXX-XXXX
+fn display_user_code(user_code: &str) -> String {
+    let (head, tail) = user_code.split_at(user_code.len() / 2);
+    format!("{head}-{tail}")
+}
+
+// 设备授权端点：CLI 获取 device_code 和 user_code，提示用户在浏览器中打开 verification_uri
+#[post("/oauth/device_authorization")]
+async fn device_authorization(
+    req: HttpRequest,
+    form: web::Form<OAuthDeviceAuthorizationRequest>,
+    repo: web::Data<dyn UserRepository>,
+) -> Result<HttpResponse, OAuthError> {
+    let client = authenticate_client(&req, &form.client, repo.get_ref()).await?;
+    if !client
+        .grant_types
+        .iter()
+        .any(|grant_type| grant_type == DEVICE_CODE_GRANT_TYPE)
+    {
+        return Err(OAuthError::UnauthorizedClient);
+    }
+    let scope = resolve_scope(&client, form.scope.as_deref()).ok_or(OAuthError::InvalidScope)?;
+
+    let device_code = generate_random_token();
+    let record = OAuthDeviceCode {
+        device_code_hash: hash_token(&device_code),
+        user_code: generate_user_code(),
+        client_id: client.client_id,
+        scope,
+        status: "pending".to_string(),
+        user_id: None,
+        amr: None,
+        auth_time: None,
+        interval_seconds: DEVICE_POLL_INTERVAL_SECONDS,
+        last_polled_at: None,
+        expires_at: Utc::now() + Duration::minutes(DEVICE_CODE_MINUTES),
+    };
+    repo.create_oauth_device_code(&record)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to create device code"))?;
+
+    let verification_uri = env::var("OAUTH_DEVICE_VERIFICATION_URL")
+        .unwrap_or_else(|_| "http://localhost:5173/#/device".to_string());
+    let user_code = display_user_code(&record.user_code);
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(OAuthDeviceAuthorizationResponse {
+            device_code,
+            verification_uri_complete: frontend_url(&verification_uri, "user_code", &user_code),
+            verification_uri,
+            user_code,
+            expires_in: Duration::minutes(DEVICE_CODE_MINUTES).num_seconds(),
+            interval: DEVICE_POLL_INTERVAL_SECONDS,
+        }))
+}
+
+// 令牌端点的 device_code 授权：用户同意前返回 authorization_pending，轮询过快返回 slow_down
+pub(crate) async fn exchange_device_code(
+    repo: &dyn UserRepository,
+    client: &OAuthClient,
+    form: &OAuthTokenRequest,
+) -> Result<(User, SessionGrant), OAuthError> {
+    let device_code = form
+        .device_code
+        .as_deref()
+        .ok_or(OAuthError::InvalidRequest("Missing device_code"))?;
+    let device_code_hash = hash_token(device_code);
+    let record = repo
+        .get_oauth_device_code(&device_code_hash)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to load device code"))?
+        .filter(|record| record.client_id == client.client_id)
+        .ok_or(OAuthError::InvalidGrant("Invalid device_code"))?;
+
+    if record.expires_at <= Utc::now() {
+        return Err(OAuthError::ExpiredToken);
+    }
+
+    match record.status.as_str() {
+        "approved" => {}
+        "denied" => return Err(OAuthError::AccessDenied),
+        _ => {
+            let too_fast = record.last_polled_at.is_some_and(|last| {
+                Utc::now() < last + Duration::seconds(record.interval_seconds.into())
+            });
+            let interval = if too_fast {
+                record.interval_seconds + 5
+            } else {
+                record.interval_seconds
+            };
+            repo.record_oauth_device_poll(&device_code_hash, interval)
+                .await
+                .map_err(|_| OAuthError::ServerError("Failed to record device poll"))?;
+            return Err(if too_fast {
+                OAuthError::SlowDown
+            } else {
+                OAuthError::AuthorizationPending
+            });
+        }
+    }
+
+    // 并发轮询时只有一个请求能兑换令牌
+    let taken = repo
+        .take_approved_oauth_device_code(&device_code_hash)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to use device code"))?;
+    let (true, Some(user_id), Some(amr), Some(auth_time)) =
+        (taken, record.user_id, record.amr, record.auth_time)
+    else {
+        return Err(OAuthError::InvalidGrant("Invalid device_code"));
+    };
+
+    let user = repo
+        .get_user_by_id(&user_id)
+        .await
+        .map_err(|_| OAuthError::InvalidGrant("User not found"))?;
+
+    let grant = SessionGrant {
+        amr,
+        auth_time,
+        client_id: Some(record.client_id),
+        scope: Some(record.scope),
+        ..SessionGrant::new(Vec::new())
+    };
+    Ok((user, grant))
+}
+
+// 只有完成了第二因子的登录才能授权设备，MFA 非强制时也是如此
+fn require_mfa(auth: &AuthenticatedUser) -> Result<(), HttpResponse> {
+    let verified = auth
+        .claims
+        .amr
+        .as_ref()
+        .is_some_and(|amr| amr.iter().any(|method| method == "mfa"));
+    if verified {
+        Ok(())
+    } else {
+        Err(AuthError::MfaRequired.error_response())
+    }
+}
+
+async fn load_pending_device_code(
+    repo: &dyn UserRepository,
+    user_code: &str,
+) -> Result<OAuthDeviceCode, HttpResponse> {
+    match repo
+        .get_pending_oauth_device_code(&normalize_user_code(user_code))
+        .await
+    {
+        Ok(Some(record)) => Ok(record),
+        Ok(None) => Err(HttpResponse::NotFound().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Invalid or expired device code".to_string(),
+            data: None,
+        })),
+        Err(_) => Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to load device code".to_string(),
+            data: None,
+        })),
+    }
+}
+
+// 验证页面展示给用户确认：哪个应用、请求哪些权限
+#[get("/oauth/device/{user_code}")]
+async fn get_device_code(
+    auth: AuthenticatedUser,
+    path: web::Path<String>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_mfa(&auth) {
+        return resp;
+    }
+    let record = match load_pending_device_code(repo.get_ref(), &path).await {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    let client_name = match repo.get_oauth_client(&record.client_id).await {
+        Ok(Some(client)) => client.name,
+        _ => record.client_id.clone(),
+    };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "Device code found".to_string(),
+        data: Some(OAuthRequestData {
+            client_id: record.client_id,
+            client_name,
+            scope: record.scope,
+        }),
+    })
+}
+
+// 用户同意后，设备下一次轮询即可获得令牌，令牌继承当前登录的 amr 和 auth_time
+#[post("/oauth/device/approve")]
+async fn approve_device_code(
+    auth: AuthenticatedUser,
+    data: web::Json<OAuthDeviceVerifySchema>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_mfa(&auth) {
+        return resp;
+    }
+    let record = match load_pending_device_code(repo.get_ref(), &data.user_code).await {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    match repo.get_oauth_client(&record.client_id).await {
+        Ok(Some(client)) => {
+            if let Err(resp) = check_required_acr(&client, &auth) {
+                return resp;
+            }
+        }
+        _ => {
+            return HttpResponse::NotFound().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "Invalid or expired device code".to_string(),
+                data: None,
+            });
+        }
+    }
+
+    let amr = auth.claims.amr.clone().unwrap_or_default();
+    let auth_time = auth
+        .claims
+        .auth_time
+        .and_then(|t| DateTime::from_timestamp(t as i64, 0))
+        .unwrap_or_else(Utc::now);
+    match repo
+        .approve_oauth_device_code(&record.user_code, &auth.user.id, &amr, &auth_time)
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::<()> {
+            status: "success".to_string(),
+            message: "Device authorized".to_string(),
+            data: None,
+        }),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Invalid or expired device code".to_string(),
+            data: None,
+        }),
+        Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to authorize device".to_string(),
+            data: None,
+        }),
+    }
+}
+
+#[post("/oauth/device/deny")]
+async fn deny_device_code(
+    auth: AuthenticatedUser,
+    data: web::Json<OAuthDeviceVerifySchema>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    if let Err(resp) = require_mfa(&auth) {
+        return resp;
+    }
+    match repo
+        .deny_oauth_device_code(&normalize_user_code(&data.user_code))
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::<()> {
+            status: "success".to_string(),
+            message: "Device authorization denied".to_string(),
+            data: None,
+        }),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Invalid or expired device code".to_string(),
+            data: None,
+        }),
+        Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to deny device".to_string(),
+            data: None,
+        }),
+    }
+}
+
+// /oauth/device_authorization 挂在根路径下
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(device_authorization);
+}
+
+// 验证页面使用的接口，挂在 /api 下
+pub fn api_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_device_code)
+        .service(approve_device_code)
+        .service(deny_device_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, http::StatusCode, test};
+    use serde_json::{Value, json};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        handlers::issue_session_tokens,
+        oauth,
+        test_support::{create_user, repository},
+    };
+
+    async fn create_device_client(pool: &PgPool) {
+        sqlx::query(
+            "INSERT INTO oauth_clients (client_id, name, allowed_scopes, grant_types) VALUES ($1, $2, $3, $4)",
+        )
+        .bind("cli")
+        .bind("CLI")
+        .bind(vec!["openid"])
+        .bind(vec![DEVICE_CODE_GRANT_TYPE])
+        .execute(pool)
+        .await
+        .expect("Failed to create test client");
+    }
+
+    async fn access_token_with_amr(repo: &dyn UserRepository, user: &User, amr: &[&str]) -> String {
+        let grant = SessionGrant::new(amr.iter().map(|method| method.to_string()).collect());
+        let (access_token, _) = issue_session_tokens(repo, user, grant, None)
+            .await
+            .expect("Failed to issue tokens");
+        access_token
+    }
+
+    fn poll_request(device_code: &str) -> test::TestRequest {
+        test::TestRequest::post().uri("/oauth/token").set_form([
+            ("grant_type", DEVICE_CODE_GRANT_TYPE),
+            ("client_id", "cli"),
+            ("device_code", device_code),
+        ])
+    }
+
+    fn approve_request(user_code: &str, access_token: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/oauth/device/approve")
+            .insert_header(("Authorization", format!("Bearer {access_token}")))
+            .set_json(json!({ "user_code": user_code }))
+    }
+
+    #[sqlx::test]
+    async fn device_code_flow_polls_until_approved_and_is_single_use(pool: PgPool) {
+        let repo = repository(pool.clone());
+        create_device_client(&pool).await;
+        let user = create_user(repo.as_ref(), "alice").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config)
+                .configure(api_config)
+                .configure(oauth::config),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/oauth/device_authorization")
+            .set_form([("client_id", "cli"), ("scope", "openid")])
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let device_code = body["device_code"].as_str().unwrap().to_string();
+        // 用户输入时可能是小写
+        let user_code = body["user_code"].as_str().unwrap().to_lowercase();
+
+        let resp = test::call_service(&app, poll_request(&device_code).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "authorization_pending");
+
+        // 间隔未到就再次轮询
+        let resp = test::call_service(&app, poll_request(&device_code).to_request()).await;
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "slow_down");
+
+        let mfa_token = access_token_with_amr(repo.as_ref(), &user, &["pwd", "mfa"]).await;
+        let req = approve_request(&user_code, &mfa_token).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, poll_request(&device_code).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["access_token"].is_string());
+
+        // device_code 只能兑换一次
+        let resp = test::call_service(&app, poll_request(&device_code).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_grant");
+    }
+
+    #[sqlx::test]
+    async fn approval_requires_a_second_factor(pool: PgPool) {
+        let repo = repository(pool.clone());
+        create_device_client(&pool).await;
+        let user = create_user(repo.as_ref(), "alice").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config)
+                .configure(api_config)
+                .configure(oauth::config),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/oauth/device_authorization")
+            .set_form([("client_id", "cli")])
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let device_code = body["device_code"].as_str().unwrap().to_string();
+        let user_code = body["user_code"].as_str().unwrap().to_string();
+
+        let password_token = access_token_with_amr(repo.as_ref(), &user, &["pwd"]).await;
+        let req = approve_request(&user_code, &password_token).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&app, poll_request(&device_code).to_request()).await;
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "authorization_pending");
+    }
+}
@@ -0,0 +1,416 @@
+use std::{fmt, future::Future, pin::Pin, str::FromStr};
+
+use actix_web::{
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    middleware::Next,
+    web,
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::errors::ErrorKind;
+use uuid::Uuid;
+
+use crate::{
+    models::{ApiResponse, Claims, User},
+    repositories::UserRepository,
+    utils::{
+        acr_for_amr, acr_satisfies, step_up_max_age, validate_access_token,
+        validate_enrollment_token, validate_mfa_token,
+    },
+};
+
+// 认证失败的统一错误，转换为和 handlers 一致的 ApiResponse
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidTokenFormat,
+    InvalidToken,
+    InvalidMfaToken,
+    MfaRequired,
+    RecentMfaRequired,
+    AccessTokenRequired,
+    ClientTokenNotAllowed,
+    UserNotFound,
+    Internal,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            AuthError::MissingToken => "Authorization header missing",
+            AuthError::InvalidTokenFormat => "Invalid token format",
+            AuthError::InvalidToken => "Invalid or expired access token",
+            AuthError::InvalidMfaToken => "Invalid or expired MFA token",
+            AuthError::MfaRequired => "MFA verification required",
+            AuthError::RecentMfaRequired => {
+                "Recent MFA verification required, please sign in again"
+            }
+            AuthError::AccessTokenRequired => "An access token is required for this action",
+            AuthError::ClientTokenNotAllowed => {
+                "Tokens issued to OAuth clients cannot access this API"
+            }
+            AuthError::UserNotFound => "User not found",
+            AuthError::Internal => "Failed to authenticate request",
+        };
+        write!(f, "{message}")
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            // 令牌本身有效，但缺少 "mfa"：身份已知、权限不足
+            AuthError::MfaRequired
+            | AuthError::RecentMfaRequired
+            | AuthError::AccessTokenRequired
+            | AuthError::ClientTokenNotAllowed => StatusCode::FORBIDDEN,
+            AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: self.to_string(),
+            data: None,
+        })
+    }
+}
+
+// 从 Authorization 头中取出 Bearer token
+pub fn bearer_token(req: &HttpRequest) -> Result<String, AuthError> {
+    let value = req
+        .headers()
+        .get("Authorization")
+        .ok_or(AuthError::MissingToken)?
+        .to_str()
+        .map_err(|_| AuthError::InvalidTokenFormat)?;
+
+    value
+        .strip_prefix("Bearer ")
+        .map(|token| token.to_string())
+        .ok_or(AuthError::InvalidTokenFormat)
+}
+
+// 通过 access token 认证的用户
+// 在 handler 参数中声明即可，认证失败时直接返回 401/403
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user: User,
+    pub claims: Claims,
+}
+
+impl AuthenticatedUser {
+    // 高风险操作的 step-up 检查：令牌必须来自 MFA（或 passkey）登录，
+    // 且 auth_time 在 step_up_max_age 之内，否则需要重新登录
+    pub fn require_recent_mfa(&self) -> Result<(), AuthError> {
+        let amr = self.claims.amr.as_deref().unwrap_or_default();
+        if !acr_satisfies(&acr_for_amr(amr), "mfa") {
+            return Err(AuthError::MfaRequired);
+        }
+
+        let recent = self
+            .claims
+            .auth_time
+            .and_then(|t| DateTime::from_timestamp(t as i64, 0))
+            .is_some_and(|auth_time| Utc::now() - auth_time <= step_up_max_age());
+        if !recent {
+            return Err(AuthError::RecentMfaRequired);
+        }
+        Ok(())
+    }
+
+    async fn authenticate(req: &HttpRequest) -> Result<Self, AuthError> {
+        let token = bearer_token(req)?;
+        let repo = user_repository(req)?;
+        Self::from_token(&token, repo.get_ref()).await
+    }
+
+    async fn from_token(token: &str, repo: &dyn UserRepository) -> Result<Self, AuthError> {
+        let claims = validate_access_token(token, repo)
+            .await
+            .map_err(|e| match e.kind() {
+                ErrorKind::MissingRequiredClaim(claim) if claim == "amr" => AuthError::MfaRequired,
+                _ => AuthError::InvalidToken,
+            })?;
+
+        // 通过 OAuth 签发给第三方应用的令牌不能用来管理账户（改密码、关闭 MFA 等）
+        if claims.client_id.is_some() {
+            return Err(AuthError::ClientTokenNotAllowed);
+        }
+
+        let user_id = Uuid::from_str(&claims.sub).map_err(|_| AuthError::InvalidToken)?;
+        let user = repo
+            .get_user_by_id(&user_id)
+            .await
+            .map_err(|_| AuthError::UserNotFound)?;
+
+        Ok(Self { user, claims })
+    }
+}
+
+fn user_repository(req: &HttpRequest) -> Result<&web::Data<dyn UserRepository>, AuthError> {
+    req.app_data::<web::Data<dyn UserRepository>>()
+        .ok_or(AuthError::Internal)
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AuthError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            // require_auth 中间件已经认证过的请求，直接复用结果
+            if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+                return Ok(user.clone());
+            }
+            AuthenticatedUser::authenticate(&req).await
+        })
+    }
+}
+
+// 第二因子（OTP / WebAuthn）设置接口使用的认证方式：
+// - 已登录用户使用 access token 管理自己的第二因子；已经拥有第二因子时，
+//   更换 OTP 密钥或新增 passkey 还要求最近完成过 MFA，否则被盗的 access token 就能换成长期凭证
+// - 首次设置的用户还拿不到 access token，使用登录返回的 enrollment token，
+//   但仅限尚未拥有任何第二因子的账户，否则只凭密码就能重置别人的 MFA
+#[derive(Debug, Clone)]
+pub struct MfaSetupUser {
+    pub user: User,
+    // 是否通过 enrollment token 认证（即首次注册第二因子）
+    pub enrollment: bool,
+}
+
+impl FromRequest for MfaSetupUser {
+    type Error = AuthError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let token = bearer_token(&req)?;
+            let repo = user_repository(&req)?;
+
+            if let Ok(auth) = AuthenticatedUser::from_token(&token, repo.get_ref()).await {
+                if has_second_factor(repo.get_ref(), &auth.user).await? {
+                    auth.require_recent_mfa()?;
+                }
+                return Ok(Self {
+                    user: auth.user,
+                    enrollment: false,
+                });
+            }
+
+            let claims = validate_enrollment_token(&token).map_err(|_| AuthError::InvalidToken)?;
+            let user_id = Uuid::from_str(&claims.sub).map_err(|_| AuthError::InvalidToken)?;
+            let user = repo
+                .get_user_by_id(&user_id)
+                .await
+                .map_err(|_| AuthError::UserNotFound)?;
+
+            if has_second_factor(repo.get_ref(), &user).await? {
+                return Err(AuthError::AccessTokenRequired);
+            }
+
+            Ok(Self {
+                user,
+                enrollment: true,
+            })
+        })
+    }
+}
+
+async fn has_second_factor(repo: &dyn UserRepository, user: &User) -> Result<bool, AuthError> {
+    if user.otp_active() {
+        return Ok(true);
+    }
+    let credentials = repo
+        .list_webauthn_credentials(&user.id)
+        .await
+        .map_err(|_| AuthError::Internal)?;
+    Ok(!credentials.is_empty())
+}
+
+// 登录第二步使用的认证方式：Bearer 为登录返回的 mfa token
+#[derive(Debug, Clone)]
+pub struct MfaPendingUser {
+    pub user: User,
+    // mfa token 的 jti 和过期时间，用于限制单个 token 的失败次数
+    pub jti: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl FromRequest for MfaPendingUser {
+    type Error = AuthError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let token = bearer_token(&req)?;
+            let repo = user_repository(&req)?;
+
+            let claims = validate_mfa_token(&token).map_err(|_| AuthError::InvalidMfaToken)?;
+            let user_id = Uuid::from_str(&claims.sub).map_err(|_| AuthError::InvalidMfaToken)?;
+            let jti = claims
+                .jti
+                .as_deref()
+                .and_then(|jti| Uuid::from_str(jti).ok())
+                .ok_or(AuthError::InvalidMfaToken)?;
+            let expires_at =
+                DateTime::from_timestamp(claims.exp as i64, 0).ok_or(AuthError::InvalidMfaToken)?;
+            let user = repo
+                .get_user_by_id(&user_id)
+                .await
+                .map_err(|_| AuthError::UserNotFound)?;
+
+            Ok(Self {
+                user,
+                jti,
+                expires_at,
+            })
+        })
+    }
+}
+
+// 可以 wrap 到整个 scope 上的认证中间件
+// 认证通过后把 AuthenticatedUser 放入请求扩展，handler 中仍可直接提取
+pub async fn require_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let user = AuthenticatedUser::authenticate(req.request()).await?;
+    req.extensions_mut().insert(user);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, Responder, get, middleware, test};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        handlers::issue_session_tokens,
+        models::SessionGrant,
+        test_support::{create_user, repository, service_client},
+        utils::{
+            TokenSettings, generate_client_access_token, generate_mfa_token, generate_refresh_token,
+        },
+    };
+
+    #[get("/whoami")]
+    async fn whoami(auth: AuthenticatedUser) -> impl Responder {
+        HttpResponse::Ok().body(auth.user.username)
+    }
+
+    async fn access_token_for(repo: &dyn UserRepository, user: &User) -> String {
+        let grant = SessionGrant::new(vec!["pwd".to_string(), "mfa".to_string()]);
+        let (access_token, _) = issue_session_tokens(repo, user, grant, None)
+            .await
+            .expect("Failed to issue tokens");
+        access_token
+    }
+
+    fn get(uri: &str, authorization: Option<&str>) -> test::TestRequest {
+        let req = test::TestRequest::get().uri(uri);
+        match authorization {
+            Some(value) => req.insert_header(("Authorization", value.to_string())),
+            None => req,
+        }
+    }
+
+    #[sqlx::test]
+    async fn extractor_accepts_a_valid_access_token(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let token = access_token_for(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .service(whoami),
+        )
+        .await;
+
+        let req = get("/whoami", Some(&format!("Bearer {token}"))).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "alice");
+    }
+
+    #[sqlx::test]
+    async fn extractor_rejects_missing_malformed_and_non_user_tokens(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "bob").await;
+        let token = access_token_for(repo.as_ref(), &user).await;
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        let refresh_token = generate_refresh_token(
+            &user.id,
+            &Uuid::new_v4(),
+            &TokenSettings::global(),
+            repo.as_ref(),
+        )
+        .await
+        .unwrap();
+        // client_credentials 令牌代表客户端本身，没有对应的用户
+        let client_token = generate_client_access_token(
+            &service_client("service"),
+            "urn:auth-center:api",
+            "openid",
+            repo.as_ref(),
+        )
+        .await
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .service(whoami),
+        )
+        .await;
+
+        for authorization in [
+            None,
+            Some(format!("Basic {token}")),
+            Some(token.clone()),
+            Some("Bearer not-a-jwt".to_string()),
+            Some(format!("Bearer {mfa_token}")),
+            Some(format!("Bearer {refresh_token}")),
+            Some(format!("Bearer {client_token}")),
+        ] {
+            let req = get("/whoami", authorization.as_deref()).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{authorization:?}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn middleware_protects_the_whole_scope(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "carol").await;
+        let token = access_token_for(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new().app_data(web::Data::from(repo.clone())).service(
+                web::scope("/api")
+                    .wrap(middleware::from_fn(require_auth))
+                    .service(whoami),
+            ),
+        )
+        .await;
+
+        // 中间件认证失败时以错误的形式返回
+        let error = test::try_call_service(&app, get("/api/whoami", None).to_request())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let req = get("/api/whoami", Some(&format!("Bearer {token}"))).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "carol");
+    }
+}
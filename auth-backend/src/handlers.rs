@@ -1,27 +1,228 @@
-use std::str::FromStr;
+use std::{env, str::FromStr};
 
-use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
-use bcrypt::{hash, verify};
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, ResponseError, get,
+    http::{StatusCode, header},
+    middleware, post, rt, web,
+};
+use chrono::{DateTime, Duration, Utc};
 use rand::Rng;
 use serde_json::json;
-use totp_rs::{Algorithm, Secret, TOTP};
 use uuid::Uuid;
 
 use crate::{
+    extractors::{AuthenticatedUser, MfaPendingUser, MfaSetupUser, require_auth},
+    mailer::{Email, Mailer},
     models::{
-        ApiResponse, DisableOTPSchema, GenerateOTPSchema, LoginMfaData, LoginRequest,
-        OtpSueecessData, RegisterRequest, UserData, VerifyOTPSchema,
+        ApiResponse, ChangePasswordRequest, DisableOTPSchema, ForgotPasswordRequest, LoginData,
+        LoginNextStep, LoginRequest, NewSession, OtpAttempt, OtpSueecessData, OtpVerifiedData,
+        RecoveryCodesData, RefreshTokenRequest, RegisterRequest, ResetPasswordRequest, Session,
+        SessionGrant, User, UserData, ValidateOTPSchema, VerifyOTPSchema,
     },
+    oauth::frontend_url,
+    password::PasswordHasher,
+    password_policy::PasswordPolicy,
     repositories::UserRepository,
     utils::{
-        generate_access_token, generate_mfa_token, generate_refresh_token, validate_mfa_token,
+        MAX_MFA_TOKEN_FAILURES, TokenSettings, TokenType, build_totp, generate_access_token,
+        generate_enrollment_token, generate_mfa_token, generate_random_token,
+        generate_recovery_codes, generate_refresh_token, hash_token, match_totp_step, mfa_required,
+        normalize_recovery_code, otp_lockout, token_ttl, validate_refresh_token,
     },
 };
 
+pub(crate) fn device_info(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+// 签发 access token + refresh token，并把 refresh token 记录到 sessions 表
+// grant.family_id 为同一次登录的标识，轮换时沿用旧 session 的 family_id
+// amr、auth_time 以及 OAuth 客户端信息记录在 session 中，轮换时保持不变
+// 令牌格式（JWT 或引用令牌）和有效期由客户端配置决定，没有客户端时使用全局配置
+pub(crate) async fn issue_session_tokens(
+    repo: &dyn UserRepository,
+    user: &User,
+    grant: SessionGrant,
+    device_info: Option<String>,
+) -> Result<(String, String), HttpResponse> {
+    let settings = match grant.client_id.as_deref() {
+        Some(client_id) => match repo.get_oauth_client(client_id).await {
+            Ok(Some(client)) => TokenSettings::for_client(&client),
+            Ok(None) => TokenSettings::global(),
+            Err(_) => {
+                return Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "Failed to load client".to_string(),
+                    data: None,
+                }));
+            }
+        },
+        None => TokenSettings::global(),
+    };
+
+    let access_token = generate_access_token(&user.id, user.token_version, &grant, &settings, repo)
+        .await
+        .map_err(|_| {
+            HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "Failed to generate access token".to_string(),
+                data: None,
+            })
+        })?;
+
+    let jti = Uuid::new_v4();
+    let refresh_token = generate_refresh_token(&user.id, &jti, &settings, repo)
+        .await
+        .map_err(|_| {
+            HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "Failed to generate refresh token".to_string(),
+                data: None,
+            })
+        })?;
+
+    let session = NewSession {
+        user_id: user.id,
+        family_id: grant.family_id,
+        jti,
+        token_hash: hash_token(&refresh_token),
+        device_info,
+        expires_at: Utc::now() + settings.refresh_ttl,
+        amr: grant.amr,
+        auth_time: grant.auth_time,
+        client_id: grant.client_id,
+        scope: grant.scope,
+    };
+
+    if repo.create_session(&session).await.is_err() {
+        return Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to create session".to_string(),
+            data: None,
+        }));
+    }
+
+    Ok((access_token, refresh_token))
+}
+
+// 生成一组新的恢复码，数据库中只保存哈希
+async fn issue_recovery_codes(
+    repo: &dyn UserRepository,
+    user_id: &Uuid,
+) -> Result<Vec<String>, HttpResponse> {
+    let codes = generate_recovery_codes(RECOVERY_CODE_COUNT);
+    let hashes: Vec<String> = codes.iter().map(|code| hash_token(code)).collect();
+
+    if repo.replace_recovery_codes(user_id, &hashes).await.is_err() {
+        return Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to generate recovery codes".to_string(),
+            data: None,
+        }));
+    }
+
+    Ok(codes)
+}
+
+// 登录第二步提交的验证方式，同时提交时优先使用 OTP
+enum SecondFactor<'a> {
+    Otp(&'a str),
+    RecoveryCode(&'a str),
+}
+
+// 校验用户当前生效的 OTP 时可能出现的失败
+enum OtpCheckError {
+    Invalid,
+    // 验证码正确，但所在时间步已经被使用过
+    Replayed,
+    Locked(DateTime<Utc>),
+    Internal,
+}
+
+impl OtpCheckError {
+    fn response(&self) -> HttpResponse {
+        match self {
+            OtpCheckError::Invalid => HttpResponse::Unauthorized().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "Invalid OTP token".to_string(),
+                data: None,
+            }),
+            OtpCheckError::Replayed => HttpResponse::Unauthorized().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "OTP token has already been used".to_string(),
+                data: None,
+            }),
+            OtpCheckError::Locked(locked_until) => {
+                let retry_after = (*locked_until - Utc::now()).num_seconds().max(1);
+                HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(ApiResponse::<()> {
+                        status: "error".to_string(),
+                        message: "Too many failed OTP attempts, please try again later".to_string(),
+                        data: None,
+                    })
+            }
+            OtpCheckError::Internal => {
+                HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "Failed to verify OTP".to_string(),
+                    data: None,
+                })
+            }
+        }
+    }
+}
+
+// 校验前先占用一次尝试：锁定期内直接拒绝，否则先计入连续失败次数（达到阈值时按指数退避锁定），
+// 验证成功后再清空。先计数再校验，并发请求无法同时越过锁定检查
+async fn claim_otp_attempt(repo: &dyn UserRepository, user: &User) -> Result<(), OtpCheckError> {
+    match repo.claim_otp_attempt(&user.id, otp_lockout).await {
+        Ok(OtpAttempt::Claimed) => Ok(()),
+        Ok(OtpAttempt::Locked(locked_until)) => Err(OtpCheckError::Locked(locked_until)),
+        Err(_) => Err(OtpCheckError::Internal),
+    }
+}
+
+// 校验用户当前生效的 OTP：
+// - 每次校验计入连续失败次数，成功后清空（accept_otp_step）
+// - 每个时间步只能成功使用一次，防止验证码被截获后重放
+async fn check_user_otp(
+    repo: &dyn UserRepository,
+    user: &User,
+    token: &str,
+) -> Result<(), OtpCheckError> {
+    claim_otp_attempt(repo, user).await?;
+
+    let step = user
+        .otp_base32
+        .as_deref()
+        .and_then(|otp_base32| match_totp_step(&build_totp(otp_base32), token));
+
+    let Some(step) = step else {
+        return Err(OtpCheckError::Invalid);
+    };
+
+    match repo.accept_otp_step(&user.id, step).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(OtpCheckError::Replayed),
+        Err(_) => Err(OtpCheckError::Internal),
+    }
+}
+
+// 未在有效期内完成验证的 OTP 注册会被丢弃，需要重新生成
+const OTP_ENROLLMENT_MINUTES: i64 = 10;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
 #[post("/auth/login")]
 async fn login(
+    req: HttpRequest,
     data: web::Json<LoginRequest>,
     repo: web::Data<dyn UserRepository>,
+    hasher: web::Data<PasswordHasher>,
 ) -> impl Responder {
     let user = match repo.get_user_by_email(&data.email).await {
         Ok(user) => user,
@@ -34,18 +235,12 @@ async fn login(
         }
     };
 
-    let is_valid = match verify(&data.password, &user.password_hash) {
-        Ok(valid) => valid,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "Password verification failed".to_string(),
-                data: None,
-            });
-        }
+    let check = match hasher.verify(&data.password, &user.password_hash).await {
+        Ok(check) => check,
+        Err(e) => return e.response(),
     };
 
-    if !is_valid {
+    if !check.valid {
         return HttpResponse::Unauthorized().json(ApiResponse::<()> {
             status: "error".to_string(),
             message: "Invalid credentials".to_string(),
@@ -53,21 +248,121 @@ async fn login(
         });
     }
 
-    let token = match generate_mfa_token(&user.id) {
-        Ok(t) => t,
+    // 只有登录时才拿得到明文密码：哈希算法或参数过时的，顺便升级为当前配置
+    // 升级失败不影响本次登录，下次登录会再次尝试
+    if check.needs_rehash {
+        match hasher.hash(&data.password).await {
+            Ok(password_hash) => {
+                if let Err(e) = repo.update_user_password(&user.id, &password_hash).await {
+                    println!("⚠️ 升级密码哈希失败: {e:#}");
+                }
+            }
+            Err(e) => println!("⚠️ 升级密码哈希失败: {e:?}"),
+        }
+    }
+
+    let has_webauthn = match repo.list_webauthn_credentials(&user.id).await {
+        Ok(credentials) => !credentials.is_empty(),
         Err(_) => {
             return HttpResponse::InternalServerError().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "Could not generate MFA token".to_string(),
+                message: "Could not load MFA methods".to_string(),
                 data: None,
             });
         }
     };
 
+    let mut mfa_methods = Vec::new();
+    if user.otp_active() {
+        mfa_methods.push("otp".to_string());
+    }
+    if has_webauthn {
+        mfa_methods.push("webauthn".to_string());
+    }
+
+    // 已有可用的第二因子：进入第二步验证
+    if !mfa_methods.is_empty() {
+        let token = match generate_mfa_token(&user.id) {
+            Ok(t) => t,
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "Could not generate MFA token".to_string(),
+                    data: None,
+                });
+            }
+        };
+
+        return HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Login successful".to_string(),
+            data: Some(LoginData {
+                next_step: LoginNextStep::MfaRequired,
+                mfa_methods: Some(mfa_methods),
+                mfa_token: Some(token),
+                enrollment_token: None,
+                tokens: None,
+            }),
+        });
+    }
+
+    // 尚未启用 OTP 且 MFA 强制：只签发注册 OTP 用的令牌
+    if mfa_required() {
+        let token = match generate_enrollment_token(&user.id) {
+            Ok(t) => t,
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "Could not generate enrollment token".to_string(),
+                    data: None,
+                });
+            }
+        };
+
+        return HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Login successful, MFA enrollment required".to_string(),
+            data: Some(LoginData {
+                next_step: LoginNextStep::MfaEnrollmentRequired,
+                mfa_methods: None,
+                mfa_token: None,
+                enrollment_token: Some(token),
+                tokens: None,
+            }),
+        });
+    }
+
+    // MFA 非强制：直接签发只包含 "pwd" 的令牌
+    let (access_token, refresh_token) = match issue_session_tokens(
+        repo.get_ref(),
+        &user,
+        SessionGrant::new(vec!["pwd".to_string()]),
+        device_info(&req),
+    )
+    .await
+    {
+        Ok(tokens) => tokens,
+        Err(resp) => return resp,
+    };
+
     HttpResponse::Ok().json(ApiResponse {
         status: "success".to_string(),
         message: "Login successful".to_string(),
-        data: Some(LoginMfaData { mfa_token: token }),
+        data: Some(LoginData {
+            next_step: LoginNextStep::Authenticated,
+            mfa_methods: None,
+            mfa_token: None,
+            enrollment_token: None,
+            tokens: Some(OtpSueecessData {
+                access_token,
+                refresh_token,
+                user: UserData {
+                    id: user.id.to_string(),
+                    email: user.email,
+                    name: user.username,
+                },
+            }),
+        }),
     })
 }
 
@@ -75,16 +370,19 @@ async fn login(
 async fn register(
     data: web::Json<RegisterRequest>,
     repo: web::Data<dyn UserRepository>,
+    hasher: web::Data<PasswordHasher>,
+    policy: web::Data<PasswordPolicy>,
 ) -> impl Responder {
-    let password_hash = match hash(&data.password, bcrypt::DEFAULT_COST) {
+    if let Err(e) = policy
+        .check(&data.password, &[&data.username, &data.email])
+        .await
+    {
+        return e.response();
+    }
+
+    let password_hash = match hasher.hash(&data.password).await {
         Ok(h) => h,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "Could not hash password".to_string(),
-                data: None,
-            });
-        }
+        Err(e) => return e.response(),
     };
 
     match repo.create_user(&data, &password_hash).await {
@@ -101,56 +399,165 @@ async fn register(
     }
 }
 
-#[get("/hello")]
-async fn hello() -> impl Responder {
-    HttpResponse::Ok().body("Hello world!")
+// 找回密码：无论邮箱是否注册都立即返回相同的响应，避免泄露哪些邮箱已注册
+// 查找用户和发送邮件都在后台进行，响应时间也不会因此不同
+#[post("/auth/password/forgot")]
+async fn forgot_password(
+    data: web::Json<ForgotPasswordRequest>,
+    repo: web::Data<dyn UserRepository>,
+    mailer: web::Data<dyn Mailer>,
+) -> impl Responder {
+    let repo = repo.into_inner();
+    let mailer = mailer.into_inner();
+    let email = data.into_inner().email;
+    rt::spawn(async move {
+        if let Err(e) = send_password_reset(repo.as_ref(), mailer.as_ref(), &email).await {
+            println!("⚠️ 发送密码重置邮件失败: {e:#}");
+        }
+    });
+
+    HttpResponse::Ok().json(ApiResponse::<()> {
+        status: "success".to_string(),
+        message: "If the email is registered, a password reset link has been sent".to_string(),
+        data: None,
+    })
 }
 
-#[post("/auth/otp/generate")]
-async fn generate_otp(
-    data: web::Json<GenerateOTPSchema>,
+async fn send_password_reset(
+    repo: &dyn UserRepository,
+    mailer: &dyn Mailer,
+    email: &str,
+) -> anyhow::Result<()> {
+    let Ok(user) = repo.get_user_by_email(email).await else {
+        return Ok(());
+    };
+
+    let token = generate_random_token();
+    let ttl = token_ttl(TokenType::PasswordReset);
+    repo.create_password_reset_token(&user.id, &hash_token(&token), &(Utc::now() + ttl))
+        .await?;
+
+    // 前端的重置密码页面，令牌通过 token 参数传递
+    let base = env::var("PASSWORD_RESET_URL")
+        .unwrap_or_else(|_| "http://localhost:5173/#/reset-password".to_string());
+    let link = frontend_url(&base, "token", &token);
+    mailer
+        .send(&Email {
+            to: user.email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {},\n\nUse the link below to reset your password. It expires in {} minutes and can only be used once.\n\n{link}\n\nIf you did not request a password reset, you can ignore this email.",
+                user.username,
+                ttl.num_minutes()
+            ),
+        })
+        .await
+}
+
+// 重置密码：令牌只能使用一次；启用了 OTP 的用户还需要提供验证码，
+// 防止拿到邮箱的攻击者直接接管账户。成功后吊销该用户的所有会话
+#[post("/auth/password/reset")]
+async fn reset_password(
+    data: web::Json<ResetPasswordRequest>,
     repo: web::Data<dyn UserRepository>,
+    hasher: web::Data<PasswordHasher>,
+    policy: web::Data<PasswordPolicy>,
 ) -> impl Responder {
-    let user_id = match Uuid::from_str(&data.user_id) {
-        Ok(id) => id,
-        Err(_) => {
-            return HttpResponse::BadRequest().json(ApiResponse::<()> {
+    let invalid_token = || {
+        HttpResponse::BadRequest().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Invalid or expired reset token".to_string(),
+            data: None,
+        })
+    };
+    let internal_error = |message: &str| {
+        HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: message.to_string(),
+            data: None,
+        })
+    };
+
+    let token_hash = hash_token(&data.token);
+    let user = match repo.get_password_reset_user(&token_hash).await {
+        Ok(Some(user_id)) => match repo.get_user_by_id(&user_id).await {
+            Ok(user) => user,
+            Err(_) => return invalid_token(),
+        },
+        Ok(None) => return invalid_token(),
+        Err(_) => return internal_error("Failed to load reset token"),
+    };
+
+    if let Err(e) = policy
+        .check(&data.new_password, &[&user.username, &user.email])
+        .await
+    {
+        return e.response();
+    }
+
+    if user.otp_active() {
+        let Some(otp_token) = data.otp_token.as_deref() else {
+            return HttpResponse::Unauthorized().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "Invalid user_id format".to_string(),
+                message: "OTP token is required".to_string(),
                 data: None,
             });
+        };
+        if let Err(e) = check_user_otp(repo.get_ref(), &user, otp_token).await {
+            return e.response();
         }
+    }
+
+    // 先计算哈希再使用令牌：服务繁忙被拒绝时令牌仍然可以重试
+    let password_hash = match hasher.hash(&data.new_password).await {
+        Ok(h) => h,
+        Err(e) => return e.response(),
     };
 
-    if (repo.get_user_by_id(&user_id).await).is_err() {
-        return HttpResponse::NotFound().json(ApiResponse::<()> {
-            status: "error".to_string(),
-            message: "User not found".to_string(),
-            data: None,
-        });
+    // 与 logout-all 相同：吊销所有 refresh token，并使已签发的 access token 失效
+    // 并发请求中只有一个能使用令牌，失败时令牌和密码都保持不变
+    match repo
+        .reset_password_with_token(&token_hash, &user.id, &password_hash)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return invalid_token(),
+        Err(_) => return internal_error("Failed to reset password"),
     }
 
+    HttpResponse::Ok().json(ApiResponse::<()> {
+        status: "success".to_string(),
+        message: "Password has been reset".to_string(),
+        data: None,
+    })
+}
+
+#[get("/hello")]
+async fn hello() -> impl Responder {
+    HttpResponse::Ok().body("Hello world!")
+}
+
+#[post("/auth/otp/generate")]
+async fn generate_otp(auth: MfaSetupUser, repo: web::Data<dyn UserRepository>) -> impl Responder {
+    let user_id = auth.user.id;
+
     let mut rng = rand::thread_rng();
     let data_byte: [u8; 21] = rng.r#gen();
     let base32_string = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &data_byte);
 
-    let totp = TOTP::new(
-        Algorithm::SHA1,
-        6,
-        1,
-        30,
-        Secret::Encoded(base32_string).to_bytes().unwrap(),
-    )
-    .unwrap();
+    let totp = build_totp(&base32_string);
 
     let otp_base32 = totp.get_secret_base32();
-    let email = data.email.to_owned();
+    let email = auth.user.email.to_owned();
     let issuer = "AuthApp";
     let otp_auth_url =
         format!("otpauth://totp/{issuer}:{email}?secret={otp_base32}&issuer={issuer}");
 
+    // 新密钥只是 pending 状态，已启用的验证器在 verify 成功前继续有效
+    let expires_at = Utc::now() + Duration::minutes(OTP_ENROLLMENT_MINUTES);
+
     match repo
-        .update_user_otp(&user_id, &otp_base32, &otp_auth_url)
+        .set_pending_user_otp(&user_id, &otp_base32, &otp_auth_url, &expires_at)
         .await
     {
         Ok(_) => HttpResponse::Ok().json(ApiResponse {
@@ -158,7 +565,8 @@ async fn generate_otp(
             message: "OTP generated successfully".to_string(),
             data: Some(json!({
                 "otp_base32": otp_base32,
-                "otp_auth_url": otp_auth_url
+                "otp_auth_url": otp_auth_url,
+                "expires_at": expires_at
             })),
         }),
         Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
@@ -171,66 +579,101 @@ async fn generate_otp(
 
 #[post("/auth/otp/verify")]
 async fn verify_otp(
+    req: HttpRequest,
+    auth: MfaSetupUser,
     data: web::Json<VerifyOTPSchema>,
     repo: web::Data<dyn UserRepository>,
 ) -> impl Responder {
-    let user_id = match Uuid::from_str(&data.user_id) {
-        Ok(id) => id,
-        Err(_) => {
-            return HttpResponse::BadRequest().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "Invalid user_id format".to_string(),
-                data: None,
-            });
-        }
-    };
-
-    let user = match repo.get_user_by_id(&user_id).await {
-        Ok(user) => user,
-        Err(_) => {
-            return HttpResponse::NotFound().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "User not found".to_string(),
-                data: None,
-            });
-        }
-    };
+    let user = auth.user;
+    let user_id = user.id;
 
-    let otp_base32 = match &user.otp_base32 {
+    let otp_base32 = match &user.otp_pending_base32 {
         Some(base32) => base32.clone(),
         None => {
             return HttpResponse::BadRequest().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "OTP not set up for this user".to_string(),
+                message: "No pending OTP enrollment for this user".to_string(),
                 data: None,
             });
         }
     };
 
-    let totp = TOTP::new(
-        Algorithm::SHA1,
-        6,
-        1,
-        30,
-        Secret::Encoded(otp_base32).to_bytes().unwrap(),
-    )
-    .unwrap();
+    if user
+        .otp_pending_expires_at
+        .is_none_or(|expires_at| expires_at <= Utc::now())
+    {
+        return HttpResponse::BadRequest().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "OTP enrollment has expired, please generate a new one".to_string(),
+            data: None,
+        });
+    }
 
-    let is_valid = totp.check_current(&data.token).unwrap();
+    let step = match_totp_step(&build_totp(&otp_base32), &data.token);
 
-    if is_valid {
-        if repo.verify_user_otp(&user_id).await.is_err() {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "Failed to update OTP status".to_string(),
-                data: None,
-            });
+    if let Some(step) = step {
+        match repo
+            .activate_pending_user_otp(&user_id, &otp_base32, step)
+            .await
+        {
+            Ok(true) => {}
+            // pending 密钥在校验期间被替换或过期
+            Ok(false) => {
+                return HttpResponse::Conflict().json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "OTP enrollment changed, please try again".to_string(),
+                    data: None,
+                });
+            }
+            Err(_) => {
+                return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "Failed to update OTP status".to_string(),
+                    data: None,
+                });
+            }
+        }
+
+        // 新的验证器生效，旧的恢复码随之作废
+        let recovery_codes = match issue_recovery_codes(repo.get_ref(), &user_id).await {
+            Ok(codes) => codes,
+            Err(resp) => return resp,
         };
 
-        HttpResponse::Ok().json(ApiResponse::<()> {
+        // 首次注册：用户刚刚同时证明了密码和 OTP，直接完成登录
+        let tokens = if auth.enrollment {
+            let (access_token, refresh_token) = match issue_session_tokens(
+                repo.get_ref(),
+                &user,
+                SessionGrant::new(vec!["pwd".to_string(), "mfa".to_string()]),
+                device_info(&req),
+            )
+            .await
+            {
+                Ok(tokens) => tokens,
+                Err(resp) => return resp,
+            };
+
+            Some(OtpSueecessData {
+                access_token,
+                refresh_token,
+                user: UserData {
+                    id: user.id.to_string(),
+                    email: user.email,
+                    name: user.username,
+                },
+            })
+        } else {
+            None
+        };
+
+        HttpResponse::Ok().json(ApiResponse {
             status: "success".to_string(),
             message: "OTP verified successfully".to_string(),
-            data: None,
+            data: Some(OtpVerifiedData {
+                recovery_codes,
+                tokens,
+            }),
         })
     } else {
         HttpResponse::Unauthorized().json(ApiResponse::<()> {
@@ -244,126 +687,362 @@ async fn verify_otp(
 #[post("/auth/otp/validate")]
 async fn validate_otp(
     req: HttpRequest,
-    data: web::Json<VerifyOTPSchema>,
+    auth: MfaPendingUser,
+    data: web::Json<ValidateOTPSchema>,
     repo: web::Data<dyn UserRepository>,
 ) -> impl Responder {
-    // validate mfa token first
-    let token = match req.headers().get("Authorization") {
-        Some(header_value) => {
-            if let Ok(value) = header_value.to_str() {
-                if value.starts_with("Bearer ") {
-                    value.strip_prefix("Bearer ").unwrap().to_string()
-                } else {
-                    return HttpResponse::Unauthorized().json(ApiResponse::<()> {
-                        status: "error".to_string(),
-                        message: "Invalid token format".to_string(),
-                        data: None,
-                    });
-                }
-            } else {
-                return HttpResponse::Unauthorized().json(ApiResponse::<()> {
-                    status: "error".to_string(),
-                    message: "Invalid token".to_string(),
-                    data: None,
-                });
-            }
-        }
-        None => {
-            return HttpResponse::Unauthorized().json(ApiResponse::<()> {
+    let user = auth.user;
+
+    // 只接受已经验证过的 OTP 密钥
+    if !user.otp_active() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "OTP is not enabled for this user".to_string(),
+            data: None,
+        });
+    }
+
+    // 先检查请求体：没有提交任何验证码的请求不占用尝试次数
+    let factor = match (data.token.as_deref(), data.recovery_code.as_deref()) {
+        (Some(token), _) => SecondFactor::Otp(token),
+        (None, Some(recovery_code)) => SecondFactor::RecoveryCode(recovery_code),
+        (None, None) => {
+            return HttpResponse::BadRequest().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "Authorization header missing".to_string(),
+                message: "OTP token or recovery code is required".to_string(),
                 data: None,
             });
         }
     };
 
-    let claims = match validate_mfa_token(&token) {
-        Ok(claims) => claims,
-        Err(_) => {
+    // 单个 mfa token 的尝试次数有上限，校验前先占用一次，用完后只能重新输入密码登录
+    match repo
+        .claim_mfa_token_attempt(
+            &auth.jti,
+            &user.id,
+            &auth.expires_at,
+            MAX_MFA_TOKEN_FAILURES,
+        )
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
             return HttpResponse::Unauthorized().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "Invalid or expired MFA token".to_string(),
+                message: "Too many failed attempts, please log in again".to_string(),
                 data: None,
             });
         }
-    };
-
-    let user_id = match Uuid::from_str(&claims.sub) {
-        Ok(id) => id,
         Err(_) => {
-            return HttpResponse::BadRequest().json(ApiResponse::<()> {
+            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "Invalid user_id format in token".to_string(),
+                message: "Failed to verify MFA token".to_string(),
                 data: None,
             });
         }
-    };
+    }
 
-    let user = match repo.get_user_by_id(&user_id).await {
-        Ok(user) => user,
-        Err(_) => {
-            return HttpResponse::NotFound().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "User not found".to_string(),
-                data: None,
-            });
-        }
-    };
+    let amr = match factor {
+        SecondFactor::Otp(token) => {
+            if let Err(e) = check_user_otp(repo.get_ref(), &user, token).await {
+                return e.response();
+            }
 
-    if !user.otp_enabled.unwrap_or(false) {
-        return HttpResponse::BadRequest().json(ApiResponse::<()> {
-            status: "error".to_string(),
-            message: "OTP is not enabled for this user".to_string(),
-            data: None,
-        });
-    }
+            vec!["pwd".to_string(), "mfa".to_string()]
+        }
+        // 丢失验证器时使用恢复码，amr 中额外记录 "rc" 以便审计
+        SecondFactor::RecoveryCode(recovery_code) => {
+            // 恢复码与 OTP 共用按用户的失败计数和锁定，不能绕过锁定继续猜测
+            if let Err(e) = claim_otp_attempt(repo.get_ref(), &user).await {
+                return e.response();
+            }
+            let code_hash = hash_token(&normalize_recovery_code(recovery_code));
+            match repo.use_recovery_code(&user.id, &code_hash).await {
+                Ok(true) => {
+                    if repo.reset_otp_attempts(&user.id).await.is_err() {
+                        return OtpCheckError::Internal.response();
+                    }
+                }
+                Ok(false) => {
+                    return HttpResponse::Unauthorized().json(ApiResponse::<()> {
+                        status: "error".to_string(),
+                        message: "Invalid recovery code".to_string(),
+                        data: None,
+                    });
+                }
+                Err(_) => {
+                    return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                        status: "error".to_string(),
+                        message: "Failed to verify recovery code".to_string(),
+                        data: None,
+                    });
+                }
+            }
+
+            vec!["pwd".to_string(), "mfa".to_string(), "rc".to_string()]
+        }
+    };
 
-    let otp_base32 = user.otp_base32.to_owned().unwrap();
+    if repo.reset_mfa_token_attempts(&auth.jti).await.is_err() {
+        return OtpCheckError::Internal.response();
+    }
 
-    let totp = TOTP::new(
-        Algorithm::SHA1,
-        6,
-        1,
-        30,
-        Secret::Encoded(otp_base32).to_bytes().unwrap(),
+    let (access_token, refresh_token) = match issue_session_tokens(
+        repo.get_ref(),
+        &user,
+        SessionGrant::new(amr),
+        device_info(&req),
     )
-    .unwrap();
+    .await
+    {
+        Ok(tokens) => tokens,
+        Err(resp) => return resp,
+    };
 
-    let is_valid = totp.check_current(&data.token).unwrap();
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "OTP validated successfully".to_string(),
+        data: Some(OtpSueecessData {
+            access_token,
+            refresh_token,
+            user: UserData {
+                id: user.id.to_string(),
+                email: user.email,
+                name: user.username,
+            },
+        }),
+    })
+}
 
-    if !is_valid {
-        return HttpResponse::Unauthorized().json(ApiResponse::<()> {
+#[post("/auth/otp/disable")]
+async fn disable_otp(
+    auth: AuthenticatedUser,
+    data: web::Json<DisableOTPSchema>,
+    repo: web::Data<dyn UserRepository>,
+    hasher: web::Data<PasswordHasher>,
+) -> impl Responder {
+    // 关闭 MFA 属于高风险操作：令牌必须来自最近的 MFA 登录，并且要重新确认身份
+    if let Err(e) = auth.require_recent_mfa() {
+        return e.error_response();
+    }
+    let user = auth.user;
+    let user_id = user.id;
+
+    if !user.otp_active() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()> {
             status: "error".to_string(),
-            message: "Invalid OTP token".to_string(),
+            message: "OTP is already disabled for this user".to_string(),
             data: None,
         });
     }
 
-    let access_token = match generate_access_token(&user.id) {
-        Ok(token) => token,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+    let confirmed = match (&data.password, &data.token) {
+        // 密码确认与 OTP 共用连续失败次数和锁定，不能借此接口暴力猜测密码
+        (Some(password), _) => {
+            if let Err(e) = claim_otp_attempt(repo.get_ref(), &user).await {
+                return e.response();
+            }
+            match hasher.verify(password, &user.password_hash).await {
+                Ok(check) if check.valid => {
+                    if repo.reset_otp_attempts(&user_id).await.is_err() {
+                        return OtpCheckError::Internal.response();
+                    }
+                    true
+                }
+                Ok(_) => false,
+                Err(e) => return e.response(),
+            }
+        }
+        (None, Some(token)) => match check_user_otp(repo.get_ref(), &user, token).await {
+            Ok(()) => true,
+            Err(e @ (OtpCheckError::Locked(_) | OtpCheckError::Internal)) => {
+                return e.response();
+            }
+            Err(_) => false,
+        },
+        (None, None) => {
+            return HttpResponse::BadRequest().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "Failed to generate access token".to_string(),
+                message: "Password or OTP token is required".to_string(),
                 data: None,
             });
         }
     };
 
-    let refresh_token = match generate_refresh_token(&user.id) {
-        Ok(token) => token,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "Failed to generate refresh token".to_string(),
-                data: None,
-            });
+    if !confirmed {
+        return HttpResponse::Unauthorized().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Re-authentication failed".to_string(),
+            data: None,
+        });
+    }
+
+    if repo.disable_user_otp(&user_id).await.is_err() {
+        return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to disable OTP".to_string(),
+            data: None,
+        });
+    }
+
+    HttpResponse::Ok().json(ApiResponse::<()> {
+        status: "success".to_string(),
+        message: "OTP disabled successfully".to_string(),
+        data: None,
+    })
+}
+
+#[get("/auth/otp/recovery-codes")]
+async fn recovery_codes_status(
+    auth: AuthenticatedUser,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    match repo.count_unused_recovery_codes(&auth.user.id).await {
+        Ok(remaining) => HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Recovery codes fetched successfully".to_string(),
+            data: Some(json!({ "remaining": remaining })),
+        }),
+        Err(_) => HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to fetch recovery codes".to_string(),
+            data: None,
+        }),
+    }
+}
+
+// 重新生成恢复码需要提供当前的 OTP，旧的恢复码全部作废
+#[post("/auth/otp/recovery-codes/regenerate")]
+async fn regenerate_recovery_codes(
+    auth: AuthenticatedUser,
+    data: web::Json<VerifyOTPSchema>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    let user = auth.user;
+
+    if !user.otp_active() {
+        return HttpResponse::BadRequest().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "OTP is not enabled for this user".to_string(),
+            data: None,
+        });
+    }
+
+    if let Err(e) = check_user_otp(repo.get_ref(), &user, &data.token).await {
+        return e.response();
+    }
+
+    match issue_recovery_codes(repo.get_ref(), &user.id).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "Recovery codes regenerated successfully".to_string(),
+            data: Some(RecoveryCodesData { recovery_codes }),
+        }),
+        Err(resp) => resp,
+    }
+}
+
+// 轮换 refresh token 时可能出现的失败
+pub(crate) enum RefreshError {
+    Invalid,
+    Revoked,
+    // 已经轮换过的 refresh token 被再次使用，整个 family 已被吊销
+    ReuseDetected,
+    UserNotFound,
+    Internal(&'static str),
+}
+
+impl RefreshError {
+    fn response(&self) -> HttpResponse {
+        let (status, message) = match self {
+            RefreshError::Invalid => (StatusCode::UNAUTHORIZED, "Invalid or expired refresh token"),
+            RefreshError::Revoked => (StatusCode::UNAUTHORIZED, "Session has been revoked"),
+            RefreshError::ReuseDetected => (
+                StatusCode::UNAUTHORIZED,
+                "Refresh token reuse detected, session revoked",
+            ),
+            RefreshError::UserNotFound => (StatusCode::UNAUTHORIZED, "User not found"),
+            RefreshError::Internal(message) => (StatusCode::INTERNAL_SERVER_ERROR, *message),
+        };
+        HttpResponse::build(status).json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: message.to_string(),
+            data: None,
+        })
+    }
+}
+
+// 校验 refresh token 并把对应的 session 标记为已轮换，返回旧 session 和用户
+// client_id 必须与签发时一致：SPA 的会话为 None，OAuth 会话为对应客户端
+pub(crate) async fn rotate_refresh_token(
+    repo: &dyn UserRepository,
+    refresh_token: &str,
+    client_id: Option<&str>,
+) -> Result<(Session, User), RefreshError> {
+    let session = match validate_refresh_token(refresh_token, repo).await {
+        Ok((_, session)) if session.client_id.as_deref() == client_id => session,
+        _ => return Err(RefreshError::Invalid),
+    };
+
+    if session.revoked_at.is_some() {
+        return Err(RefreshError::Revoked);
+    }
+
+    // 已经轮换过的 refresh token 再次出现，说明它可能已被窃取：吊销整个 family
+    // rotate_session 返回 false 时同理（并发请求抢先完成了轮换）
+    let rotated = match repo.rotate_session(&session.jti).await {
+        Ok(rotated) => session.rotated_at.is_none() && rotated,
+        Err(_) => return Err(RefreshError::Internal("Failed to rotate session")),
+    };
+
+    if !rotated {
+        if repo
+            .revoke_session_family(&session.family_id)
+            .await
+            .is_err()
+        {
+            return Err(RefreshError::Internal("Failed to revoke session"));
         }
+        return Err(RefreshError::ReuseDetected);
+    }
+
+    // 用户可能在 refresh token 有效期内被删除，必须重新确认
+    let user = repo
+        .get_user_by_id(&session.user_id)
+        .await
+        .map_err(|_| RefreshError::UserNotFound)?;
+
+    Ok((session, user))
+}
+
+#[post("/auth/token/refresh")]
+async fn refresh_tokens(
+    req: HttpRequest,
+    data: web::Json<RefreshTokenRequest>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    // OAuth 客户端的 refresh token 只能通过 /oauth/token 使用
+    let (session, user) =
+        match rotate_refresh_token(repo.get_ref(), &data.refresh_token, None).await {
+            Ok(rotated) => rotated,
+            Err(e) => return e.response(),
+        };
+
+    let (access_token, refresh_token) = match issue_session_tokens(
+        repo.get_ref(),
+        &user,
+        SessionGrant::from_session(&session),
+        device_info(&req),
+    )
+    .await
+    {
+        Ok(tokens) => tokens,
+        Err(resp) => return resp,
     };
 
     HttpResponse::Ok().json(ApiResponse {
         status: "success".to_string(),
-        message: "OTP validated successfully".to_string(),
+        message: "Token refreshed successfully".to_string(),
         data: Some(OtpSueecessData {
             access_token,
             refresh_token,
@@ -376,71 +1055,912 @@ async fn validate_otp(
     })
 }
 
-#[post("/auth/otp/disable")]
-async fn disable_otp(
-    data: web::Json<DisableOTPSchema>,
-    repo: web::Data<dyn UserRepository>,
-) -> impl Responder {
-    let user_id = match Uuid::from_str(&data.user_id) {
-        Ok(id) => id,
-        Err(_) => {
+// 登出当前会话：吊销 access token 所属的 session family
+#[post("/auth/logout")]
+async fn logout(auth: AuthenticatedUser, repo: web::Data<dyn UserRepository>) -> impl Responder {
+    let family_id = match auth.claims.sid.as_deref().map(Uuid::from_str) {
+        Some(Ok(id)) => id,
+        _ => {
             return HttpResponse::BadRequest().json(ApiResponse::<()> {
                 status: "error".to_string(),
-                message: "Invalid user_id format".to_string(),
+                message: "Token is not bound to a session".to_string(),
                 data: None,
             });
         }
     };
 
-    let user = match repo.get_user_by_id(&user_id).await {
-        Ok(user) => user,
-        Err(_) => {
-            return HttpResponse::NotFound().json(ApiResponse::<()> {
-                status: "error".to_string(),
-                message: "User not found".to_string(),
-                data: None,
-            });
-        }
-    };
+    if repo.revoke_session_family(&family_id).await.is_err() {
+        return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to revoke session".to_string(),
+            data: None,
+        });
+    }
 
-    if !user.otp_enabled.unwrap_or(false) {
-        return HttpResponse::BadRequest().json(ApiResponse::<()> {
+    HttpResponse::Ok().json(ApiResponse::<()> {
+        status: "success".to_string(),
+        message: "Logged out successfully".to_string(),
+        data: None,
+    })
+}
+
+// 登出所有设备：吊销用户全部 session，并让已签发的 access token 全部失效
+#[post("/auth/logout-all")]
+async fn logout_all(
+    auth: AuthenticatedUser,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    let user_id = auth.user.id;
+
+    if repo.revoke_user_sessions(&user_id).await.is_err()
+        || repo.increment_token_version(&user_id).await.is_err()
+    {
+        return HttpResponse::InternalServerError().json(ApiResponse::<()> {
             status: "error".to_string(),
-            message: "OTP is already disabled for this user".to_string(),
+            message: "Failed to revoke sessions".to_string(),
+            data: None,
+        });
+    }
+
+    HttpResponse::Ok().json(ApiResponse::<()> {
+        status: "success".to_string(),
+        message: "Logged out from all sessions".to_string(),
+        data: None,
+    })
+}
+
+// /user scope 整体由 require_auth 中间件保护
+#[get("/me")]
+async fn me(auth: AuthenticatedUser) -> impl Responder {
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "User fetched successfully".to_string(),
+        data: Some(json!({
+            "user": UserData {
+                id: auth.user.id.to_string(),
+                email: auth.user.email,
+                name: auth.user.username,
+            }
+        })),
+    })
+}
+
+// 修改密码是高风险操作：除了当前密码，还要求最近完成过 MFA
+// 成功后保留当前会话，吊销其他所有会话
+#[post("/change-password")]
+async fn change_password(
+    auth: AuthenticatedUser,
+    data: web::Json<ChangePasswordRequest>,
+    repo: web::Data<dyn UserRepository>,
+    hasher: web::Data<PasswordHasher>,
+    policy: web::Data<PasswordPolicy>,
+) -> impl Responder {
+    if let Err(e) = auth.require_recent_mfa() {
+        return e.error_response();
+    }
+
+    if let Err(e) = policy
+        .check(&data.new_password, &[&auth.user.username, &auth.user.email])
+        .await
+    {
+        return e.response();
+    }
+
+    let check = match hasher
+        .verify(&data.current_password, &auth.user.password_hash)
+        .await
+    {
+        Ok(check) => check,
+        Err(e) => return e.response(),
+    };
+    if !check.valid {
+        return HttpResponse::Unauthorized().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Current password is incorrect".to_string(),
             data: None,
         });
     }
 
-    if repo.update_user_otp(&user_id, "", "").await.is_err() {
+    let password_hash = match hasher.hash(&data.new_password).await {
+        Ok(h) => h,
+        Err(e) => return e.response(),
+    };
+    if repo
+        .update_user_password(&auth.user.id, &password_hash)
+        .await
+        .is_err()
+    {
         return HttpResponse::InternalServerError().json(ApiResponse::<()> {
             status: "error".to_string(),
-            message: "Failed to disable OTP".to_string(),
+            message: "Failed to update password".to_string(),
             data: None,
         });
     }
 
-    if repo.disable_user_otp(&user_id).await.is_err() {
+    // 其他会话的 access token 带有 sid，session family 被吊销后立即失效
+    // 没有 sid 的令牌无法区分当前会话，只能全部吊销
+    let current_family = auth
+        .claims
+        .sid
+        .as_deref()
+        .and_then(|sid| Uuid::parse_str(sid).ok());
+    let revoked = match current_family {
+        Some(family_id) => repo
+            .revoke_other_user_sessions(&auth.user.id, &family_id)
+            .await
+            .is_ok(),
+        None => {
+            repo.revoke_user_sessions(&auth.user.id).await.is_ok()
+                && repo.increment_token_version(&auth.user.id).await.is_ok()
+        }
+    };
+    if !revoked {
         return HttpResponse::InternalServerError().json(ApiResponse::<()> {
             status: "error".to_string(),
-            message: "Failed to disable OTP".to_string(),
+            message: "Failed to revoke sessions".to_string(),
             data: None,
         });
     }
 
     HttpResponse::Ok().json(ApiResponse::<()> {
         status: "success".to_string(),
-        message: "OTP disabled successfully".to_string(),
+        message: "Password changed successfully".to_string(),
         data: None,
     })
 }
+
 // --- 关键的 Actix-web 模式 ---
 // 这个函数会配置这个模块的所有路由
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(hello)
         .service(login)
         .service(register)
+        .service(forgot_password)
+        .service(reset_password)
         .service(generate_otp)
         .service(verify_otp)
         .service(validate_otp)
-        .service(disable_otp);
+        .service(disable_otp)
+        .service(recovery_codes_status)
+        .service(regenerate_recovery_codes)
+        .service(refresh_tokens)
+        .service(logout)
+        .service(logout_all)
+        .service(
+            web::scope("/user")
+                .wrap(middleware::from_fn(require_auth))
+                .service(me)
+                .service(change_password),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, http::StatusCode, test};
+    use serde_json::Value;
+    use sqlx::PgPool;
+    use tokio::task::JoinSet;
+    use totp_rs::TOTP;
+
+    use super::*;
+    use crate::{
+        password::HashAlgorithm,
+        test_support::{create_user, repository},
+        utils::{MAX_OTP_FAILURES, validate_access_token},
+    };
+
+    // 与 validate_otp 签发的 amr 相同
+    fn mfa_amr() -> Vec<String> {
+        vec!["pwd".to_string(), "mfa".to_string()]
+    }
+
+    async fn sign_in(repo: &dyn UserRepository, user: &User) -> (String, String) {
+        issue_session_tokens(repo, user, SessionGrant::new(mfa_amr()), None)
+            .await
+            .expect("Failed to issue tokens")
+    }
+
+    fn refresh_request(refresh_token: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/auth/token/refresh")
+            .set_json(json!({ "refresh_token": refresh_token }))
+    }
+
+    #[sqlx::test]
+    async fn refresh_token_can_only_be_rotated_once(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (_, refresh_token) = sign_in(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let resp = test::call_service(&app, refresh_request(&refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_ne!(body["refresh_token"], refresh_token);
+
+        let resp = test::call_service(&app, refresh_request(&refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn reused_refresh_token_revokes_the_whole_family(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "bob").await;
+        let (_, first) = sign_in(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let body: Value =
+            test::call_and_read_body_json(&app, refresh_request(&first).to_request()).await;
+        let second = body["refresh_token"].as_str().unwrap().to_string();
+
+        // 攻击者重放已轮换的令牌：合法用户手中的新令牌也随之失效
+        let resp = test::call_service(&app, refresh_request(&first).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, refresh_request(&second).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn other_sessions_survive_a_reuse_detection(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "carol").await;
+        let (_, stolen) = sign_in(repo.as_ref(), &user).await;
+        let (_, other_device) = sign_in(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        test::call_service(&app, refresh_request(&stolen).to_request()).await;
+        test::call_service(&app, refresh_request(&stolen).to_request()).await;
+
+        let resp = test::call_service(&app, refresh_request(&other_device).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn unknown_refresh_token_is_rejected(pool: PgPool) {
+        let repo = repository(pool);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let resp = test::call_service(&app, refresh_request("not-a-token").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    fn authorized_post(uri: &str, token: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {token}")))
+    }
+
+    #[sqlx::test]
+    async fn logout_only_ends_the_current_session(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (access_token, refresh_token) = sign_in(repo.as_ref(), &user).await;
+        let (other_access_token, other_refresh_token) = sign_in(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let req = authorized_post("/auth/logout", &access_token).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // 当前会话的 access token 和 refresh token 都已失效
+        let req = authorized_post("/auth/logout", &access_token).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let resp = test::call_service(&app, refresh_request(&refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // 其他设备不受影响
+        assert!(
+            validate_access_token(&other_access_token, repo.as_ref())
+                .await
+                .is_ok()
+        );
+        let resp =
+            test::call_service(&app, refresh_request(&other_refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn logout_all_ends_every_session(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "bob").await;
+        let (access_token, _) = sign_in(repo.as_ref(), &user).await;
+        let (other_access_token, other_refresh_token) = sign_in(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let req = authorized_post("/auth/logout-all", &access_token).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        assert!(
+            validate_access_token(&other_access_token, repo.as_ref())
+                .await
+                .is_err()
+        );
+        let resp =
+            test::call_service(&app, refresh_request(&other_refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // 下一个时间步的验证码，仍在允许的偏移范围内
+    fn next_code(totp: &TOTP) -> String {
+        totp.generate(Utc::now().timestamp() as u64 + 30)
+    }
+
+    #[sqlx::test]
+    async fn otp_is_only_enabled_after_verify(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let enrollment_token = generate_enrollment_token(&user.id).unwrap();
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let req = authorized_post("/auth/otp/generate", &enrollment_token).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let totp = build_totp(body["otp_base32"].as_str().unwrap());
+
+        // 未验证的 pending 密钥不能用于登录
+        let req = authorized_post("/auth/otp/validate", &mfa_token)
+            .set_json(json!({ "token": totp.generate_current().unwrap() }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        let pending = repo.get_user_by_id(&user.id).await.unwrap();
+        assert!(!pending.otp_active());
+
+        let req = authorized_post("/auth/otp/verify", &enrollment_token)
+            .set_json(json!({ "token": totp.generate_current().unwrap() }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let enabled = repo.get_user_by_id(&user.id).await.unwrap();
+        assert!(enabled.otp_active());
+        assert!(enabled.otp_pending_base32.is_none());
+
+        let req = authorized_post("/auth/otp/validate", &mfa_token)
+            .set_json(json!({ "token": next_code(&totp) }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body["access_token"].is_string());
+    }
+
+    #[sqlx::test]
+    async fn re_enrollment_keeps_the_active_secret_until_verified(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "bob").await;
+        let enrollment_token = generate_enrollment_token(&user.id).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .configure(config),
+        )
+        .await;
+
+        let req = authorized_post("/auth/otp/generate", &enrollment_token).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let active = body["otp_base32"].as_str().unwrap().to_string();
+        let req = authorized_post("/auth/otp/verify", &enrollment_token)
+            .set_json(json!({ "token": build_totp(&active).generate_current().unwrap() }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let (access_token, _) = sign_in(repo.as_ref(), &user).await;
+        let req = authorized_post("/auth/otp/generate", &access_token).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_ne!(body["otp_base32"], active);
+
+        let user = repo.get_user_by_id(&user.id).await.unwrap();
+        assert!(user.otp_active());
+        assert_eq!(user.otp_base32.as_deref(), Some(active.as_str()));
+    }
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    async fn create_user_with_password(repo: &dyn UserRepository, name: &str) -> User {
+        let request = RegisterRequest {
+            username: name.to_string(),
+            email: format!("{name}@example.com"),
+            password: PASSWORD.to_string(),
+        };
+        let password_hash = bcrypt::hash(PASSWORD, 4).unwrap();
+        repo.create_user(&request, &password_hash).await.unwrap()
+    }
+
+    // bcrypt 最低成本，测试中尽快完成哈希
+    fn test_hasher() -> web::Data<PasswordHasher> {
+        web::Data::new(PasswordHasher::new(HashAlgorithm::Bcrypt(4), 1, 8))
+    }
+
+    fn login_request(email: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/auth/login")
+            .set_json(json!({ "email": email, "password": PASSWORD }))
+    }
+
+    #[sqlx::test]
+    async fn login_asks_unenrolled_users_to_enroll(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user_with_password(repo.as_ref(), "alice").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .configure(config),
+        )
+        .await;
+
+        let body: Value =
+            test::call_and_read_body_json(&app, login_request(&user.email).to_request()).await;
+        assert_eq!(body["next_step"], "mfa_enrollment_required");
+        assert!(body["enrollment_token"].is_string());
+        assert!(body.get("mfa_token").is_none());
+        assert!(body.get("access_token").is_none());
+
+        // enrollment token 不能当作 access token 使用
+        let enrollment_token = body["enrollment_token"].as_str().unwrap();
+        let req = authorized_post("/auth/logout", enrollment_token).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[sqlx::test]
+    async fn enrollment_token_is_rejected_once_a_factor_exists(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user_with_password(repo.as_ref(), "bob").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .configure(config),
+        )
+        .await;
+
+        let body: Value =
+            test::call_and_read_body_json(&app, login_request(&user.email).to_request()).await;
+        let enrollment_token = body["enrollment_token"].as_str().unwrap().to_string();
+        let req = authorized_post("/auth/otp/generate", &enrollment_token).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let totp = build_totp(body["otp_base32"].as_str().unwrap());
+        let req = authorized_post("/auth/otp/verify", &enrollment_token)
+            .set_json(json!({ "token": totp.generate_current().unwrap() }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body["access_token"].is_string());
+
+        // 已有第二因子后，只凭密码换来的 enrollment token 不能再替换它
+        let req = authorized_post("/auth/otp/generate", &enrollment_token).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let body: Value =
+            test::call_and_read_body_json(&app, login_request(&user.email).to_request()).await;
+        assert_eq!(body["next_step"], "mfa_required");
+        assert!(body["mfa_token"].is_string());
+        assert!(body.get("enrollment_token").is_none());
+    }
+
+    const OTP_SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+    // 与当前时间步（及前后各一步）的验证码都不相同的概率极高
+    const WRONG_OTP: &str = "000000";
+
+    // 启用 OTP；激活时记录的时间步早于当前，当前的验证码还可以使用一次
+    async fn enable_otp(repo: &dyn UserRepository, user: &User) -> (User, TOTP) {
+        let expires_at = Utc::now() + Duration::minutes(OTP_ENROLLMENT_MINUTES);
+        repo.set_pending_user_otp(&user.id, OTP_SECRET, "otpauth://test", &expires_at)
+            .await
+            .expect("Failed to set pending OTP");
+        assert!(
+            repo.activate_pending_user_otp(&user.id, OTP_SECRET, 0)
+                .await
+                .expect("Failed to activate OTP")
+        );
+        let user = repo.get_user_by_id(&user.id).await.unwrap();
+        (user, build_totp(OTP_SECRET))
+    }
+
+    #[sqlx::test]
+    async fn otp_code_cannot_be_replayed(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (user, totp) = enable_otp(repo.as_ref(), &user).await;
+        let code = totp.generate_current().unwrap();
+
+        assert!(check_user_otp(repo.as_ref(), &user, &code).await.is_ok());
+        assert!(matches!(
+            check_user_otp(repo.as_ref(), &user, &code).await,
+            Err(OtpCheckError::Replayed)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn otp_is_locked_after_repeated_failures(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (mut user, totp) = enable_otp(repo.as_ref(), &user).await;
+
+        for _ in 0..MAX_OTP_FAILURES {
+            assert!(matches!(
+                check_user_otp(repo.as_ref(), &user, WRONG_OTP).await,
+                Err(OtpCheckError::Invalid)
+            ));
+            user = repo.get_user_by_id(&user.id).await.unwrap();
+        }
+        // 锁定期内正确的验证码也被拒绝
+        let code = totp.generate_current().unwrap();
+        assert!(matches!(
+            check_user_otp(repo.as_ref(), &user, &code).await,
+            Err(OtpCheckError::Locked(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn successful_otp_resets_the_failure_count(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (user, totp) = enable_otp(repo.as_ref(), &user).await;
+
+        for _ in 1..MAX_OTP_FAILURES {
+            assert!(
+                check_user_otp(repo.as_ref(), &user, WRONG_OTP)
+                    .await
+                    .is_err()
+            );
+        }
+        let code = totp.generate_current().unwrap();
+        assert!(check_user_otp(repo.as_ref(), &user, &code).await.is_ok());
+
+        let user = repo.get_user_by_id(&user.id).await.unwrap();
+        assert_eq!(user.failed_otp_attempts, 0);
+        assert!(user.otp_locked_until.is_none());
+    }
+
+    #[sqlx::test]
+    async fn concurrent_otp_attempts_cannot_bypass_the_lockout(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (user, _) = enable_otp(repo.as_ref(), &user).await;
+
+        let mut attempts = JoinSet::new();
+        for _ in 0..20 {
+            let (repo, user) = (repo.clone(), user.clone());
+            attempts.spawn(async move {
+                matches!(
+                    check_user_otp(repo.as_ref(), &user, WRONG_OTP).await,
+                    Err(OtpCheckError::Invalid)
+                )
+            });
+        }
+        // 只有锁定之前占用到尝试次数的请求才会真正校验验证码
+        let verified = attempts
+            .join_all()
+            .await
+            .into_iter()
+            .filter(|verified| *verified)
+            .count();
+        assert_eq!(verified, MAX_OTP_FAILURES as usize);
+    }
+
+    #[sqlx::test]
+    async fn recovery_code_failures_count_toward_the_user_lockout(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (user, totp) = enable_otp(repo.as_ref(), &user).await;
+        issue_recovery_codes(repo.as_ref(), &user.id)
+            .await
+            .expect("Failed to issue recovery codes");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        for _ in 0..MAX_OTP_FAILURES {
+            let req = authorized_post("/auth/otp/validate", &mfa_token)
+                .set_json(json!({ "recovery_code": "wrong-code" }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // 换一个新的 mfa token 也无法绕过按用户的锁定
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        let req = authorized_post("/auth/otp/validate", &mfa_token)
+            .set_json(json!({ "token": totp.generate_current().unwrap() }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[sqlx::test]
+    async fn mfa_token_is_rejected_after_too_many_failures(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (user, totp) = enable_otp(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        for _ in 0..MAX_MFA_TOKEN_FAILURES {
+            let req = authorized_post("/auth/otp/validate", &mfa_token)
+                .set_json(json!({ "recovery_code": "wrong-code" }))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+
+        // 该 mfa token 已作废，正确的验证码也无法再换取令牌
+        let req = authorized_post("/auth/otp/validate", &mfa_token)
+            .set_json(json!({ "token": totp.generate_current().unwrap() }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["status"], "error");
+        assert!(body.get("access_token").is_none());
+    }
+
+    #[sqlx::test]
+    async fn empty_validate_requests_do_not_use_up_the_mfa_token(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (user, totp) = enable_otp(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        for _ in 0..=MAX_MFA_TOKEN_FAILURES {
+            let req = authorized_post("/auth/otp/validate", &mfa_token)
+                .set_json(json!({}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let req = authorized_post("/auth/otp/validate", &mfa_token)
+            .set_json(json!({ "token": totp.generate_current().unwrap() }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["status"], "success");
+    }
+
+    #[sqlx::test]
+    async fn reset_token_is_single_use_and_ends_every_session(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user_with_password(repo.as_ref(), "alice").await;
+        let (access_token, refresh_token) = sign_in(repo.as_ref(), &user).await;
+        let reset_token = "reset-token";
+        repo.create_password_reset_token(
+            &user.id,
+            &hash_token(reset_token),
+            &(Utc::now() + Duration::hours(1)),
+        )
+        .await
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .app_data(web::Data::new(PasswordPolicy::from_env()))
+                .configure(config),
+        )
+        .await;
+        let reset_request = |new_password: &str| {
+            test::TestRequest::post()
+                .uri("/auth/password/reset")
+                .set_json(json!({ "token": reset_token, "new_password": new_password }))
+        };
+
+        let req = reset_request("correct horse battery staple").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        assert!(
+            validate_access_token(&access_token, repo.as_ref())
+                .await
+                .is_err()
+        );
+        let resp = test::call_service(&app, refresh_request(&refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // 令牌已使用，第二次重置不会生效
+        let req = reset_request("another horse battery staple").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let user = repo.get_user_by_id(&user.id).await.unwrap();
+        assert!(bcrypt::verify("correct horse battery staple", &user.password_hash).unwrap());
+    }
+
+    fn disable_otp_request(access_token: &str, password: &str) -> test::TestRequest {
+        authorized_post("/auth/otp/disable", access_token).set_json(json!({ "password": password }))
+    }
+
+    #[sqlx::test]
+    async fn disable_otp_requires_a_recent_mfa(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user_with_password(repo.as_ref(), "alice").await;
+        let (user, _) = enable_otp(repo.as_ref(), &user).await;
+        let stale_grant = SessionGrant {
+            auth_time: Utc::now() - Duration::days(1),
+            ..SessionGrant::new(mfa_amr())
+        };
+        let (access_token, _) = issue_session_tokens(repo.as_ref(), &user, stale_grant, None)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .configure(config),
+        )
+        .await;
+
+        let req = disable_otp_request(&access_token, PASSWORD).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let user = repo.get_user_by_id(&user.id).await.unwrap();
+        assert!(user.otp_active());
+    }
+
+    #[sqlx::test]
+    async fn disable_otp_password_attempts_are_locked_out(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user_with_password(repo.as_ref(), "alice").await;
+        let (user, _) = enable_otp(repo.as_ref(), &user).await;
+        let (access_token, _) = sign_in(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .configure(config),
+        )
+        .await;
+
+        for _ in 0..MAX_OTP_FAILURES {
+            let req = disable_otp_request(&access_token, "wrong-password").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // 锁定期内正确的密码也会被拒绝
+        let req = disable_otp_request(&access_token, PASSWORD).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let user = repo.get_user_by_id(&user.id).await.unwrap();
+        assert!(user.otp_active());
+    }
+
+    fn change_password_request(access_token: &str) -> test::TestRequest {
+        authorized_post("/user/change-password", access_token).set_json(json!({
+            "current_password": PASSWORD,
+            "new_password": "correct horse battery staple",
+        }))
+    }
+
+    #[sqlx::test]
+    async fn change_password_requires_a_recent_mfa(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user_with_password(repo.as_ref(), "alice").await;
+        let (password_only, _) = issue_session_tokens(
+            repo.as_ref(),
+            &user,
+            SessionGrant::new(vec!["pwd".to_string()]),
+            None,
+        )
+        .await
+        .unwrap();
+        let stale_grant = SessionGrant {
+            auth_time: Utc::now() - Duration::days(1),
+            ..SessionGrant::new(mfa_amr())
+        };
+        let (stale_mfa, _) = issue_session_tokens(repo.as_ref(), &user, stale_grant, None)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .app_data(web::Data::new(PasswordPolicy::from_env()))
+                .configure(config),
+        )
+        .await;
+
+        for access_token in [password_only, stale_mfa] {
+            // 只有密码的令牌在 MFA 强制时会被中间件拒绝
+            let req = change_password_request(&access_token).to_request();
+            let status = match test::try_call_service(&app, req).await {
+                Ok(resp) => resp.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        let user = repo.get_user_by_id(&user.id).await.unwrap();
+        assert!(bcrypt::verify(PASSWORD, &user.password_hash).unwrap());
+    }
+
+    #[sqlx::test]
+    async fn change_password_revokes_the_other_sessions(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user_with_password(repo.as_ref(), "alice").await;
+        let (access_token, refresh_token) = sign_in(repo.as_ref(), &user).await;
+        let (other_access_token, other_refresh_token) = sign_in(repo.as_ref(), &user).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(test_hasher())
+                .app_data(web::Data::new(PasswordPolicy::from_env()))
+                .configure(config),
+        )
+        .await;
+
+        let req = change_password_request(&access_token).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        assert!(
+            validate_access_token(&access_token, repo.as_ref())
+                .await
+                .is_ok()
+        );
+        assert!(
+            validate_access_token(&other_access_token, repo.as_ref())
+                .await
+                .is_err()
+        );
+        let resp =
+            test::call_service(&app, refresh_request(&other_refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, refresh_request(&refresh_token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,347 @@
+use actix_web::{HttpRequest, HttpResponse, http::header, post, web};
+use chrono::DateTime;
+
+use crate::{
+    models::{IntrospectionData, OAuthTokenActionRequest},
+    oauth::{OAuthError, authenticate_client},
+    repositories::UserRepository,
+    utils::{ValidatedToken, validate_token},
+};
+
+// 管理员授予该 scope 的客户端可以内省任意 access token，动态注册时不能申请
+pub const INTROSPECT_SCOPE: &str = "introspect";
+
+// 令牌内省（RFC 7662）：资源服务器用它确认令牌是否仍然有效
+// 只有机密客户端可以调用，公开客户端无法证明自己的身份
+// access token 的 aud 须在调用方的 allowed_audiences 中，refresh token 只能由签发对象内省，
+// 其余情况一律返回 inactive，不透露令牌是否有效
+#[post("/oauth/introspect")]
+async fn introspect(
+    req: HttpRequest,
+    form: web::Form<OAuthTokenActionRequest>,
+    repo: web::Data<dyn UserRepository>,
+) -> Result<HttpResponse, OAuthError> {
+    let client = authenticate_client(&req, &form.client, repo.get_ref()).await?;
+    if client.token_endpoint_auth_method == "none" {
+        return Err(OAuthError::InvalidClient(
+            "Public clients cannot introspect tokens",
+        ));
+    }
+
+    let may_introspect = |aud: Option<&str>| {
+        client
+            .allowed_scopes
+            .iter()
+            .any(|scope| scope == INTROSPECT_SCOPE)
+            || aud.is_some_and(|aud| {
+                client
+                    .allowed_audiences
+                    .iter()
+                    .any(|allowed| allowed == aud)
+            })
+    };
+
+    // 与各接口使用同一套检查：过期、登出、轮换、吊销的令牌都是 inactive
+    let data = match validate_token(&form.token, repo.get_ref()).await {
+        Ok(ValidatedToken::Access(claims)) if may_introspect(claims.aud.as_deref()) => {
+            IntrospectionData {
+                active: true,
+                token_type: Some("access_token".to_string()),
+                sub: Some(claims.sub),
+                aud: claims.aud,
+                iss: claims.iss,
+                exp: Some(claims.exp),
+                iat: claims.iat,
+                jti: claims.jti,
+                client_id: claims.client_id,
+                scope: claims.scope,
+                amr: claims.amr,
+                acr: claims.acr,
+                auth_time: claims.auth_time,
+            }
+        }
+        Ok(ValidatedToken::Client(claims)) if may_introspect(claims.aud.as_deref()) => {
+            IntrospectionData {
+                active: true,
+                token_type: Some("access_token".to_string()),
+                sub: Some(claims.sub),
+                aud: claims.aud,
+                iss: claims.iss,
+                exp: Some(claims.exp),
+                iat: claims.iat,
+                jti: claims.jti,
+                client_id: claims.client_id,
+                scope: claims.scope,
+                ..IntrospectionData::default()
+            }
+        }
+        // refresh token 本身不带 amr 和 scope，以 session 中的记录为准
+        Ok(ValidatedToken::Refresh(claims, session))
+            if session.client_id.as_deref() == Some(client.client_id.as_str()) =>
+        {
+            IntrospectionData {
+                active: true,
+                token_type: Some("refresh_token".to_string()),
+                sub: Some(claims.sub),
+                aud: claims.aud,
+                iss: claims.iss,
+                exp: Some(claims.exp),
+                iat: claims.iat,
+                jti: claims.jti,
+                client_id: session.client_id,
+                scope: session.scope,
+                amr: Some(session.amr),
+                auth_time: Some(session.auth_time.timestamp() as usize),
+                ..IntrospectionData::default()
+            }
+        }
+        _ => IntrospectionData::default(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(data))
+}
+
+// 令牌吊销（RFC 7009）：客户端只能吊销签发给自己的令牌
+// 无效的令牌、不属于该客户端的令牌同样返回 200，不透露令牌是否存在
+#[post("/oauth/revoke")]
+async fn revoke(
+    req: HttpRequest,
+    form: web::Form<OAuthTokenActionRequest>,
+    repo: web::Data<dyn UserRepository>,
+) -> Result<HttpResponse, OAuthError> {
+    let client = authenticate_client(&req, &form.client, repo.get_ref()).await?;
+    let own = |client_id: Option<&str>| client_id == Some(client.client_id.as_str());
+
+    match validate_token(&form.token, repo.get_ref()).await {
+        // 吊销 refresh token 时吊销整个 session family，由它换取的 access token 随之失效
+        Ok(ValidatedToken::Refresh(_, session)) if own(session.client_id.as_deref()) => repo
+            .revoke_session_family(&session.family_id)
+            .await
+            .map_err(|_| OAuthError::ServerError("Failed to revoke token"))?,
+        Ok(ValidatedToken::Access(claims)) | Ok(ValidatedToken::Client(claims))
+            if own(claims.client_id.as_deref()) =>
+        {
+            let (Some(jti), Some(expires_at)) = (
+                claims.jti.as_deref(),
+                DateTime::from_timestamp(claims.exp as i64, 0),
+            ) else {
+                return Err(OAuthError::ServerError("Failed to revoke token"));
+            };
+            repo.revoke_token(jti, &expires_at)
+                .await
+                .map_err(|_| OAuthError::ServerError("Failed to revoke token"))?
+        }
+        _ => {}
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish())
+}
+
+// 挂在根路径下，与 /oauth/token 并列
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(introspect).service(revoke);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, http::StatusCode, test};
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        handlers::issue_session_tokens,
+        models::SessionGrant,
+        oauth,
+        test_support::{create_user, repository},
+        utils::{hash_token, validate_access_token},
+    };
+
+    const SERVICE_SECRET: &str = "service-secret";
+    const AUDIENCE: &str = "https://api.example.com";
+
+    async fn create_reference_client(pool: &PgPool, client_id: &str, auth_method: &str) {
+        sqlx::query(
+            "INSERT INTO oauth_clients (client_id, name, allowed_scopes, token_endpoint_auth_method, client_secret_hash, grant_types, allowed_audiences, token_format) VALUES ($1, $2, $3, $4, $5, $6, $7, 'reference')",
+        )
+        .bind(client_id)
+        .bind(client_id)
+        .bind(vec!["read"])
+        .bind(auth_method)
+        .bind(hash_token(SERVICE_SECRET))
+        .bind(vec!["client_credentials", "refresh_token"])
+        .bind(vec![AUDIENCE])
+        .execute(pool)
+        .await
+        .expect("Failed to create test client");
+    }
+
+    async fn create_confidential_client(
+        pool: &PgPool,
+        client_id: &str,
+        allowed_scopes: &[&str],
+        allowed_audiences: &[&str],
+    ) {
+        sqlx::query(
+            "INSERT INTO oauth_clients (client_id, name, allowed_scopes, token_endpoint_auth_method, client_secret_hash, allowed_audiences) VALUES ($1, $2, $3, 'client_secret_post', $4, $5)",
+        )
+        .bind(client_id)
+        .bind(client_id)
+        .bind(allowed_scopes)
+        .bind(hash_token(SERVICE_SECRET))
+        .bind(allowed_audiences)
+        .execute(pool)
+        .await
+        .expect("Failed to create test client");
+    }
+
+    fn service_request(uri: &str, params: &[(&str, &str)]) -> test::TestRequest {
+        client_request("service", uri, params)
+    }
+
+    fn client_request(client_id: &str, uri: &str, params: &[(&str, &str)]) -> test::TestRequest {
+        let mut form = vec![("client_id", client_id), ("client_secret", SERVICE_SECRET)];
+        form.extend_from_slice(params);
+        test::TestRequest::post().uri(uri).set_form(form)
+    }
+
+    #[sqlx::test]
+    async fn reference_token_can_be_introspected_until_revoked(pool: PgPool) {
+        let repo = repository(pool.clone());
+        create_reference_client(&pool, "service", "client_secret_post").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(oauth::config)
+                .configure(config),
+        )
+        .await;
+
+        let req =
+            service_request("/oauth/token", &[("grant_type", "client_credentials")]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let token = body["access_token"].as_str().unwrap().to_string();
+        assert!(!token.contains('.'));
+
+        let req = service_request("/oauth/introspect", &[("token", &token)]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["active"], true);
+        assert_eq!(body["client_id"], "service");
+        assert_eq!(body["aud"], AUDIENCE);
+
+        let req = service_request("/oauth/revoke", &[("token", &token)]).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = service_request("/oauth/introspect", &[("token", &token)]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, serde_json::json!({ "active": false }));
+    }
+
+    #[sqlx::test]
+    async fn revoking_a_reference_refresh_token_ends_the_session(pool: PgPool) {
+        let repo = repository(pool.clone());
+        create_reference_client(&pool, "cli", "none").await;
+        let user = create_user(repo.as_ref(), "alice").await;
+        let grant = SessionGrant {
+            client_id: Some("cli".to_string()),
+            scope: Some("read".to_string()),
+            ..SessionGrant::new(vec!["pwd".to_string(), "mfa".to_string()])
+        };
+        let (access_token, refresh_token) = issue_session_tokens(repo.as_ref(), &user, grant, None)
+            .await
+            .expect("Failed to issue tokens");
+        assert!(!access_token.contains('.') && !refresh_token.contains('.'));
+
+        // 引用令牌同样可以直接访问本服务的接口
+        let claims = validate_access_token(&access_token, repo.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, user.id.to_string());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/oauth/revoke")
+            .set_form([("client_id", "cli"), ("token", refresh_token.as_str())])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        assert!(
+            validate_access_token(&access_token, repo.as_ref())
+                .await
+                .is_err()
+        );
+    }
+
+    #[sqlx::test]
+    async fn only_resource_servers_for_the_audience_can_introspect(pool: PgPool) {
+        let repo = repository(pool.clone());
+        create_reference_client(&pool, "service", "client_secret_post").await;
+        create_confidential_client(&pool, "other-api", &[], &["https://other.example.com"]).await;
+        create_confidential_client(&pool, "auditor", &[INTROSPECT_SCOPE], &[]).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(oauth::config)
+                .configure(config),
+        )
+        .await;
+
+        let req =
+            service_request("/oauth/token", &[("grant_type", "client_credentials")]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let token = body["access_token"].as_str().unwrap().to_string();
+
+        let req =
+            client_request("other-api", "/oauth/introspect", &[("token", &token)]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, serde_json::json!({ "active": false }));
+
+        let req = client_request("auditor", "/oauth/introspect", &[("token", &token)]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["active"], true);
+        assert_eq!(body["aud"], AUDIENCE);
+    }
+
+    #[sqlx::test]
+    async fn refresh_tokens_can_only_be_introspected_by_their_client(pool: PgPool) {
+        let repo = repository(pool.clone());
+        create_reference_client(&pool, "service", "client_secret_post").await;
+        create_confidential_client(&pool, "auditor", &[INTROSPECT_SCOPE], &[AUDIENCE]).await;
+        let user = create_user(repo.as_ref(), "alice").await;
+        let grant = SessionGrant {
+            client_id: Some("service".to_string()),
+            scope: Some("read".to_string()),
+            ..SessionGrant::new(vec!["pwd".to_string(), "mfa".to_string()])
+        };
+        let (_, refresh_token) = issue_session_tokens(repo.as_ref(), &user, grant, None)
+            .await
+            .expect("Failed to issue tokens");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let req = service_request("/oauth/introspect", &[("token", &refresh_token)]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["active"], true);
+        assert_eq!(body["token_type"], "refresh_token");
+
+        let req = client_request("auditor", "/oauth/introspect", &[("token", &refresh_token)])
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, serde_json::json!({ "active": false }));
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    env, fs,
+    str::FromStr,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use actix_web::{HttpResponse, Responder, get, http::header, rt, web};
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey, spki::der::pem::LineEnding};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, ThumbprintHash,
+    },
+};
+use rand::rngs::OsRng;
+
+use crate::{models::SigningKeyRecord, repositories::UserRepository};
+
+// 定时任务的执行间隔：数据库来源时检查是否需要轮换，文件来源时重新读取密钥文件
+const KEY_REFRESH_MINUTES: u64 = 10;
+// 新密钥提前发布到 JWKS 的时间，需要大于 KEY_REFRESH_MINUTES 加上 JWKS 的缓存时间
+const KEY_PUBLISH_LEAD_HOURS: i64 = 1;
+
+// JWT 签名密钥
+// 非对称密钥的公钥部分通过 /.well-known/jwks.json 发布，下游服务只需公钥即可验证令牌
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub encoding: EncodingKey,
+    pub decoding: DecodingKey,
+    // 对称密钥（HS256）不能公开，没有 jwk
+    pub jwk: Option<Jwk>,
+    // 成为签名密钥的时间；None 表示只用于验证（已被取代的旧密钥）
+    pub activated_at: Option<DateTime<Utc>>,
+}
+
+enum KeySource {
+    // JWT_SECRET (HS256)，仅用于本地开发
+    Secret,
+    // JWT_PRIVATE_KEY_PATH + JWT_VERIFICATION_KEY_PATHS
+    Files,
+    // signing_keys 表，自动生成、轮换和退役
+    Database,
+}
+
+// 持有当前签名密钥和仍然有效的旧密钥，按 kid 选择验证密钥
+pub struct KeyManager {
+    source: KeySource,
+    keys: RwLock<Vec<Arc<SigningKey>>>,
+}
+
+static KEY_MANAGER: OnceLock<KeyManager> = OnceLock::new();
+
+fn key_manager() -> &'static KeyManager {
+    KEY_MANAGER
+        .get()
+        .expect("Key manager must be initialized before issuing tokens")
+}
+
+// 当前签名密钥：已生效的密钥中最新的一把
+pub fn signing_key() -> Arc<SigningKey> {
+    key_manager().current()
+}
+
+// 按 kid 查找验证用的密钥；本服务签发的令牌都带 kid，没有 kid 的令牌一律拒绝
+pub fn verification_key(kid: Option<&str>) -> Option<Arc<SigningKey>> {
+    kid.and_then(|kid| key_manager().find(kid))
+}
+
+// 启动时加载密钥，配置错误时尽早失败
+pub async fn init(repo: &dyn UserRepository) -> Result<&'static KeyManager> {
+    let source = match env::var("JWT_KEY_SOURCE").as_deref() {
+        Ok("database") => KeySource::Database,
+        Ok("file") => KeySource::Files,
+        Ok(other) => bail!("Unsupported JWT_KEY_SOURCE: {other}"),
+        Err(_) if env::var("JWT_PRIVATE_KEY_PATH").is_ok() => KeySource::Files,
+        Err(_) => {
+            println!("⚠️ JWT_PRIVATE_KEY_PATH 未设置，使用 JWT_SECRET (HS256) 签名令牌");
+            KeySource::Secret
+        }
+    };
+
+    let manager = KeyManager {
+        source,
+        keys: RwLock::new(Vec::new()),
+    };
+    manager.refresh(repo).await?;
+
+    Ok(KEY_MANAGER.get_or_init(|| manager))
+}
+
+// 后台定时轮换 / 重新加载密钥，失败时保留当前的密钥集合
+pub fn spawn_rotation(repo: Arc<dyn UserRepository>) {
+    rt::spawn(async move {
+        let mut interval =
+            rt::time::interval(std::time::Duration::from_secs(KEY_REFRESH_MINUTES * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = key_manager().refresh(repo.as_ref()).await {
+                println!("⚠️ 刷新 JWT 签名密钥失败: {e:#}");
+            }
+        }
+    });
+}
+
+impl KeyManager {
+    pub fn current(&self) -> Arc<SigningKey> {
+        let now = Utc::now();
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|key| key.activated_at.is_some_and(|at| at <= now))
+            .max_by_key(|key| key.activated_at)
+            .cloned()
+            .expect("No active JWT signing key")
+    }
+
+    pub fn find(&self, kid: &str) -> Option<Arc<SigningKey>> {
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .find(|key| key.kid == kid)
+            .cloned()
+    }
+
+    // 所有可以用于验证的公钥，包括即将生效的下一把密钥
+    pub fn jwks(&self) -> JwkSet {
+        let keys = self
+            .keys
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|key| key.jwk.clone())
+            .collect();
+        JwkSet { keys }
+    }
+
+    async fn refresh(&self, repo: &dyn UserRepository) -> Result<()> {
+        let keys = match self.source {
+            KeySource::Secret => vec![secret_key()?],
+            KeySource::Files => file_keys()?,
+            KeySource::Database => {
+                rotate_database_keys(repo).await?;
+                database_keys(repo).await?
+            }
+        };
+
+        if !keys
+            .iter()
+            .any(|key| key.activated_at.is_some_and(|at| at <= Utc::now()))
+        {
+            bail!("No active JWT signing key");
+        }
+
+        *self.keys.write().unwrap() = keys.into_iter().map(Arc::new).collect();
+        Ok(())
+    }
+}
+
+fn secret_key() -> Result<SigningKey> {
+    let secret = env::var("JWT_SECRET").context("JWT_SECRET must be set")?;
+    let kid = env::var("JWT_KEY_ID").unwrap_or_else(|_| "shared-secret".to_string());
+    Ok(hs256_key(kid, secret.as_bytes()))
+}
+
+fn hs256_key(kid: String, secret: &[u8]) -> SigningKey {
+    SigningKey {
+        kid,
+        algorithm: Algorithm::HS256,
+        encoding: EncodingKey::from_secret(secret),
+        decoding: DecodingKey::from_secret(secret),
+        jwk: None,
+        activated_at: Some(DateTime::UNIX_EPOCH),
+    }
+}
+
+// 测试使用固定的 HS256 密钥，不依赖环境变量
+#[cfg(test)]
+pub fn init_for_tests() {
+    KEY_MANAGER.get_or_init(|| KeyManager {
+        source: KeySource::Secret,
+        keys: RwLock::new(vec![Arc::new(hs256_key(
+            "test".to_string(),
+            b"test-secret",
+        ))]),
+    });
+}
+
+// 测试中模拟客户端持有的非对称密钥
+#[cfg(test)]
+pub fn generate_key_for_tests(algorithm: Algorithm) -> SigningKey {
+    let pem = generate_private_key_pem(algorithm).expect("Failed to generate key");
+    build_key(algorithm, pem.as_bytes(), None, Some(Utc::now())).expect("Failed to build key")
+}
+
+fn configured_algorithm() -> Result<Algorithm> {
+    let algorithm = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "RS256".to_string());
+    Algorithm::from_str(&algorithm).map_err(|_| anyhow!("Unsupported JWT_ALGORITHM: {algorithm}"))
+}
+
+// 文件来源：JWT_PRIVATE_KEY_PATH 为当前签名密钥
+// JWT_VERIFICATION_KEY_PATHS 为逗号分隔的旧私钥，只用于验证；
+// 旧密钥算法不同时写成 "ES256:/path/to/key.pem"
+// 轮换时把旧的私钥路径移到 JWT_VERIFICATION_KEY_PATHS，等旧令牌全部过期后再删除
+fn file_keys() -> Result<Vec<SigningKey>> {
+    let algorithm = configured_algorithm()?;
+    let path = env::var("JWT_PRIVATE_KEY_PATH").context("JWT_PRIVATE_KEY_PATH must be set")?;
+    let pem = fs::read(&path).with_context(|| format!("Failed to read {path}"))?;
+    let mut keys = vec![build_key(
+        algorithm,
+        &pem,
+        env::var("JWT_KEY_ID").ok(),
+        Some(DateTime::UNIX_EPOCH),
+    )?];
+
+    let verification_paths = env::var("JWT_VERIFICATION_KEY_PATHS").unwrap_or_default();
+    for entry in verification_paths
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (algorithm, path) = match entry.split_once(':') {
+            Some((alg, path)) if Algorithm::from_str(alg).is_ok() => {
+                (Algorithm::from_str(alg).unwrap(), path)
+            }
+            _ => (algorithm, entry),
+        };
+        let pem = fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+        keys.push(build_key(algorithm, &pem, None, None)?);
+    }
+
+    Ok(keys)
+}
+
+async fn database_keys(repo: &dyn UserRepository) -> Result<Vec<SigningKey>> {
+    repo.list_signing_keys()
+        .await?
+        .into_iter()
+        .map(|record| {
+            let algorithm = Algorithm::from_str(&record.algorithm)
+                .map_err(|_| anyhow!("Unsupported algorithm for key {}", record.kid))?;
+            build_key(
+                algorithm,
+                record.private_key_pem.as_bytes(),
+                Some(record.kid),
+                Some(record.activated_at),
+            )
+        })
+        .collect()
+}
+
+// 数据库来源的轮换流程：
+// 1. 没有生效的密钥时立即生成一把
+// 2. 当前密钥使用满 JWT_KEY_ROTATION_DAYS 前 KEY_PUBLISH_LEAD_HOURS，生成下一把并提前发布
+// 3. 被取代的旧密钥再保留 JWT_KEY_RETENTION_DAYS（需大于最长的令牌有效期）后退役
+async fn rotate_database_keys(repo: &dyn UserRepository) -> Result<()> {
+    let rotation = Duration::days(env_days("JWT_KEY_ROTATION_DAYS", 30)?);
+    let retention = Duration::days(env_days("JWT_KEY_RETENTION_DAYS", 8)?);
+    let publish_lead = Duration::hours(KEY_PUBLISH_LEAD_HOURS);
+
+    let now = Utc::now();
+    let records = repo.list_signing_keys().await?;
+    let current = records
+        .iter()
+        .filter(|record| record.activated_at <= now)
+        .max_by_key(|record| record.activated_at);
+
+    let Some(current) = current else {
+        create_database_key(repo, now).await?;
+        return Ok(());
+    };
+
+    repo.retire_signing_keys(&current.activated_at, &(current.activated_at + retention))
+        .await?;
+
+    let next_rotation = current.activated_at + rotation;
+    let has_pending = records.iter().any(|record| record.activated_at > now);
+    if !has_pending && now + publish_lead >= next_rotation {
+        create_database_key(repo, next_rotation.max(now + publish_lead)).await?;
+    }
+
+    Ok(())
+}
+
+async fn create_database_key(repo: &dyn UserRepository, activated_at: DateTime<Utc>) -> Result<()> {
+    let algorithm = configured_algorithm()?;
+    // RSA 密钥生成比较耗时，放到阻塞线程池中执行
+    let pem = web::block(move || generate_private_key_pem(algorithm)).await??;
+    let key = build_key(algorithm, pem.as_bytes(), None, Some(activated_at))?;
+
+    repo.create_signing_key(&SigningKeyRecord {
+        kid: key.kid,
+        algorithm: format!("{algorithm:?}"),
+        private_key_pem: pem,
+        activated_at,
+        retired_at: None,
+    })
+    .await?;
+
+    Ok(())
+}
+
+fn env_days(name: &str, default: i64) -> Result<i64> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| anyhow!("{name} must be a number of days")),
+        Err(_) => Ok(default),
+    }
+}
+
+fn generate_private_key_pem(algorithm: Algorithm) -> Result<String> {
+    let pem = match algorithm {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => rsa::RsaPrivateKey::new(&mut OsRng, 2048)?
+            .to_pkcs8_pem(LineEnding::LF)?
+            .to_string(),
+        Algorithm::ES256 => p256::SecretKey::random(&mut OsRng)
+            .to_pkcs8_pem(LineEnding::LF)?
+            .to_string(),
+        Algorithm::EdDSA => ed25519_dalek::SigningKey::generate(&mut OsRng)
+            .to_pkcs8_pem(LineEnding::LF)?
+            .to_string(),
+        _ => bail!("Cannot generate {algorithm:?} keys, use RS256, ES256 or EdDSA"),
+    };
+    Ok(pem)
+}
+
+// 从 PEM 格式的私钥构建签名密钥
+// 未指定 kid 时使用公钥的 RFC 7638 指纹，同一把密钥的 kid 始终不变
+fn build_key(
+    algorithm: Algorithm,
+    pem: &[u8],
+    kid: Option<String>,
+    activated_at: Option<DateTime<Utc>>,
+) -> Result<SigningKey> {
+    let (encoding, mut jwk) = asymmetric_key(algorithm, pem)
+        .with_context(|| format!("Invalid private key for {algorithm:?}"))?;
+
+    let kid = kid.unwrap_or_else(|| jwk.thumbprint(ThumbprintHash::SHA256));
+    jwk.common.key_id = Some(kid.clone());
+    jwk.common.public_key_use = Some(PublicKeyUse::Signature);
+
+    let decoding = DecodingKey::from_jwk(&jwk)?;
+
+    Ok(SigningKey {
+        kid,
+        algorithm,
+        encoding,
+        decoding,
+        jwk: Some(jwk),
+        activated_at,
+    })
+}
+
+// 从 PEM 格式的私钥得到签名密钥和对应的公钥 JWK
+fn asymmetric_key(
+    algorithm: Algorithm,
+    pem: &[u8],
+) -> Result<(EncodingKey, Jwk), jsonwebtoken::errors::Error> {
+    match algorithm {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => {
+            let encoding = EncodingKey::from_rsa_pem(pem)?;
+            let jwk = Jwk::from_encoding_key(&encoding, algorithm)?;
+            Ok((encoding, jwk))
+        }
+        Algorithm::ES256 | Algorithm::ES384 => {
+            let encoding = EncodingKey::from_ec_pem(pem)?;
+            let jwk = Jwk::from_encoding_key(&encoding, algorithm)?;
+            Ok((encoding, jwk))
+        }
+        // jsonwebtoken 不支持从 Ed25519 私钥导出 JWK，需要自己计算公钥
+        Algorithm::EdDSA => {
+            let encoding = EncodingKey::from_ed_pem(pem)?;
+            let pem = std::str::from_utf8(pem).map_err(|_| invalid_key())?;
+            let signing_key =
+                ed25519_dalek::SigningKey::from_pkcs8_pem(pem).map_err(|_| invalid_key())?;
+            let jwk = Jwk {
+                common: CommonParameters {
+                    key_algorithm: Some(KeyAlgorithm::EdDSA),
+                    ..Default::default()
+                },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(signing_key.verifying_key().as_bytes()),
+                }),
+            };
+            Ok((encoding, jwk))
+        }
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Err(invalid_key()),
+    }
+}
+
+fn invalid_key() -> jsonwebtoken::errors::Error {
+    jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat)
+}
+
+// 标准 JWKS 格式，不使用 ApiResponse 包装，方便现成的 JWT 库直接读取
+#[get("/.well-known/jwks.json")]
+async fn jwks() -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(key_manager().jwks())
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(jwks);
+}
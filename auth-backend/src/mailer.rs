@@ -0,0 +1,106 @@
+use std::{env, fs, path::PathBuf};
+
+use actix_web::web;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// 发送邮件的"契约"，与 UserRepository 一样通过 web::Data<dyn Mailer> 注入
+// 接入真实的邮件服务时只需要新增一个实现
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<()>;
+}
+
+// 本地开发：直接打印到日志
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        println!(
+            "📧 To: {}\nSubject: {}\n\n{}",
+            email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+// 本地测试：每封邮件写成目录下的一个 .eml 文件
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let dir = self.dir.clone();
+        let path = dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%d%H%M%S"),
+            Uuid::new_v4().simple()
+        ));
+        let content = format!(
+            "To: {}\nSubject: {}\nDate: {}\n\n{}\n",
+            email.to,
+            email.subject,
+            Utc::now().to_rfc2822(),
+            email.body
+        );
+
+        // 写文件会阻塞，放到 actix 的阻塞线程池中
+        web::block(move || {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+            fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))
+        })
+        .await
+        .context("Failed to run mail writer")?
+    }
+}
+
+// MAIL_TRANSPORT：log（默认）或 file（写入 MAIL_FILE_DIR，默认 ./mail）
+pub fn from_env() -> Box<dyn Mailer> {
+    match env::var("MAIL_TRANSPORT").as_deref() {
+        Ok("file") => Box::new(FileMailer {
+            dir: env::var("MAIL_FILE_DIR")
+                .unwrap_or_else(|_| "./mail".to_string())
+                .into(),
+        }),
+        Ok("log") | Err(_) => Box::new(LogMailer),
+        Ok(other) => panic!("Unsupported MAIL_TRANSPORT: {other}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn file_mailer_writes_one_file_per_email() {
+        let dir = env::temp_dir().join(format!("mail-{}", Uuid::new_v4().simple()));
+        let mailer = FileMailer { dir: dir.clone() };
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Reset your password".to_string(),
+            body: "Hello".to_string(),
+        };
+
+        mailer.send(&email).await.unwrap();
+        mailer.send(&email).await.unwrap();
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 2);
+        let content = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(content.starts_with("To: alice@example.com\nSubject: Reset your password\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -8,10 +8,23 @@ use sqlx::PgPool;
 
 use crate::repositories::{PostgresRepository, UserRepository};
 
+mod clients;
+mod device;
+mod extractors;
 mod handlers;
+mod introspection;
+mod keys;
+mod mailer;
 mod models;
+mod oauth;
+mod oidc;
+mod password;
+mod password_policy;
 mod repositories;
+#[cfg(test)]
+mod test_support;
 mod utils;
+mod webauthn;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -32,12 +45,44 @@ async fn main() -> std::io::Result<()> {
     //    `Arc<dyn UserRepository>` 是在 Actix 中注入 Trait 的标准方式
     let repo_data: Arc<dyn UserRepository> = Arc::new(repo);
 
+    // 邮件发送方式由 MAIL_TRANSPORT 决定，与 repo 一样以 Trait Object 注入
+    let mailer_data: Arc<dyn mailer::Mailer> = Arc::from(mailer::from_env());
+
+    // 密码哈希算法及参数，所有 worker 共享
+    let hasher_data = web::Data::new(password::PasswordHasher::from_env());
+
+    // 密码策略，启动时加载泄露密码库
+    let policy_data = web::Data::new(password_policy::PasswordPolicy::from_env());
+
+    // WebAuthn 依赖方配置，所有 worker 共享
+    let webauthn_data = web::Data::new(webauthn::build_webauthn());
+    let attestation_ca_data = web::Data::new(webauthn::build_attestation_ca_list());
+
+    // 启动时加载签名密钥，配置错误时尽早失败；之后由后台任务定时轮换
+    let key_manager = keys::init(repo_data.as_ref())
+        .await
+        .expect("Failed to load JWT signing keys");
+    let signing_key = key_manager.current();
+    println!(
+        "🔑 JWT 签名算法 {:?}，kid = {}",
+        signing_key.algorithm, signing_key.kid
+    );
+    keys::spawn_rotation(repo_data.clone());
+
+    // 客户端登记的 CORS 来源，管理接口修改后立即生效，并定期从数据库刷新
+    clients::reload_origins(repo_data.as_ref())
+        .await
+        .expect("Failed to load client origins");
+    clients::spawn_origin_refresh(repo_data.clone());
+
     println!("🚀 服务器启动于 http://127.0.0.1:8080");
 
     HttpServer::new(move || {
         // 定义 CORS 策略
         let _cors = Cors::default()
             .allowed_origin("http://localhost:5173")
+            // OAuth 客户端登记的来源（oauth_clients.allowed_origins）
+            .allowed_origin_fn(|origin, _| clients::is_allowed_origin(origin))
             // 如果有其他环境，也添加它们
             // .allowed_origin("https-your-production-frontend.com")
             // 允许的 HTTP 方法
@@ -57,7 +102,25 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(_cors)
             .app_data(web::Data::from(repo_data.clone()))
-            .service(web::scope("/api").configure(handlers::config))
+            .app_data(web::Data::from(mailer_data.clone()))
+            .app_data(hasher_data.clone())
+            .app_data(policy_data.clone())
+            .app_data(webauthn_data.clone())
+            .app_data(attestation_ca_data.clone())
+            .configure(keys::config)
+            .configure(oauth::config)
+            .configure(device::config)
+            .configure(introspection::config)
+            .configure(clients::config)
+            .configure(oidc::config)
+            .service(
+                web::scope("/api")
+                    .configure(handlers::config)
+                    .configure(webauthn::config)
+                    .configure(oauth::api_config)
+                    .configure(device::api_config)
+                    .configure(clients::api_config),
+            )
     })
     .bind(("127.0.0.1", 8080))?
     .run()
@@ -1,6 +1,7 @@
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use sqlx::types::uuid;
+use webauthn_rs::prelude::{PublicKeyCredential, RegisterPublicKeyCredential};
 
 #[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
 pub struct User {
@@ -13,11 +14,251 @@ pub struct User {
     pub otp_verified: Option<bool>,
     pub otp_base32: Option<String>,
     pub otp_auth_url: Option<String>,
+    pub otp_pending_base32: Option<String>,
+    pub otp_pending_auth_url: Option<String>,
+    pub otp_pending_expires_at: Option<DateTime<Utc>>,
+    pub otp_last_used_step: Option<i64>,
+    pub failed_otp_attempts: i32,
+    pub otp_locked_until: Option<DateTime<Utc>>,
+
+    pub token_version: i32,
 
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
 }
 
+impl User {
+    // 只有验证通过的 OTP 才算作已启用的第二因子
+    pub fn otp_active(&self) -> bool {
+        self.otp_enabled.unwrap_or(false)
+            && self.otp_verified.unwrap_or(false)
+            && self.otp_base32.is_some()
+    }
+}
+
+// 校验 OTP 前占用一次尝试的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpAttempt {
+    Claimed,
+    Locked(DateTime<Utc>),
+}
+
+#[derive(Debug, Serialize, Clone, sqlx::FromRow)]
+pub struct Session {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub family_id: uuid::Uuid,
+    pub jti: uuid::Uuid,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub device_info: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub amr: Vec<String>,
+    pub auth_time: DateTime<Utc>,
+    pub client_id: Option<String>,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct NewSession {
+    pub user_id: uuid::Uuid,
+    pub family_id: uuid::Uuid,
+    pub jti: uuid::Uuid,
+    pub token_hash: String,
+    pub device_info: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub amr: Vec<String>,
+    pub auth_time: DateTime<Utc>,
+    pub client_id: Option<String>,
+    pub scope: Option<String>,
+}
+
+// 一次登录（session family）的认证上下文，轮换 refresh token 时原样保留
+#[derive(Debug, Clone)]
+pub struct SessionGrant {
+    pub family_id: uuid::Uuid,
+    pub amr: Vec<String>,
+    pub auth_time: DateTime<Utc>,
+    // 通过 OAuth 授权签发时为对应的客户端和授权范围
+    pub client_id: Option<String>,
+    pub scope: Option<String>,
+}
+
+impl SessionGrant {
+    // 刚刚完成认证的新会话
+    pub fn new(amr: Vec<String>) -> Self {
+        Self {
+            family_id: uuid::Uuid::new_v4(),
+            amr,
+            auth_time: Utc::now(),
+            client_id: None,
+            scope: None,
+        }
+    }
+
+    pub fn from_session(session: &Session) -> Self {
+        Self {
+            family_id: session.family_id,
+            amr: session.amr.clone(),
+            auth_time: session.auth_time,
+            client_id: session.client_id.clone(),
+            scope: session.scope.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    pub token_endpoint_auth_method: String,
+    pub client_secret_hash: Option<String>,
+    pub jwks: Option<serde_json::Value>,
+    pub grant_types: Vec<String>,
+    pub allowed_audiences: Vec<String>,
+    // jwt 或 reference，为空时使用全局配置
+    pub token_format: Option<String>,
+    pub access_token_ttl_seconds: Option<i32>,
+    pub refresh_token_ttl_seconds: Option<i32>,
+    pub required_acr: Option<String>,
+    pub allowed_origins: Vec<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// 客户端元数据（RFC 7591 2），管理接口和动态注册共用
+// 除 client_name 外都可以省略，默认值见 clients::build_client
+#[derive(Debug, Deserialize)]
+pub struct OAuthClientMetadata {
+    pub client_name: String,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Option<Vec<String>>,
+    pub token_endpoint_auth_method: Option<String>,
+    // 空格分隔的 scope 列表
+    pub scope: Option<String>,
+    pub jwks: Option<serde_json::Value>,
+    #[serde(default)]
+    pub allowed_audiences: Vec<String>,
+    pub token_format: Option<String>,
+    pub access_token_ttl_seconds: Option<i32>,
+    pub refresh_token_ttl_seconds: Option<i32>,
+    pub required_acr: Option<String>,
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+// 客户端信息（RFC 7591 3.2.1）；client_secret 只在创建和轮换时返回一次
+#[derive(Debug, Serialize)]
+pub struct OAuthClientData {
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    // 返回 client_secret 时必须同时返回，0 表示不过期
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret_expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id_issued_at: Option<i64>,
+    pub client_name: String,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub token_endpoint_auth_method: String,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwks: Option<serde_json::Value>,
+    pub allowed_audiences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token_ttl_seconds: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token_ttl_seconds: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_acr: Option<String>,
+    pub allowed_origins: Vec<String>,
+}
+
+// 等待用户登录并同意的授权请求
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OAuthAuthorizationRequest {
+    pub id: uuid::Uuid,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    pub nonce: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+// 授权码，amr 和 auth_time 来自用户同意授权时的登录
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OAuthAuthorizationCode {
+    pub code_hash: String,
+    pub client_id: String,
+    pub user_id: uuid::Uuid,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    pub amr: Vec<String>,
+    pub auth_time: DateTime<Utc>,
+    pub family_id: uuid::Uuid,
+    pub nonce: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+// 设备授权，status 为 pending / approved / denied
+// 用户同意后 user_id、amr、auth_time 才有值
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OAuthDeviceCode {
+    pub device_code_hash: String,
+    pub user_code: String,
+    pub client_id: String,
+    pub scope: String,
+    pub status: String,
+    pub user_id: Option<uuid::Uuid>,
+    pub amr: Option<Vec<String>>,
+    pub auth_time: Option<DateTime<Utc>>,
+    pub interval_seconds: i32,
+    pub last_polled_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Clone, sqlx::FromRow)]
+pub struct WebauthnCredential {
+    pub id: uuid::Uuid,
+    pub credential_id: String,
+    #[serde(skip_serializing)]
+    pub passkey: serde_json::Value,
+    pub name: String,
+    pub attested: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct WebauthnCeremony {
+    pub user_id: Option<uuid::Uuid>,
+    pub state: serde_json::Value,
+}
+
+// 数据库中保存的 JWT 签名密钥，包含私钥，不能序列化返回
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SigningKeyRecord {
+    pub kid: String,
+    pub algorithm: String,
+    pub private_key_pem: String,
+    pub activated_at: DateTime<Utc>,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ApiResponse<T: Serialize> {
     pub status: String,
@@ -41,20 +282,214 @@ pub struct RegisterRequest {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct GenerateOTPSchema {
+pub struct VerifyOTPSchema {
+    pub token: String,
+}
+
+// MFA 第二步：提供 OTP 或者一个未使用的恢复码
+#[derive(Debug, Deserialize)]
+pub struct ValidateOTPSchema {
+    pub token: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+// 关闭 OTP 前需要再次确认身份：提供当前密码或当前 OTP 之一
+#[derive(Debug, Deserialize)]
+pub struct DisableOTPSchema {
+    pub password: Option<String>,
+    pub token: Option<String>,
+}
+
+// security_key 为 true 时要求注册经 attestation 验证的安全密钥
+#[derive(Debug, Deserialize)]
+pub struct WebauthnRegisterStartSchema {
+    #[serde(default)]
+    pub security_key: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebauthnRegisterFinishSchema {
+    pub ceremony_id: uuid::Uuid,
+    pub name: Option<String>,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebauthnAuthenticateFinishSchema {
+    pub ceremony_id: uuid::Uuid,
+    pub credential: PublicKeyCredential,
+}
+
+// start 接口返回给浏览器的 options，原样传给 navigator.credentials.create/get
+#[derive(Debug, Serialize)]
+pub struct WebauthnChallengeData<T: Serialize> {
+    pub ceremony_id: uuid::Uuid,
+    pub options: T,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
     pub email: String,
-    pub user_id: String,
 }
 
+// 启用了 OTP 的用户重置密码时还需要提供当前的验证码
 #[derive(Debug, Deserialize)]
-pub struct VerifyOTPSchema {
-    pub user_id: String,
+pub struct ResetPasswordRequest {
     pub token: String,
+    pub new_password: String,
+    pub otp_token: Option<String>,
 }
 
+// 修改密码需要提供当前密码，并且最近完成过 MFA
 #[derive(Debug, Deserialize)]
-pub struct DisableOTPSchema {
-    pub user_id: String,
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+// GET /oauth/authorize 的查询参数，缺失的参数在 handler 中逐一报错
+#[derive(Debug, Deserialize)]
+pub struct OAuthAuthorizeQuery {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub nonce: Option<String>,
+}
+
+// 令牌端点和设备授权端点共用的客户端认证参数
+// client_secret_post 或 private_key_jwt；client_secret_basic 使用 Authorization 头
+#[derive(Debug, Deserialize)]
+pub struct OAuthClientAuth {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+}
+
+// POST /oauth/token 的表单参数（application/x-www-form-urlencoded）
+#[derive(Debug, Deserialize)]
+pub struct OAuthTokenRequest {
+    pub grant_type: String,
+    #[serde(flatten)]
+    pub client: OAuthClientAuth,
+    // client_credentials：请求的 scope 和目标服务（RFC 8707）
+    pub scope: Option<String>,
+    pub resource: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    // 设备授权（RFC 8628）
+    pub device_code: Option<String>,
+}
+
+// POST /oauth/introspect 和 /oauth/revoke 的表单参数（RFC 7662 / RFC 7009）
+// token_type_hint 只是提示，令牌类型由令牌本身决定，因此忽略
+#[derive(Debug, Deserialize)]
+pub struct OAuthTokenActionRequest {
+    pub token: String,
+    #[serde(flatten)]
+    pub client: OAuthClientAuth,
+}
+
+// RFC 7662 2.2 的响应；令牌无效时只返回 {"active": false}
+#[derive(Debug, Default, Serialize)]
+pub struct IntrospectionData {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amr: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<usize>,
+}
+
+// POST /oauth/device_authorization 的表单参数
+#[derive(Debug, Deserialize)]
+pub struct OAuthDeviceAuthorizationRequest {
+    #[serde(flatten)]
+    pub client: OAuthClientAuth,
+    pub scope: Option<String>,
+}
+
+// RFC 8628 3.2 的响应
+#[derive(Debug, Serialize)]
+pub struct OAuthDeviceAuthorizationResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: i32,
+}
+
+// 用户在浏览器中输入的设备代码
+#[derive(Debug, Deserialize)]
+pub struct OAuthDeviceVerifySchema {
+    pub user_code: String,
+}
+
+// RFC 6749 5.1 的令牌响应，不使用 ApiResponse 包装
+#[derive(Debug, Serialize)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    // client_credentials 不签发 refresh token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+    // scope 包含 openid 时返回
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+// private_key_jwt 客户端断言中需要额外检查的声明，iss / sub / aud 由 Validation 校验
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientAssertionClaims {
+    pub exp: usize,
+    pub jti: Option<String>,
+}
+
+// 登录页展示给用户确认的授权请求
+#[derive(Debug, Serialize)]
+pub struct OAuthRequestData {
+    pub client_id: String,
+    pub client_name: String,
+    pub scope: String,
+}
+
+// 用户同意或拒绝后，前端跳转回客户端的地址
+#[derive(Debug, Serialize)]
+pub struct OAuthRedirectData {
+    pub redirect_to: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -62,9 +497,66 @@ pub struct Claims {
     pub sub: String,
     pub exp: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub aud: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
+    // 每个令牌唯一；refresh token 的 jti 对应 sessions 表中的一行
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub amr: Option<Vec<String>>,
+    // 用户完成认证的时间，刷新令牌时保持不变
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<usize>,
+    // 认证强度，由 amr 推导，见 utils::acr_for_amr
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    // session family id，用于单个会话登出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    // 签发时用户的 token_version，用于登出所有设备
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ver: Option<i32>,
+    // 通过 OAuth 签发的令牌：所属客户端和授权范围（RFC 9068）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+// OpenID Connect 的 id_token，aud 为客户端的 client_id
+// name 和 email 只在授权了 profile / email scope 时出现
+#[derive(Debug, Serialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub auth_time: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub amr: Vec<String>,
+    pub acr: String,
+    pub sid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+// /userinfo 的响应，字段同样由 scope 决定
+#[derive(Debug, Serialize)]
+pub struct UserInfoData {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -74,9 +566,55 @@ pub struct UserData {
     pub name: String,
 }
 
+// 登录成功后客户端需要执行的下一步
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginNextStep {
+    // 已启用 OTP：携带 mfa_token 调用 /auth/otp/validate
+    MfaRequired,
+    // 尚未启用 OTP 且 MFA 为强制：携带 enrollment_token 完成 OTP 注册
+    MfaEnrollmentRequired,
+    // MFA 非强制且未启用 OTP：直接签发令牌
+    Authenticated,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginData {
+    pub next_step: LoginNextStep,
+    // next_step 为 mfa_required 时可用的第二因子："otp"、"webauthn"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfa_methods: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfa_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enrollment_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(flatten)]
+    pub tokens: Option<OtpSueecessData>,
+}
+
+// OTP 验证成功后返回新的恢复码（只展示这一次）
+// 首次注册时还会直接返回登录令牌
+#[derive(Debug, Serialize)]
+pub struct OtpVerifiedData {
+    pub recovery_codes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(flatten)]
+    pub tokens: Option<OtpSueecessData>,
+}
+
+// 首次注册第二因子时同时返回登录令牌
+#[derive(Debug, Serialize)]
+pub struct WebauthnRegisteredData {
+    pub credential: WebauthnCredential,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(flatten)]
+    pub tokens: Option<OtpSueecessData>,
+}
+
 #[derive(Debug, Serialize)]
-pub struct LoginMfaData {
-    pub mfa_token: String,
+pub struct RecoveryCodesData {
+    pub recovery_codes: Vec<String>,
 }
 
 #[derive(Debug, Serialize)]
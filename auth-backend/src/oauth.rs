@@ -0,0 +1,1262 @@
+use std::{env, fmt};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, ResponseError, get,
+    http::{StatusCode, header},
+    post, web,
+};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    device::{DEVICE_CODE_GRANT_TYPE, exchange_device_code},
+    extractors::AuthenticatedUser,
+    handlers::{RefreshError, device_info, issue_session_tokens, rotate_refresh_token},
+    models::{
+        ApiResponse, ClientAssertionClaims, OAuthAuthorizationCode, OAuthAuthorizationRequest,
+        OAuthAuthorizeQuery, OAuthClient, OAuthClientAuth, OAuthRedirectData, OAuthRequestData,
+        OAuthTokenRequest, OAuthTokenResponse, SessionGrant, User,
+    },
+    repositories::UserRepository,
+    utils::{
+        TokenSettings, acr_for_amr, acr_satisfies, client_token_ttl, constant_time_eq,
+        generate_client_access_token, generate_id_token, generate_random_token, has_scope,
+        hash_token, token_issuer,
+    },
+};
+
+// 用户需要在这段时间内完成登录并同意授权
+const AUTHORIZATION_REQUEST_MINUTES: i64 = 10;
+// 授权码应当立即兑换
+const AUTHORIZATION_CODE_SECONDS: i64 = 60;
+
+pub(crate) const SUPPORTED_GRANT_TYPES: [&str; 4] = [
+    "authorization_code",
+    "refresh_token",
+    "client_credentials",
+    DEVICE_CODE_GRANT_TYPE,
+];
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+// 在前端地址后追加查询参数；前端使用 hash 路由，不能用 Url 解析后再拼接
+pub(crate) fn frontend_url(base: &str, name: &str, value: &str) -> String {
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!("{base}{separator}{name}={value}")
+}
+
+// 登录页地址，授权请求的 id 通过 oauth_request 参数传给前端
+fn login_url(request_id: &Uuid) -> String {
+    let base =
+        env::var("OAUTH_LOGIN_URL").unwrap_or_else(|_| "http://localhost:5173/#/login".to_string());
+    frontend_url(&base, "oauth_request", &request_id.to_string())
+}
+
+// /oauth/token 的错误，按 RFC 6749 5.2 返回 {"error", "error_description"}
+#[derive(Debug)]
+pub enum OAuthError {
+    InvalidRequest(&'static str),
+    InvalidClient(&'static str),
+    InvalidGrant(&'static str),
+    UnauthorizedClient,
+    UnsupportedGrantType,
+    InvalidScope,
+    InvalidTarget,
+    // 动态注册（RFC 7591 3.2.2）
+    InvalidRedirectUri(&'static str),
+    InvalidClientMetadata(&'static str),
+    // 设备授权轮询（RFC 8628 3.5）
+    AuthorizationPending,
+    SlowDown,
+    AccessDenied,
+    ExpiredToken,
+    ServerError(&'static str),
+}
+
+impl OAuthError {
+    fn code(&self) -> &'static str {
+        match self {
+            OAuthError::InvalidRequest(_) => "invalid_request",
+            OAuthError::InvalidClient(_) => "invalid_client",
+            OAuthError::InvalidGrant(_) => "invalid_grant",
+            OAuthError::UnauthorizedClient => "unauthorized_client",
+            OAuthError::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthError::InvalidScope => "invalid_scope",
+            OAuthError::InvalidTarget => "invalid_target",
+            OAuthError::InvalidRedirectUri(_) => "invalid_redirect_uri",
+            OAuthError::InvalidClientMetadata(_) => "invalid_client_metadata",
+            OAuthError::AuthorizationPending => "authorization_pending",
+            OAuthError::SlowDown => "slow_down",
+            OAuthError::AccessDenied => "access_denied",
+            OAuthError::ExpiredToken => "expired_token",
+            OAuthError::ServerError(_) => "server_error",
+        }
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            OAuthError::InvalidRequest(message)
+            | OAuthError::InvalidClient(message)
+            | OAuthError::InvalidGrant(message)
+            | OAuthError::InvalidRedirectUri(message)
+            | OAuthError::InvalidClientMetadata(message)
+            | OAuthError::ServerError(message) => message,
+            OAuthError::UnauthorizedClient => "The client is not allowed to use this grant_type",
+            OAuthError::UnsupportedGrantType => "Unsupported grant_type",
+            OAuthError::InvalidScope => "Requested scope is not allowed for this client",
+            OAuthError::InvalidTarget => "Requested resource is not allowed for this client",
+            OAuthError::AuthorizationPending => "The user has not yet approved the request",
+            OAuthError::SlowDown => "Polling too frequently, increase the interval by 5 seconds",
+            OAuthError::AccessDenied => "The user denied the request",
+            OAuthError::ExpiredToken => "The device_code has expired",
+        };
+        write!(f, "{message}")
+    }
+}
+
+impl ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OAuthError::InvalidClient(_) => StatusCode::UNAUTHORIZED,
+            OAuthError::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        response.insert_header((header::CACHE_CONTROL, "no-store"));
+        if let OAuthError::InvalidClient(_) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="oauth""#));
+        }
+        response.json(json!({
+            "error": self.code(),
+            "error_description": self.to_string(),
+        }))
+    }
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(ApiResponse::<()> {
+        status: "error".to_string(),
+        message: message.to_string(),
+        data: None,
+    })
+}
+
+// 把错误（或授权码）附加到客户端的 redirect_uri 上，state 原样带回
+fn redirect_uri_with(redirect_uri: &str, params: &[(&str, &str)], state: Option<&str>) -> String {
+    let mut url = match Url::parse(redirect_uri) {
+        Ok(url) => url,
+        // redirect_uri 都来自 oauth_clients 中登记的地址，正常情况下不会解析失败
+        Err(_) => return redirect_uri.to_string(),
+    };
+    {
+        let mut query = url.query_pairs_mut();
+        for (key, value) in params {
+            query.append_pair(key, value);
+        }
+        if let Some(state) = state {
+            query.append_pair("state", state);
+        }
+    }
+    url.to_string()
+}
+
+fn redirect_error(
+    redirect_uri: &str,
+    error: &str,
+    description: &str,
+    state: Option<&str>,
+) -> HttpResponse {
+    let location = redirect_uri_with(
+        redirect_uri,
+        &[("error", error), ("error_description", description)],
+        state,
+    );
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+// PKCE code_challenge / code_verifier 都是 43~128 个 URL 安全字符（RFC 7636 4.1）
+fn valid_pkce_value(value: &str) -> bool {
+    (43..=128).contains(&value.len())
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'))
+}
+
+fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    valid_pkce_value(code_verifier)
+        && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+// 请求的 scope 必须是客户端允许范围的子集；未指定时授予全部允许的 scope
+pub(crate) fn resolve_scope(client: &OAuthClient, requested: Option<&str>) -> Option<String> {
+    let mut scopes: Vec<&str> = Vec::new();
+    match requested {
+        Some(requested) if !requested.trim().is_empty() => {
+            for scope in requested.split_whitespace() {
+                if !client.allowed_scopes.iter().any(|allowed| allowed == scope) {
+                    return None;
+                }
+                if !scopes.contains(&scope) {
+                    scopes.push(scope);
+                }
+            }
+        }
+        _ => scopes.extend(client.allowed_scopes.iter().map(String::as_str)),
+    }
+    Some(scopes.join(" "))
+}
+
+// 授权端点：校验请求后保存下来，跳转到登录页，复用密码 + 第二因子的登录流程
+// client_id 或 redirect_uri 无效时不能跳转（防止开放重定向），直接返回错误
+#[get("/oauth/authorize")]
+async fn authorize(
+    query: web::Query<OAuthAuthorizeQuery>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    let Some(client_id) = query.client_id.as_deref() else {
+        return bad_request("Missing client_id");
+    };
+    let client = match repo.get_oauth_client(client_id).await {
+        Ok(Some(client)) => client,
+        Ok(None) => return bad_request("Unknown client"),
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "Failed to load client".to_string(),
+                data: None,
+            });
+        }
+    };
+
+    // redirect_uri 必须与登记的地址完全一致；只登记了一个地址时可以省略
+    let redirect_uri = match query.redirect_uri.as_deref() {
+        Some(uri)
+            if client
+                .redirect_uris
+                .iter()
+                .any(|registered| registered == uri) =>
+        {
+            uri.to_string()
+        }
+        None if client.redirect_uris.len() == 1 => client.redirect_uris[0].clone(),
+        _ => return bad_request("Invalid redirect_uri"),
+    };
+
+    let state = query.state.as_deref();
+    if !client
+        .grant_types
+        .iter()
+        .any(|grant_type| grant_type == "authorization_code")
+    {
+        return redirect_error(
+            &redirect_uri,
+            "unauthorized_client",
+            "The client is not allowed to use the authorization code grant",
+            state,
+        );
+    }
+    if query.response_type.as_deref() != Some("code") {
+        return redirect_error(
+            &redirect_uri,
+            "unsupported_response_type",
+            "Only response_type=code is supported",
+            state,
+        );
+    }
+
+    // 所有客户端都必须使用 PKCE，且只接受 S256
+    let code_challenge = match query.code_challenge.as_deref() {
+        Some(challenge) if valid_pkce_value(challenge) => challenge.to_string(),
+        _ => {
+            return redirect_error(
+                &redirect_uri,
+                "invalid_request",
+                "A valid code_challenge is required",
+                state,
+            );
+        }
+    };
+    if query.code_challenge_method.as_deref() != Some("S256") {
+        return redirect_error(
+            &redirect_uri,
+            "invalid_request",
+            "code_challenge_method must be S256",
+            state,
+        );
+    }
+
+    let Some(scope) = resolve_scope(&client, query.scope.as_deref()) else {
+        return redirect_error(
+            &redirect_uri,
+            "invalid_scope",
+            "Requested scope is not allowed for this client",
+            state,
+        );
+    };
+
+    let request = OAuthAuthorizationRequest {
+        id: Uuid::new_v4(),
+        client_id: client.client_id,
+        redirect_uri,
+        scope,
+        state: query.state.clone(),
+        code_challenge,
+        code_challenge_method: "S256".to_string(),
+        nonce: query.nonce.clone(),
+        expires_at: Utc::now() + Duration::minutes(AUTHORIZATION_REQUEST_MINUTES),
+    };
+    if repo
+        .create_oauth_authorization_request(&request)
+        .await
+        .is_err()
+    {
+        return redirect_error(
+            &request.redirect_uri,
+            "server_error",
+            "Failed to save authorization request",
+            state,
+        );
+    }
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, login_url(&request.id)))
+        .finish()
+}
+
+// 登录页用来展示授权信息：哪个应用、请求哪些权限
+#[get("/oauth/requests/{id}")]
+async fn get_authorization_request(
+    path: web::Path<Uuid>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    let request_id = path.into_inner();
+    let (request, client) =
+        match load_authorization_request(repo.get_ref(), &request_id, None).await {
+            Ok(loaded) => loaded,
+            Err(resp) => return resp,
+        };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "Authorization request found".to_string(),
+        data: Some(OAuthRequestData {
+            client_id: client.client_id,
+            client_name: client.name,
+            scope: request.scope,
+        }),
+    })
+}
+
+// take_for 为当前用户时同时删除授权请求，保证它只能被同意或拒绝一次
+// 同意或拒绝之前都要先把请求绑定到当前用户，其他用户无法处理别人的授权请求
+async fn load_authorization_request(
+    repo: &dyn UserRepository,
+    request_id: &Uuid,
+    take_for: Option<&Uuid>,
+) -> Result<(OAuthAuthorizationRequest, OAuthClient), HttpResponse> {
+    let request = match take_for {
+        Some(user_id) => {
+            repo.take_oauth_authorization_request(request_id, user_id)
+                .await
+        }
+        None => repo.get_oauth_authorization_request(request_id).await,
+    };
+    let not_found = || {
+        HttpResponse::NotFound().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Authorization request not found or expired".to_string(),
+            data: None,
+        })
+    };
+    let request = match request {
+        Ok(Some(request)) => request,
+        Ok(None) => return Err(not_found()),
+        Err(_) => {
+            return Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "Failed to load authorization request".to_string(),
+                data: None,
+            }));
+        }
+    };
+    match repo.get_oauth_client(&request.client_id).await {
+        Ok(Some(client)) => Ok((request, client)),
+        _ => Err(not_found()),
+    }
+}
+
+// 授权请求创建时用户还没有登录，第一个同意或拒绝它的用户即为请求的所有者
+// 已绑定到其他用户时按不存在处理，不暴露请求的状态
+async fn bind_authorization_request(
+    repo: &dyn UserRepository,
+    request_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<(), HttpResponse> {
+    match repo
+        .bind_oauth_authorization_request(request_id, user_id)
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HttpResponse::NotFound().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Authorization request not found or expired".to_string(),
+            data: None,
+        })),
+        Err(_) => Err(HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to load authorization request".to_string(),
+            data: None,
+        })),
+    }
+}
+
+// 客户端可以要求更高的认证强度（required_acr），例如只接受 passkey 登录
+// 不满足时返回 403，用户换用更强的方式登录后可以再次同意
+pub(crate) fn check_required_acr(
+    client: &OAuthClient,
+    auth: &AuthenticatedUser,
+) -> Result<(), HttpResponse> {
+    let Some(required) = client.required_acr.as_deref() else {
+        return Ok(());
+    };
+    let acr = acr_for_amr(auth.claims.amr.as_deref().unwrap_or_default());
+    if acr_satisfies(&acr, required) {
+        return Ok(());
+    }
+    Err(HttpResponse::Forbidden().json(ApiResponse {
+        status: "error".to_string(),
+        message: "This application requires a stronger sign-in method".to_string(),
+        data: Some(json!({ "required_acr": required, "acr": acr })),
+    }))
+}
+
+// 用户登录（含第二因子）后同意授权：签发授权码并返回跳转地址
+// 授权码继承当前登录的 amr 和 auth_time
+#[post("/oauth/requests/{id}/approve")]
+async fn approve_authorization_request(
+    auth: AuthenticatedUser,
+    path: web::Path<Uuid>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    let request_id = path.into_inner();
+    if let Err(resp) = bind_authorization_request(repo.get_ref(), &request_id, &auth.user.id).await
+    {
+        return resp;
+    }
+    // 先检查认证强度再取出授权请求，不满足时请求保留
+    let client = match load_authorization_request(repo.get_ref(), &request_id, None).await {
+        Ok((_, client)) => client,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = check_required_acr(&client, &auth) {
+        return resp;
+    }
+    let (request, _) =
+        match load_authorization_request(repo.get_ref(), &request_id, Some(&auth.user.id)).await {
+            Ok(loaded) => loaded,
+            Err(resp) => return resp,
+        };
+
+    let amr = auth.claims.amr.clone().unwrap_or_default();
+    let auth_time = auth
+        .claims
+        .auth_time
+        .and_then(|t| DateTime::from_timestamp(t as i64, 0))
+        .unwrap_or_else(Utc::now);
+
+    let code = generate_random_token();
+    let record = OAuthAuthorizationCode {
+        code_hash: hash_token(&code),
+        client_id: request.client_id,
+        user_id: auth.user.id,
+        redirect_uri: request.redirect_uri,
+        scope: request.scope,
+        code_challenge: request.code_challenge,
+        code_challenge_method: request.code_challenge_method,
+        amr,
+        auth_time,
+        family_id: Uuid::new_v4(),
+        nonce: request.nonce,
+        expires_at: Utc::now() + Duration::seconds(AUTHORIZATION_CODE_SECONDS),
+        used_at: None,
+    };
+    if repo.create_oauth_authorization_code(&record).await.is_err() {
+        return HttpResponse::InternalServerError().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "Failed to create authorization code".to_string(),
+            data: None,
+        });
+    }
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "Authorization approved".to_string(),
+        data: Some(OAuthRedirectData {
+            redirect_to: redirect_uri_with(
+                &record.redirect_uri,
+                &[("code", &code)],
+                request.state.as_deref(),
+            ),
+        }),
+    })
+}
+
+// 用户拒绝授权：带 access_denied 跳转回客户端
+// 与同意一样需要登录，否则任何拿到请求 id 的人都可以替用户拒绝
+#[post("/oauth/requests/{id}/deny")]
+async fn deny_authorization_request(
+    auth: AuthenticatedUser,
+    path: web::Path<Uuid>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    let request_id = path.into_inner();
+    if let Err(resp) = bind_authorization_request(repo.get_ref(), &request_id, &auth.user.id).await
+    {
+        return resp;
+    }
+    let (request, _) =
+        match load_authorization_request(repo.get_ref(), &request_id, Some(&auth.user.id)).await {
+            Ok(loaded) => loaded,
+            Err(resp) => return resp,
+        };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "Authorization denied".to_string(),
+        data: Some(OAuthRedirectData {
+            redirect_to: redirect_uri_with(
+                &request.redirect_uri,
+                &[
+                    ("error", "access_denied"),
+                    ("error_description", "The user denied the request"),
+                ],
+                request.state.as_deref(),
+            ),
+        }),
+    })
+}
+
+// application/x-www-form-urlencoded 解码（Basic 认证中的 client_id / secret 需要先编码）
+fn form_decode(value: &str) -> String {
+    url::form_urlencoded::parse(format!("v={value}").as_bytes())
+        .next()
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_default()
+}
+
+// client_secret_basic：Authorization: Basic base64(client_id:client_secret)
+fn basic_credentials(req: &HttpRequest) -> Result<Option<(String, String)>, OAuthError> {
+    let Some(value) = req.headers().get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let invalid = || OAuthError::InvalidClient("Invalid Authorization header");
+    let encoded = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Basic "))
+        .ok_or_else(invalid)?;
+    let decoded = STANDARD.decode(encoded).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let (client_id, secret) = decoded.split_once(':').ok_or_else(invalid)?;
+    Ok(Some((form_decode(client_id), form_decode(secret))))
+}
+
+// 客户端断言中的 sub 即 client_id；只用于找到客户端，签名随后再验证
+fn unverified_assertion_subject(assertion: &str) -> Option<String> {
+    let payload = URL_SAFE_NO_PAD.decode(assertion.split('.').nth(1)?).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims.get("sub")?.as_str().map(str::to_string)
+}
+
+// 令牌端点的客户端认证（RFC 6749 2.3，RFC 7523 2.2）
+// 认证方式必须与客户端登记的 token_endpoint_auth_method 一致，公开客户端只需要 client_id
+pub(crate) async fn authenticate_client(
+    req: &HttpRequest,
+    form: &OAuthClientAuth,
+    repo: &dyn UserRepository,
+) -> Result<OAuthClient, OAuthError> {
+    let basic = basic_credentials(req)?;
+    let assertion = match (
+        form.client_assertion_type.as_deref(),
+        form.client_assertion.as_deref(),
+    ) {
+        (Some(CLIENT_ASSERTION_TYPE), Some(assertion)) => Some(assertion),
+        (None, None) => None,
+        _ => {
+            return Err(OAuthError::InvalidRequest(
+                "Unsupported client_assertion_type",
+            ));
+        }
+    };
+
+    let client_id = match (&basic, form.client_id.as_deref()) {
+        (Some((basic_id, _)), Some(form_id)) if basic_id != form_id => {
+            return Err(OAuthError::InvalidRequest("client_id does not match"));
+        }
+        (Some((basic_id, _)), _) => basic_id.clone(),
+        (None, Some(form_id)) => form_id.to_string(),
+        (None, None) => assertion
+            .and_then(unverified_assertion_subject)
+            .ok_or(OAuthError::InvalidRequest("Missing client_id"))?,
+    };
+
+    // 每个请求只能使用一种认证方式
+    let multiple =
+        || OAuthError::InvalidRequest("Only one client authentication method may be used");
+    let secret = match (basic, form.client_secret.as_deref()) {
+        (Some(_), Some(_)) => return Err(multiple()),
+        (Some((_, secret)), None) => Some(secret),
+        (None, secret) => secret.map(str::to_string),
+    };
+    if secret.is_some() && assertion.is_some() {
+        return Err(multiple());
+    }
+
+    let client = repo
+        .get_oauth_client(&client_id)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to load client"))?
+        .ok_or(OAuthError::InvalidClient("Unknown client"))?;
+
+    let failed = OAuthError::InvalidClient("Client authentication failed");
+    match client.token_endpoint_auth_method.as_str() {
+        "none" if secret.is_none() && assertion.is_none() => Ok(client),
+        "client_secret_basic" | "client_secret_post" => {
+            match (secret, client.client_secret_hash.as_deref()) {
+                (Some(secret), Some(expected))
+                    if constant_time_eq(&hash_token(&secret), expected) =>
+                {
+                    Ok(client)
+                }
+                _ => Err(failed),
+            }
+        }
+        "private_key_jwt" => match assertion {
+            Some(assertion) => {
+                verify_client_assertion(repo, &client, assertion).await?;
+                Ok(client)
+            }
+            None => Err(failed),
+        },
+        _ => Err(failed),
+    }
+}
+
+// 用客户端登记的公钥验证断言：iss 和 sub 都是 client_id，aud 为本服务的令牌端点或签发者
+async fn verify_client_assertion(
+    repo: &dyn UserRepository,
+    client: &OAuthClient,
+    assertion: &str,
+) -> Result<(), OAuthError> {
+    let failed = || OAuthError::InvalidClient("Invalid client assertion");
+
+    let header = decode_header(assertion).map_err(|_| failed())?;
+    let jwks: JwkSet = client
+        .jwks
+        .clone()
+        .and_then(|jwks| serde_json::from_value(jwks).ok())
+        .ok_or_else(failed)?;
+    let jwk = match header.kid.as_deref() {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(failed)?;
+
+    // 只接受非对称密钥，算法以 JWK 声明的为准，不信任 header 中的 alg
+    if matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_))
+        || matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        )
+        || jwk
+            .common
+            .key_algorithm
+            .is_some_and(|alg| alg.to_string() != format!("{:?}", header.alg))
+    {
+        return Err(failed());
+    }
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| failed())?;
+
+    let issuer = token_issuer();
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[format!("{issuer}/oauth/token"), issuer]);
+    validation.set_issuer(&[&client.client_id]);
+    validation.sub = Some(client.client_id.clone());
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<ClientAssertionClaims>(assertion, &key, &validation)
+        .map_err(|_| failed())?
+        .claims;
+
+    // 每个断言只能使用一次
+    let jti = claims.jti.ok_or_else(failed)?;
+    let expires_at = DateTime::from_timestamp(claims.exp as i64, 0).ok_or_else(failed)?;
+    let fresh = repo
+        .record_client_assertion(&client.client_id, &jti, &expires_at)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to record client assertion"))?;
+    if !fresh {
+        return Err(failed());
+    }
+    Ok(())
+}
+
+// 令牌端点：用授权码或 refresh token 换取令牌，或者客户端为自己获取令牌
+#[post("/oauth/token")]
+async fn token(
+    req: HttpRequest,
+    form: web::Form<OAuthTokenRequest>,
+    repo: web::Data<dyn UserRepository>,
+) -> Result<HttpResponse, OAuthError> {
+    if !SUPPORTED_GRANT_TYPES.contains(&form.grant_type.as_str()) {
+        return Err(OAuthError::UnsupportedGrantType);
+    }
+    let client = authenticate_client(&req, &form.client, repo.get_ref()).await?;
+    if !client.grant_types.contains(&form.grant_type) {
+        return Err(OAuthError::UnauthorizedClient);
+    }
+    if form.grant_type == "client_credentials" {
+        return client_credentials_token(repo.get_ref(), &client, &form).await;
+    }
+
+    // 只有授权码兑换时才有 nonce，刷新时签发的 id_token 不带 nonce
+    let (user, grant, nonce) = match form.grant_type.as_str() {
+        "authorization_code" => exchange_authorization_code(repo.get_ref(), &client, &form).await?,
+        DEVICE_CODE_GRANT_TYPE => {
+            let (user, grant) = exchange_device_code(repo.get_ref(), &client, &form).await?;
+            (user, grant, None)
+        }
+        "refresh_token" => {
+            let refresh_token = form
+                .refresh_token
+                .as_deref()
+                .ok_or(OAuthError::InvalidRequest("Missing refresh_token"))?;
+            let (session, user) =
+                rotate_refresh_token(repo.get_ref(), refresh_token, Some(&client.client_id))
+                    .await
+                    .map_err(|e| match e {
+                        RefreshError::Internal(message) => OAuthError::ServerError(message),
+                        _ => OAuthError::InvalidGrant("Invalid or expired refresh token"),
+                    })?;
+            (user, SessionGrant::from_session(&session), None)
+        }
+        _ => return Err(OAuthError::UnsupportedGrantType),
+    };
+
+    let scope = grant.scope.clone().unwrap_or_default();
+    let id_token = if has_scope(grant.scope.as_deref(), "openid") {
+        let id_token = generate_id_token(&user, &client.client_id, &grant, nonce.as_deref())
+            .map_err(|_| OAuthError::ServerError("Failed to generate id token"))?;
+        Some(id_token)
+    } else {
+        None
+    };
+    let (access_token, refresh_token) =
+        issue_session_tokens(repo.get_ref(), &user, grant, device_info(&req))
+            .await
+            .map_err(|_| OAuthError::ServerError("Failed to issue tokens"))?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(OAuthTokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: TokenSettings::for_client(&client).access_ttl.num_seconds(),
+            refresh_token: Some(refresh_token),
+            scope,
+            id_token,
+        }))
+}
+
+// 服务之间调用使用的令牌：没有用户参与，只签发 access token
+async fn client_credentials_token(
+    repo: &dyn UserRepository,
+    client: &OAuthClient,
+    form: &OAuthTokenRequest,
+) -> Result<HttpResponse, OAuthError> {
+    // 公开客户端无法保管凭据，不能代表自己获取令牌
+    if client.token_endpoint_auth_method == "none" {
+        return Err(OAuthError::UnauthorizedClient);
+    }
+    let scope = resolve_scope(client, form.scope.as_deref()).ok_or(OAuthError::InvalidScope)?;
+
+    // 目标服务必须是客户端登记的 aud 之一；只登记了一个时可以省略
+    let audience = match form.resource.as_deref() {
+        Some(resource) if client.allowed_audiences.iter().any(|aud| aud == resource) => {
+            resource.to_string()
+        }
+        None if client.allowed_audiences.len() == 1 => client.allowed_audiences[0].clone(),
+        _ => return Err(OAuthError::InvalidTarget),
+    };
+
+    let access_token = generate_client_access_token(client, &audience, &scope, repo)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to generate access token"))?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(OAuthTokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: client_token_ttl(client).num_seconds(),
+            refresh_token: None,
+            scope,
+            id_token: None,
+        }))
+}
+
+async fn exchange_authorization_code(
+    repo: &dyn UserRepository,
+    client: &OAuthClient,
+    form: &OAuthTokenRequest,
+) -> Result<(User, SessionGrant, Option<String>), OAuthError> {
+    let (Some(code), Some(redirect_uri), Some(code_verifier)) = (
+        form.code.as_deref(),
+        form.redirect_uri.as_deref(),
+        form.code_verifier.as_deref(),
+    ) else {
+        return Err(OAuthError::InvalidRequest(
+            "code, redirect_uri and code_verifier are required",
+        ));
+    };
+
+    let code_hash = hash_token(code);
+    let record = repo
+        .get_oauth_authorization_code(&code_hash)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to load authorization code"))?
+        .filter(|record| record.client_id == client.client_id)
+        .ok_or(OAuthError::InvalidGrant("Invalid authorization code"))?;
+
+    // 授权码被重复使用说明它可能已泄露：吊销用它签发的所有令牌（RFC 6749 4.1.2）
+    // use_oauth_authorization_code 返回 false 时同理（并发请求抢先使用了它）
+    if record.used_at.is_some() {
+        let _ = repo.revoke_session_family(&record.family_id).await;
+        return Err(OAuthError::InvalidGrant(
+            "Authorization code has already been used",
+        ));
+    }
+    if record.expires_at <= Utc::now() {
+        return Err(OAuthError::InvalidGrant("Authorization code has expired"));
+    }
+    if record.redirect_uri != redirect_uri {
+        return Err(OAuthError::InvalidGrant("redirect_uri does not match"));
+    }
+    if record.code_challenge_method != "S256" || !verify_pkce(code_verifier, &record.code_challenge)
+    {
+        return Err(OAuthError::InvalidGrant("PKCE verification failed"));
+    }
+
+    let used = repo
+        .use_oauth_authorization_code(&code_hash)
+        .await
+        .map_err(|_| OAuthError::ServerError("Failed to use authorization code"))?;
+    if !used {
+        let _ = repo.revoke_session_family(&record.family_id).await;
+        return Err(OAuthError::InvalidGrant(
+            "Authorization code is expired or has already been used",
+        ));
+    }
+
+    let user = repo
+        .get_user_by_id(&record.user_id)
+        .await
+        .map_err(|_| OAuthError::InvalidGrant("User not found"))?;
+
+    let grant = SessionGrant {
+        family_id: record.family_id,
+        amr: record.amr,
+        auth_time: record.auth_time,
+        client_id: Some(record.client_id),
+        scope: Some(record.scope),
+    };
+    Ok((user, grant, record.nonce))
+}
+
+// /oauth/authorize 和 /oauth/token 挂在根路径下
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(authorize).service(token);
+}
+
+// 登录页使用的授权确认接口，挂在 /api 下
+pub fn api_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_authorization_request)
+        .service(approve_authorization_request)
+        .service(deny_authorization_request);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::App;
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        keys::generate_key_for_tests,
+        test_support::{create_user, repository},
+    };
+
+    // RFC 7636 附录 B 的示例
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+    const REDIRECT_URI: &str = "http://localhost:3000/callback";
+
+    #[test]
+    fn pkce_accepts_the_matching_verifier() {
+        assert!(verify_pkce(VERIFIER, CHALLENGE));
+    }
+
+    #[test]
+    fn pkce_rejects_a_different_verifier() {
+        let other = VERIFIER.replace('d', "e");
+        assert!(!verify_pkce(&other, CHALLENGE));
+        assert!(!verify_pkce(CHALLENGE, CHALLENGE));
+    }
+
+    #[test]
+    fn pkce_rejects_malformed_verifiers_even_if_the_hash_matches() {
+        for verifier in ["short", &"a".repeat(129), &format!("{VERIFIER}+/")] {
+            let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+            assert!(!verify_pkce(verifier, &challenge), "{verifier}");
+        }
+    }
+
+    // 公开客户端（无密钥，只能使用授权码 + PKCE）
+    async fn create_public_client(pool: &PgPool, repo: &dyn UserRepository) -> OAuthClient {
+        sqlx::query(
+            "INSERT INTO oauth_clients (client_id, name, redirect_uris, allowed_scopes) VALUES ($1, $2, $3, $4)",
+        )
+        .bind("test-client")
+        .bind("Test")
+        .bind(vec![REDIRECT_URI])
+        .bind(vec!["openid"])
+        .execute(pool)
+        .await
+        .expect("Failed to create test client");
+        repo.get_oauth_client("test-client").await.unwrap().unwrap()
+    }
+
+    fn token_request(code: &str, code_verifier: &str) -> OAuthTokenRequest {
+        serde_json::from_value(json!({
+            "grant_type": "authorization_code",
+            "client_id": "test-client",
+            "code": code,
+            "redirect_uri": REDIRECT_URI,
+            "code_verifier": code_verifier,
+        }))
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn authorization_code_requires_the_pkce_verifier(pool: PgPool) {
+        let repo = repository(pool.clone());
+        let user = create_user(repo.as_ref(), "alice").await;
+        let client = create_public_client(&pool, repo.as_ref()).await;
+        let code = generate_random_token();
+        repo.create_oauth_authorization_code(&OAuthAuthorizationCode {
+            code_hash: hash_token(&code),
+            client_id: client.client_id.clone(),
+            user_id: user.id,
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: "openid".to_string(),
+            code_challenge: CHALLENGE.to_string(),
+            code_challenge_method: "S256".to_string(),
+            amr: vec!["pwd".to_string(), "mfa".to_string()],
+            auth_time: Utc::now(),
+            family_id: Uuid::new_v4(),
+            nonce: None,
+            expires_at: Utc::now() + Duration::seconds(AUTHORIZATION_CODE_SECONDS),
+            used_at: None,
+        })
+        .await
+        .unwrap();
+
+        // 校验失败不消耗授权码，持有正确 verifier 的客户端仍然可以兑换
+        let wrong = "a".repeat(43);
+        assert!(matches!(
+            exchange_authorization_code(repo.as_ref(), &client, &token_request(&code, &wrong))
+                .await,
+            Err(OAuthError::InvalidGrant("PKCE verification failed"))
+        ));
+
+        let (exchanged_user, grant, _) =
+            exchange_authorization_code(repo.as_ref(), &client, &token_request(&code, VERIFIER))
+                .await
+                .expect("Exchange with the right verifier should succeed");
+        assert_eq!(exchanged_user.id, user.id);
+        assert_eq!(grant.client_id.as_deref(), Some("test-client"));
+
+        assert!(matches!(
+            exchange_authorization_code(repo.as_ref(), &client, &token_request(&code, VERIFIER))
+                .await,
+            Err(OAuthError::InvalidGrant(
+                "Authorization code has already been used"
+            ))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn refresh_token_is_bound_to_its_client(pool: PgPool) {
+        let repo = repository(pool.clone());
+        let user = create_user(repo.as_ref(), "bob").await;
+        create_public_client(&pool, repo.as_ref()).await;
+        let grant = SessionGrant::new(vec!["pwd".to_string(), "mfa".to_string()]);
+        let (_, refresh_token) = issue_session_tokens(repo.as_ref(), &user, grant, None)
+            .await
+            .expect("Failed to issue tokens");
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        // 第一方登录签发的 refresh token 不能由 OAuth 客户端轮换
+        let req = actix_web::test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form([
+                ("grant_type", "refresh_token"),
+                ("client_id", "test-client"),
+                ("refresh_token", refresh_token.as_str()),
+            ])
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_grant");
+
+        // 客户端不匹配不算使用过，原来的持有者仍然可以轮换
+        assert!(
+            rotate_refresh_token(repo.as_ref(), &refresh_token, None)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[sqlx::test]
+    async fn id_token_carries_the_nonce_and_amr(pool: PgPool) {
+        let repo = repository(pool.clone());
+        let user = create_user(repo.as_ref(), "carol").await;
+        let client = create_public_client(&pool, repo.as_ref()).await;
+        let code = generate_random_token();
+        repo.create_oauth_authorization_code(&OAuthAuthorizationCode {
+            code_hash: hash_token(&code),
+            client_id: client.client_id.clone(),
+            user_id: user.id,
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: "openid profile".to_string(),
+            code_challenge: CHALLENGE.to_string(),
+            code_challenge_method: "S256".to_string(),
+            amr: vec!["pwd".to_string(), "mfa".to_string()],
+            auth_time: Utc::now(),
+            family_id: Uuid::new_v4(),
+            nonce: Some("n-0S6_WzA2Mj".to_string()),
+            expires_at: Utc::now() + Duration::seconds(AUTHORIZATION_CODE_SECONDS),
+            used_at: None,
+        })
+        .await
+        .unwrap();
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form([
+                ("grant_type", "authorization_code"),
+                ("client_id", "test-client"),
+                ("code", code.as_str()),
+                ("redirect_uri", REDIRECT_URI),
+                ("code_verifier", VERIFIER),
+            ])
+            .to_request();
+        let body: Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        let id_token = body["id_token"].as_str().expect("No id_token issued");
+        let payload = id_token.split('.').nth(1).unwrap();
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+        assert_eq!(claims["nonce"], "n-0S6_WzA2Mj");
+        assert_eq!(claims["amr"], json!(["pwd", "mfa"]));
+        assert_eq!(claims["aud"], "test-client");
+        assert_eq!(claims["sub"], user.id.to_string());
+        assert_eq!(claims["name"], "carol");
+        assert!(claims.get("email").is_none());
+    }
+
+    #[sqlx::test]
+    async fn authorization_request_belongs_to_the_first_user(pool: PgPool) {
+        let repo = repository(pool.clone());
+        let alice = create_user(repo.as_ref(), "alice").await;
+        let mallory = create_user(repo.as_ref(), "mallory").await;
+        let client = create_public_client(&pool, repo.as_ref()).await;
+        let request = OAuthAuthorizationRequest {
+            id: Uuid::new_v4(),
+            client_id: client.client_id,
+            redirect_uri: REDIRECT_URI.to_string(),
+            scope: "openid".to_string(),
+            state: None,
+            code_challenge: CHALLENGE.to_string(),
+            code_challenge_method: "S256".to_string(),
+            nonce: None,
+            expires_at: Utc::now() + Duration::minutes(AUTHORIZATION_REQUEST_MINUTES),
+        };
+        repo.create_oauth_authorization_request(&request)
+            .await
+            .unwrap();
+
+        assert!(
+            bind_authorization_request(repo.as_ref(), &request.id, &alice.id)
+                .await
+                .is_ok()
+        );
+        assert!(
+            bind_authorization_request(repo.as_ref(), &request.id, &mallory.id)
+                .await
+                .is_err()
+        );
+        assert!(
+            load_authorization_request(repo.as_ref(), &request.id, Some(&mallory.id))
+                .await
+                .is_err()
+        );
+        assert!(
+            load_authorization_request(repo.as_ref(), &request.id, Some(&alice.id))
+                .await
+                .is_ok()
+        );
+    }
+
+    const SERVICE_SECRET: &str = "service-secret";
+    const AUDIENCE: &str = "https://api.example.com";
+
+    // 可以使用 client_credentials 的机密客户端
+    async fn create_service_client(
+        pool: &PgPool,
+        client_id: &str,
+        auth_method: &str,
+        jwks: Option<Value>,
+    ) {
+        sqlx::query(
+            "INSERT INTO oauth_clients (client_id, name, allowed_scopes, token_endpoint_auth_method, client_secret_hash, jwks, grant_types, allowed_audiences) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(client_id)
+        .bind("Service")
+        .bind(vec!["read"])
+        .bind(auth_method)
+        .bind(hash_token(SERVICE_SECRET))
+        .bind(jwks)
+        .bind(vec!["client_credentials"])
+        .bind(vec![AUDIENCE])
+        .execute(pool)
+        .await
+        .expect("Failed to create test client");
+    }
+
+    fn client_credentials_request(params: &[(&str, &str)]) -> actix_web::test::TestRequest {
+        let mut form = vec![("grant_type", "client_credentials")];
+        form.extend_from_slice(params);
+        actix_web::test::TestRequest::post()
+            .uri("/oauth/token")
+            .set_form(form)
+    }
+
+    #[sqlx::test]
+    async fn client_credentials_only_issues_allowed_audiences(pool: PgPool) {
+        let repo = repository(pool.clone());
+        create_service_client(&pool, "service", "client_secret_post", None).await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+        let credentials = [("client_id", "service"), ("client_secret", SERVICE_SECRET)];
+
+        let req = client_credentials_request(
+            &[
+                credentials.as_slice(),
+                &[("resource", "https://other.example.com")],
+            ]
+            .concat(),
+        )
+        .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_target");
+
+        let req = client_credentials_request(
+            &[credentials.as_slice(), &[("resource", AUDIENCE)]].concat(),
+        )
+        .to_request();
+        let body: Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert!(body["access_token"].is_string());
+        assert!(body.get("refresh_token").is_none());
+
+        let req =
+            client_credentials_request(&[("client_id", "service"), ("client_secret", "wrong")])
+                .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn private_key_jwt_assertion_cannot_be_replayed(pool: PgPool) {
+        let repo = repository(pool.clone());
+        let key = generate_key_for_tests(Algorithm::ES256);
+        let jwks = json!({ "keys": [key.jwk] });
+        create_service_client(&pool, "service", "private_key_jwt", Some(jwks)).await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let mut header = jsonwebtoken::Header::new(Algorithm::ES256);
+        header.kid = Some(key.kid.clone());
+        let claims = json!({
+            "iss": "service",
+            "sub": "service",
+            "aud": format!("{}/oauth/token", token_issuer()),
+            "exp": (Utc::now() + Duration::minutes(1)).timestamp(),
+            "jti": Uuid::new_v4().to_string(),
+        });
+        let assertion = jsonwebtoken::encode(&header, &claims, &key.encoding).unwrap();
+        let params = [
+            ("client_assertion_type", CLIENT_ASSERTION_TYPE),
+            ("client_assertion", assertion.as_str()),
+        ];
+
+        let req = client_credentials_request(&params).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = client_credentials_request(&params).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_client");
+    }
+}
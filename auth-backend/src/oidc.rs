@@ -0,0 +1,203 @@
+use std::str::FromStr;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder, get,
+    http::{StatusCode, header},
+    web,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    device::DEVICE_CODE_GRANT_TYPE,
+    extractors::bearer_token,
+    keys::signing_key,
+    models::UserInfoData,
+    repositories::UserRepository,
+    utils::{has_scope, token_issuer, validate_access_token},
+};
+
+// OpenID Connect Discovery 1.0，现成的 OIDC 客户端库据此找到各个端点
+#[get("/.well-known/openid-configuration")]
+async fn openid_configuration() -> impl Responder {
+    let issuer = token_issuer();
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/oauth/authorize"),
+            "token_endpoint": format!("{issuer}/oauth/token"),
+            "userinfo_endpoint": format!("{issuer}/userinfo"),
+            "device_authorization_endpoint": format!("{issuer}/oauth/device_authorization"),
+            "introspection_endpoint": format!("{issuer}/oauth/introspect"),
+            "revocation_endpoint": format!("{issuer}/oauth/revoke"),
+            "registration_endpoint": format!("{issuer}/oauth/register"),
+            "jwks_uri": format!("{issuer}/.well-known/jwks.json"),
+            "response_types_supported": ["code"],
+            "grant_types_supported": [
+                "authorization_code",
+                "refresh_token",
+                "client_credentials",
+                DEVICE_CODE_GRANT_TYPE,
+            ],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": [signing_key().algorithm],
+            "scopes_supported": ["openid", "profile", "email"],
+            "token_endpoint_auth_methods_supported": [
+                "none", "client_secret_basic", "client_secret_post", "private_key_jwt",
+            ],
+            "token_endpoint_auth_signing_alg_values_supported": [
+                "RS256", "RS384", "RS512", "PS256", "PS384", "PS512", "ES256", "ES384", "EdDSA",
+            ],
+            "code_challenge_methods_supported": ["S256"],
+            "claims_supported": [
+                "iss", "sub", "aud", "exp", "iat", "auth_time", "nonce",
+                "amr", "acr", "sid", "name", "email",
+            ],
+        }))
+}
+
+// RFC 6750 3.1：错误放在 WWW-Authenticate 头中
+pub(crate) fn bearer_error(status: StatusCode, error: &str, description: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .insert_header((
+            header::WWW_AUTHENTICATE,
+            format!(r#"Bearer error="{error}", error_description="{description}""#),
+        ))
+        .json(json!({ "error": error, "error_description": description }))
+}
+
+// 只接受授权了 openid scope 的 access token，返回的字段由 profile / email scope 决定
+async fn userinfo(req: HttpRequest, repo: web::Data<dyn UserRepository>) -> impl Responder {
+    let Ok(token) = bearer_token(&req) else {
+        return bearer_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_token",
+            "Missing bearer token",
+        );
+    };
+    let claims = match validate_access_token(&token, repo.get_ref()).await {
+        Ok(claims) => claims,
+        Err(_) => {
+            return bearer_error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_token",
+                "Invalid or expired access token",
+            );
+        }
+    };
+
+    let scope = claims.scope.as_deref();
+    if !has_scope(scope, "openid") {
+        return bearer_error(
+            StatusCode::FORBIDDEN,
+            "insufficient_scope",
+            "The openid scope is required",
+        );
+    }
+
+    let user = match Uuid::from_str(&claims.sub) {
+        Ok(user_id) => repo.get_user_by_id(&user_id).await,
+        Err(_) => Err(anyhow::anyhow!("Invalid subject")),
+    };
+    let Ok(user) = user else {
+        return bearer_error(StatusCode::UNAUTHORIZED, "invalid_token", "User not found");
+    };
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(UserInfoData {
+            sub: user.id.to_string(),
+            name: has_scope(scope, "profile").then_some(user.username),
+            email: has_scope(scope, "email").then_some(user.email),
+        })
+}
+
+// 挂在根路径下；/userinfo 同时支持 GET 和 POST（OIDC Core 5.3）
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(openid_configuration).service(
+        web::resource("/userinfo")
+            .route(web::get().to(userinfo))
+            .route(web::post().to(userinfo)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test};
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        handlers::issue_session_tokens,
+        models::{SessionGrant, User},
+        test_support::{create_user, repository},
+    };
+
+    async fn access_token_with_scope(
+        repo: &dyn UserRepository,
+        user: &User,
+        scope: &str,
+    ) -> String {
+        let grant = SessionGrant {
+            client_id: Some("test-client".to_string()),
+            scope: Some(scope.to_string()),
+            ..SessionGrant::new(vec!["pwd".to_string(), "mfa".to_string()])
+        };
+        let (access_token, _) = issue_session_tokens(repo, user, grant, None)
+            .await
+            .expect("Failed to issue tokens");
+        access_token
+    }
+
+    fn userinfo_request(access_token: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri("/userinfo")
+            .insert_header(("Authorization", format!("Bearer {access_token}")))
+    }
+
+    #[sqlx::test]
+    async fn userinfo_only_returns_claims_of_granted_scopes(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let token = access_token_with_scope(repo.as_ref(), &user, "openid email").await;
+        let body: Value =
+            test::call_and_read_body_json(&app, userinfo_request(&token).to_request()).await;
+        assert_eq!(body["sub"], user.id.to_string());
+        assert_eq!(body["email"], "alice@example.com");
+        assert!(body.get("name").is_none());
+
+        let token = access_token_with_scope(repo.as_ref(), &user, "openid profile").await;
+        let body: Value =
+            test::call_and_read_body_json(&app, userinfo_request(&token).to_request()).await;
+        assert_eq!(body["name"], "alice");
+        assert!(body.get("email").is_none());
+    }
+
+    #[sqlx::test]
+    async fn userinfo_requires_the_openid_scope(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "bob").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let token = access_token_with_scope(repo.as_ref(), &user, "profile email").await;
+        let resp = test::call_service(&app, userinfo_request(&token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&app, userinfo_request("not-a-token").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
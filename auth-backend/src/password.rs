@@ -0,0 +1,227 @@
+use std::{
+    env,
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, SyncSender, TrySendError},
+    },
+    thread,
+};
+
+use actix_web::{HttpResponse, http::header};
+use anyhow::{Result, anyhow};
+use argon2::{
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher as _, PasswordVerifier, Version,
+    password_hash::SaltString,
+};
+use rand::rngs::OsRng;
+use tokio::sync::oneshot;
+
+use crate::models::ApiResponse;
+
+// 队列已满时建议客户端等待的秒数
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+// 新密码使用的哈希算法及参数，由 PASSWORD_HASHER 等环境变量决定
+// 校验时按存储格式的前缀识别算法：
+// - Argon2 使用 PHC 格式：$argon2id$v=19$m=...,t=...,p=...$salt$hash
+// - bcrypt 使用 modular crypt 格式：$2b$12$...
+#[derive(Debug, Clone)]
+pub enum HashAlgorithm {
+    Argon2id(Params),
+    Bcrypt(u32),
+}
+
+// 密码正确但存储的哈希与当前配置不一致（旧算法或旧参数）时 needs_rehash 为 true
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordCheck {
+    pub valid: bool,
+    pub needs_rehash: bool,
+}
+
+fn env_u32(name: &str, default: u32) -> u32 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+impl HashAlgorithm {
+    // 默认 Argon2id，参数取 OWASP 推荐的 m=19 MiB, t=2, p=1
+    // 配置错误时直接 panic，启动即失败
+    pub fn from_env() -> Self {
+        match env::var("PASSWORD_HASHER").as_deref() {
+            Ok("argon2id") | Err(_) => {
+                let params = Params::new(
+                    env_u32("ARGON2_MEMORY_KIB", 19 * 1024),
+                    env_u32("ARGON2_ITERATIONS", 2),
+                    env_u32("ARGON2_PARALLELISM", 1),
+                    None,
+                )
+                .expect("Invalid Argon2 parameters");
+                HashAlgorithm::Argon2id(params)
+            }
+            Ok("bcrypt") => {
+                let cost = env_u32("BCRYPT_COST", bcrypt::DEFAULT_COST);
+                assert!((4..=31).contains(&cost), "Invalid BCRYPT_COST: {cost}");
+                HashAlgorithm::Bcrypt(cost)
+            }
+            Ok(other) => panic!("Unsupported PASSWORD_HASHER: {other}"),
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String> {
+        match self {
+            HashAlgorithm::Argon2id(params) => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|e| anyhow!("Failed to hash password: {e}"))?;
+                Ok(hash.to_string())
+            }
+            HashAlgorithm::Bcrypt(cost) => Ok(bcrypt::hash(password, *cost)?),
+        }
+    }
+
+    pub fn verify(&self, password: &str, hash: &str) -> Result<PasswordCheck> {
+        if hash.starts_with("$argon2") {
+            let parsed =
+                PasswordHash::new(hash).map_err(|e| anyhow!("Invalid password hash: {e}"))?;
+            // 校验使用哈希中记录的算法和参数，与当前配置无关
+            let valid = Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok();
+            let current = match self {
+                HashAlgorithm::Argon2id(params) => {
+                    parsed.algorithm == Algorithm::Argon2id.ident()
+                        && parsed.version == Some(Version::V0x13.into())
+                        && Params::try_from(&parsed).is_ok_and(|stored| {
+                            stored.m_cost() == params.m_cost()
+                                && stored.t_cost() == params.t_cost()
+                                && stored.p_cost() == params.p_cost()
+                        })
+                }
+                HashAlgorithm::Bcrypt(_) => false,
+            };
+            Ok(PasswordCheck {
+                valid,
+                needs_rehash: valid && !current,
+            })
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            let valid = bcrypt::verify(password, hash)?;
+            let current = match self {
+                HashAlgorithm::Bcrypt(cost) => hash.get(4..6) == Some(&format!("{cost:02}")),
+                HashAlgorithm::Argon2id(_) => false,
+            };
+            Ok(PasswordCheck {
+                valid,
+                needs_rehash: valid && !current,
+            })
+        } else {
+            Err(anyhow!("Unsupported password hash format"))
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// 哈希一次需要几十到几百毫秒的 CPU，不能在 actix worker 线程上执行，
+// 否则同一 worker 上的其他请求都会被阻塞。这里交给专用的线程池：
+// - PASSWORD_HASH_WORKERS 个线程同时计算，默认等于 CPU 核数
+// - 等待中的任务最多 PASSWORD_HASH_QUEUE 个，队列满时直接返回 503，而不是无限排队
+pub struct PasswordHasher {
+    algorithm: HashAlgorithm,
+    jobs: SyncSender<Job>,
+}
+
+#[derive(Debug)]
+pub enum PasswordError {
+    Overloaded,
+    Failed(anyhow::Error),
+}
+
+impl PasswordError {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            PasswordError::Overloaded => HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECONDS.to_string()))
+                .json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "Server is busy, please try again later".to_string(),
+                    data: None,
+                }),
+            PasswordError::Failed(e) => {
+                println!("⚠️ 密码哈希失败: {e:#}");
+                HttpResponse::InternalServerError().json(ApiResponse::<()> {
+                    status: "error".to_string(),
+                    message: "Password processing failed".to_string(),
+                    data: None,
+                })
+            }
+        }
+    }
+}
+
+impl PasswordHasher {
+    pub fn from_env() -> Self {
+        let algorithm = HashAlgorithm::from_env();
+        let default_workers = thread::available_parallelism().map_or(1, |n| n.get() as u32);
+        let workers = env_u32("PASSWORD_HASH_WORKERS", default_workers).max(1);
+        let queue = env_u32("PASSWORD_HASH_QUEUE", 64);
+        Self::new(algorithm, workers, queue)
+    }
+
+    pub fn new(algorithm: HashAlgorithm, workers: u32, queue: u32) -> Self {
+        let (jobs, receiver) = mpsc::sync_channel::<Job>(queue as usize);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("password-hash-{i}"))
+                .spawn(move || {
+                    // 取任务时持有锁，取到后立即释放，计算期间其他线程可以继续取
+                    while let Ok(job) = receiver.lock().unwrap().recv() {
+                        job();
+                    }
+                })
+                .expect("Failed to spawn password hashing thread");
+        }
+
+        PasswordHasher { algorithm, jobs }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, PasswordError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&HashAlgorithm) -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let algorithm = self.algorithm.clone();
+        let job: Job = Box::new(move || {
+            let _ = tx.send(f(&algorithm));
+        });
+        self.jobs.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => PasswordError::Overloaded,
+            TrySendError::Disconnected(_) => {
+                PasswordError::Failed(anyhow!("Password hashing pool has stopped"))
+            }
+        })?;
+
+        rx.await
+            .map_err(|_| PasswordError::Failed(anyhow!("Password hashing job was dropped")))?
+            .map_err(PasswordError::Failed)
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, PasswordError> {
+        let password = password.to_string();
+        self.run(move |algorithm| algorithm.hash(&password)).await
+    }
+
+    pub async fn verify(&self, password: &str, hash: &str) -> Result<PasswordCheck, PasswordError> {
+        let (password, hash) = (password.to_string(), hash.to_string());
+        self.run(move |algorithm| algorithm.verify(&password, &hash))
+            .await
+    }
+}
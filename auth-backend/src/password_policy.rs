@@ -0,0 +1,291 @@
+use std::{env, fs, io::ErrorKind, path::PathBuf};
+
+use actix_web::{HttpResponse, web};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use zxcvbn::zxcvbn;
+
+use crate::models::ApiResponse;
+
+// zxcvbn 的耗时随长度快速增长，只评估前 100 个字符（与 zxcvbn 官方建议一致）
+const MAX_SCORED_CHARS: usize = 100;
+
+// 已知泄露的密码，按 SHA-1 比对：
+// - Hashes：BREACHED_PASSWORDS_FILE 中的哈希全部加载到内存，只保留前 8 字节，排序后二分查找
+// - Ranges：BREACHED_PASSWORDS_DIR 下按 SHA-1 前 5 位分文件（与 HIBP range 接口相同的 k-匿名格式），
+//   每次只读取一个小文件，适合完整的泄露库
+enum BreachedCorpus {
+    Disabled,
+    Hashes(Vec<u64>),
+    Ranges(PathBuf),
+}
+
+// 注册、重置密码、修改密码时统一使用的密码策略，由环境变量配置
+pub struct PasswordPolicy {
+    min_length: usize,
+    // bcrypt 只使用前 72 字节，超出部分会被静默忽略
+    max_bytes: usize,
+    min_char_classes: usize,
+    min_score: u8,
+    breached: BreachedCorpus,
+}
+
+// 不满足策略的所有原因，一次性返回给前端
+#[derive(Debug)]
+pub struct PolicyViolations(Vec<String>);
+
+impl PolicyViolations {
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::BadRequest().json(ApiResponse {
+            status: "error".to_string(),
+            message: "Password does not meet the requirements".to_string(),
+            data: Some(json!({ "violations": self.0 })),
+        })
+    }
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn sha1_hex(password: &str) -> String {
+    hex::encode_upper(Sha1::digest(password.as_bytes()))
+}
+
+// 文件每行一个 SHA-1（可带 HIBP 的 ":次数" 后缀），不是 40 位十六进制的行按明文密码处理
+fn load_hashes(path: &str) -> Vec<u64> {
+    let content = fs::read_to_string(path).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+    let mut hashes: Vec<u64> = content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let hash = line.split(':').next().unwrap_or_default();
+            let hex = if hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                hash.to_uppercase()
+            } else {
+                sha1_hex(line)
+            };
+            u64::from_str_radix(&hex[..16], 16).unwrap_or_default()
+        })
+        .collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+    hashes
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        let breached = match (
+            env::var("BREACHED_PASSWORDS_FILE"),
+            env::var("BREACHED_PASSWORDS_DIR"),
+        ) {
+            (Ok(path), _) => {
+                let hashes = load_hashes(&path);
+                println!("🔒 已加载 {} 条泄露密码哈希", hashes.len());
+                BreachedCorpus::Hashes(hashes)
+            }
+            (_, Ok(dir)) => {
+                let dir = PathBuf::from(dir);
+                assert!(dir.is_dir(), "{} is not a directory", dir.display());
+                BreachedCorpus::Ranges(dir)
+            }
+            _ => BreachedCorpus::Disabled,
+        };
+
+        PasswordPolicy {
+            min_length: env_usize("PASSWORD_MIN_LENGTH", 8).max(1),
+            max_bytes: env_usize("PASSWORD_MAX_BYTES", 72),
+            min_char_classes: env_usize("PASSWORD_MIN_CHAR_CLASSES", 1),
+            min_score: env_usize("PASSWORD_MIN_SCORE", 3).min(4) as u8,
+            breached,
+        }
+    }
+
+    // user_inputs：用户名、邮箱等，密码中不能包含这些内容
+    pub async fn check(
+        &self,
+        password: &str,
+        user_inputs: &[&str],
+    ) -> Result<(), PolicyViolations> {
+        let mut violations = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            violations.push(format!(
+                "Password must be at least {} characters",
+                self.min_length
+            ));
+        }
+        if password.len() > self.max_bytes {
+            violations.push(format!("Password must be at most {} bytes", self.max_bytes));
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ]
+        .into_iter()
+        .filter(|present| *present)
+        .count();
+        if classes < self.min_char_classes {
+            violations.push(format!(
+                "Password must contain at least {} of: lowercase letters, uppercase letters, digits, symbols",
+                self.min_char_classes
+            ));
+        }
+
+        let lowered = password.to_lowercase();
+        let contains_user_input = user_inputs
+            .iter()
+            .flat_map(|input| [*input, input.split('@').next().unwrap_or_default()])
+            .map(|input| input.to_lowercase())
+            .any(|input| input.chars().count() >= 3 && lowered.contains(&input));
+        if contains_user_input {
+            violations.push("Password must not contain your username or email".to_string());
+        }
+
+        if let Err(warning) = self.check_strength(password, user_inputs) {
+            violations.push(match warning {
+                Some(warning) => format!("Password is too easy to guess: {warning}"),
+                None => "Password is too easy to guess, avoid common words, names, dates and keyboard patterns".to_string(),
+            });
+        }
+
+        if self.is_breached(password).await {
+            violations.push(
+                "This password has appeared in a data breach, please choose another one"
+                    .to_string(),
+            );
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(PolicyViolations(violations))
+        }
+    }
+
+    // zxcvbn 评分（0-4）：内置常见密码、英文单词、人名等词典，并识别重复、序列、键盘模式和日期
+    // 不满足时返回 zxcvbn 给出的提示（可能没有）
+    fn check_strength(&self, password: &str, user_inputs: &[&str]) -> Result<(), Option<String>> {
+        let scored: String = password.chars().take(MAX_SCORED_CHARS).collect();
+        let entropy = zxcvbn(&scored, user_inputs);
+        if u8::from(entropy.score()) >= self.min_score {
+            return Ok(());
+        }
+        Err(entropy
+            .feedback()
+            .and_then(|feedback| feedback.warning())
+            .map(|warning| warning.to_string()))
+    }
+
+    async fn is_breached(&self, password: &str) -> bool {
+        match &self.breached {
+            BreachedCorpus::Disabled => false,
+            BreachedCorpus::Hashes(hashes) => {
+                let hex = sha1_hex(password);
+                let prefix = u64::from_str_radix(&hex[..16], 16).unwrap_or_default();
+                hashes.binary_search(&prefix).is_ok()
+            }
+            BreachedCorpus::Ranges(dir) => {
+                let hex = sha1_hex(password);
+                let (prefix, suffix) = hex.split_at(5);
+                let path = dir.join(format!("{prefix}.txt"));
+                // 读文件会阻塞，放到 actix 的阻塞线程池中
+                match web::block(move || fs::read_to_string(path)).await {
+                    Ok(Ok(content)) => content.lines().any(|line| {
+                        line.split(':')
+                            .next()
+                            .is_some_and(|hash| hash.eq_ignore_ascii_case(suffix))
+                    }),
+                    Ok(Err(e)) if e.kind() == ErrorKind::NotFound => false,
+                    // 泄露库不可用时不阻止用户设置密码
+                    Ok(Err(e)) => {
+                        println!("⚠️ 读取泄露密码库失败: {e}");
+                        false
+                    }
+                    Err(e) => {
+                        println!("⚠️ 读取泄露密码库失败: {e}");
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_bytes: 72,
+            min_char_classes: 1,
+            min_score: 3,
+            breached: BreachedCorpus::Disabled,
+        }
+    }
+
+    #[test]
+    fn common_passwords_are_too_weak() {
+        let policy = default_policy();
+        for password in [
+            "password",
+            "Password1",
+            "iloveyou",
+            "sunshine",
+            "football",
+            "letmein1",
+            "qwertyuiop",
+            "aaaaaaaaaa",
+            "12345678",
+            "January2024",
+        ] {
+            assert!(policy.check_strength(password, &[]).is_err(), "{password}");
+        }
+    }
+
+    #[test]
+    fn passphrases_and_random_passwords_are_strong_enough() {
+        let policy = default_policy();
+        for password in ["correct horse battery staple", "vK9#mQ2$xL7!pR4z"] {
+            assert!(policy.check_strength(password, &[]).is_ok(), "{password}");
+        }
+    }
+
+    #[test]
+    fn user_inputs_lower_the_score() {
+        let policy = default_policy();
+        let password = "xkqvbrmt2019";
+        assert!(policy.check_strength(password, &[]).is_ok());
+        assert!(
+            policy
+                .check_strength(password, &["xkqvbrmt", "xkqvbrmt@example.com"])
+                .is_err()
+        );
+    }
+
+    #[actix_web::test]
+    async fn check_reports_every_violation() {
+        let policy = default_policy();
+        let Err(PolicyViolations(violations)) = policy
+            .check("alice123", &["alice", "alice@example.com"])
+            .await
+        else {
+            panic!("alice123 should be rejected");
+        };
+        assert_eq!(violations.len(), 2, "{violations:?}");
+        assert!(
+            policy
+                .check("correct horse battery staple", &["alice"])
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -1,6 +1,11 @@
-use crate::models::{RegisterRequest, User};
+use crate::models::{
+    NewSession, OAuthAuthorizationCode, OAuthAuthorizationRequest, OAuthClient, OAuthDeviceCode,
+    OtpAttempt, RegisterRequest, Session, SigningKeyRecord, User, WebauthnCeremony,
+    WebauthnCredential,
+};
 use anyhow::{Ok, Result};
 use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
 use sqlx::PgPool;
 use std::sync::Arc;
 use uuid::Uuid;
@@ -13,14 +18,199 @@ pub trait UserRepository: Send + Sync {
     async fn get_user_by_email(&self, email: &str) -> Result<User>;
     async fn get_user_by_id(&self, user_id: &Uuid) -> Result<User>;
     async fn create_user(&self, req: &RegisterRequest, password_hash: &str) -> Result<User>;
-    async fn update_user_otp(
+    async fn update_user_password(&self, user_id: &Uuid, password_hash: &str) -> Result<()>;
+    // 保存新的重置令牌，同时作废该用户之前的令牌
+    async fn create_password_reset_token(
+        &self,
+        user_id: &Uuid,
+        token_hash: &str,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<()>;
+    // 只返回尚未使用、尚未过期的令牌对应的用户
+    async fn get_password_reset_user(&self, token_hash: &str) -> Result<Option<Uuid>>;
+    // 在一个事务中标记令牌已使用、更新密码、吊销所有会话并使已签发的 access token 失效
+    // 令牌已被使用或已过期时不做任何修改并返回 false，保证只能使用一次
+    async fn reset_password_with_token(
+        &self,
+        token_hash: &str,
+        user_id: &Uuid,
+        password_hash: &str,
+    ) -> Result<bool>;
+    // 保存待验证的 OTP 密钥，不影响当前已启用的密钥
+    async fn set_pending_user_otp(
         &self,
         user_id: &Uuid,
         otp_base32: &str,
         otp_auth_url: &str,
+        expires_at: &DateTime<Utc>,
     ) -> Result<()>;
+    // 将未过期的 pending 密钥转为生效密钥，返回是否成功
+    // 激活时同时记录验证所用的时间步，防止同一个验证码再被用于登录
+    async fn activate_pending_user_otp(
+        &self,
+        user_id: &Uuid,
+        otp_base32: &str,
+        step: i64,
+    ) -> Result<bool>;
     async fn disable_user_otp(&self, user_id: &Uuid) -> Result<()>;
-    async fn verify_user_otp(&self, user_id: &Uuid) -> Result<()>;
+    // 只接受比上次更新的时间步，返回是否成功；成功后清空失败计数
+    async fn accept_otp_step(&self, user_id: &Uuid, step: i64) -> Result<bool>;
+    // 校验 OTP / 恢复码之前先占用一次尝试：锁定期内返回 Locked；
+    // 否则连续失败次数加一，lockout 根据新的次数给出锁定时长时同时写入锁定时间。
+    // 检查和计数在同一个行锁内完成，并发请求无法同时越过锁定检查
+    async fn claim_otp_attempt(
+        &self,
+        user_id: &Uuid,
+        lockout: fn(i32) -> Option<Duration>,
+    ) -> Result<OtpAttempt>;
+    // 第二因子验证成功后清空连续失败次数和锁定
+    async fn reset_otp_attempts(&self, user_id: &Uuid) -> Result<()>;
+    // 占用某个 mfa token 的一次尝试，已达到 max_attempts 时返回 false
+    async fn claim_mfa_token_attempt(
+        &self,
+        jti: &Uuid,
+        user_id: &Uuid,
+        expires_at: &DateTime<Utc>,
+        max_attempts: i32,
+    ) -> Result<bool>;
+    async fn reset_mfa_token_attempts(&self, jti: &Uuid) -> Result<()>;
+
+    // 用新的一组恢复码替换旧的（旧的全部作废）
+    async fn replace_recovery_codes(&self, user_id: &Uuid, code_hashes: &[String]) -> Result<()>;
+    // 消耗一个未使用的恢复码，返回是否成功
+    async fn use_recovery_code(&self, user_id: &Uuid, code_hash: &str) -> Result<bool>;
+    async fn count_unused_recovery_codes(&self, user_id: &Uuid) -> Result<i64>;
+
+    async fn create_webauthn_credential(
+        &self,
+        user_id: &Uuid,
+        credential_id: &str,
+        passkey: &serde_json::Value,
+        name: &str,
+        attested: bool,
+    ) -> Result<WebauthnCredential>;
+    async fn list_webauthn_credentials(&self, user_id: &Uuid) -> Result<Vec<WebauthnCredential>>;
+    // 认证成功后保存更新过的凭证（签名计数器等），并记录使用时间
+    async fn update_webauthn_credential(
+        &self,
+        id: &Uuid,
+        passkey: &serde_json::Value,
+    ) -> Result<()>;
+    async fn delete_webauthn_credential(&self, user_id: &Uuid, id: &Uuid) -> Result<bool>;
+    async fn create_webauthn_ceremony(
+        &self,
+        user_id: Option<&Uuid>,
+        kind: &str,
+        state: &serde_json::Value,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<Uuid>;
+    // 取出并删除未过期的仪式状态，保证每个 challenge 只能使用一次
+    async fn take_webauthn_ceremony(
+        &self,
+        id: &Uuid,
+        kind: &str,
+    ) -> Result<Option<WebauthnCeremony>>;
+
+    async fn create_session(&self, session: &NewSession) -> Result<Session>;
+    async fn get_session_by_jti(&self, jti: &Uuid) -> Result<Session>;
+    // 只有尚未轮换、尚未吊销的 session 才能被轮换，返回是否轮换成功
+    async fn rotate_session(&self, jti: &Uuid) -> Result<bool>;
+    async fn revoke_session_family(&self, family_id: &Uuid) -> Result<()>;
+    async fn revoke_user_sessions(&self, user_id: &Uuid) -> Result<()>;
+    // 吊销用户除 keep_family_id 之外的所有 session
+    async fn revoke_other_user_sessions(&self, user_id: &Uuid, keep_family_id: &Uuid)
+    -> Result<()>;
+    async fn is_session_family_active(&self, family_id: &Uuid) -> Result<bool>;
+    async fn increment_token_version(&self, user_id: &Uuid) -> Result<()>;
+    // 吊销单个 access token，记录保留到令牌过期
+    async fn revoke_token(&self, jti: &str, expires_at: &DateTime<Utc>) -> Result<()>;
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool>;
+    // 引用令牌：保存签发时的声明，按令牌哈希取回
+    async fn create_reference_token(
+        &self,
+        token_hash: &str,
+        claims: &serde_json::Value,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<()>;
+    async fn get_reference_token(&self, token_hash: &str) -> Result<Option<serde_json::Value>>;
+
+    // 未退役的签名密钥（包括尚未生效的下一把密钥），按生效时间排序
+    async fn list_signing_keys(&self) -> Result<Vec<SigningKeyRecord>>;
+    async fn create_signing_key(&self, key: &SigningKeyRecord) -> Result<()>;
+    // 为 activated_before 之前生效、尚未设置退役时间的密钥设置退役时间
+    async fn retire_signing_keys(
+        &self,
+        activated_before: &DateTime<Utc>,
+        retired_at: &DateTime<Utc>,
+    ) -> Result<u64>;
+
+    async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>>;
+    async fn list_oauth_clients(&self) -> Result<Vec<OAuthClient>>;
+    async fn create_oauth_client(&self, client: &OAuthClient) -> Result<()>;
+    // 按 client_id 更新除 created_at 以外的所有字段，返回客户端是否存在
+    async fn update_oauth_client(&self, client: &OAuthClient) -> Result<bool>;
+    // 删除客户端，同时吊销通过它签发的会话
+    async fn delete_oauth_client(&self, client_id: &str) -> Result<bool>;
+    // 所有客户端登记的 CORS 来源
+    async fn list_oauth_client_origins(&self) -> Result<Vec<String>>;
+    // 记录已使用的客户端断言 jti，返回 false 表示该断言已经用过
+    async fn record_client_assertion(
+        &self,
+        client_id: &str,
+        jti: &str,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<bool>;
+    async fn create_oauth_authorization_request(
+        &self,
+        request: &OAuthAuthorizationRequest,
+    ) -> Result<()>;
+    // 只返回尚未过期的授权请求；take 会同时删除它，保证只能被同意或拒绝一次
+    async fn get_oauth_authorization_request(
+        &self,
+        id: &Uuid,
+    ) -> Result<Option<OAuthAuthorizationRequest>>;
+    // 把授权请求绑定到第一个处理它的用户，已绑定到其他用户时返回 false
+    async fn bind_oauth_authorization_request(&self, id: &Uuid, user_id: &Uuid) -> Result<bool>;
+    // 只有绑定的用户才能取出授权请求
+    async fn take_oauth_authorization_request(
+        &self,
+        id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<Option<OAuthAuthorizationRequest>>;
+    async fn create_oauth_authorization_code(&self, code: &OAuthAuthorizationCode) -> Result<()>;
+    async fn get_oauth_authorization_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<OAuthAuthorizationCode>>;
+    // 只有尚未使用、尚未过期的授权码才能被使用，返回是否成功
+    async fn use_oauth_authorization_code(&self, code_hash: &str) -> Result<bool>;
+
+    async fn create_oauth_device_code(&self, device_code: &OAuthDeviceCode) -> Result<()>;
+    async fn get_oauth_device_code(
+        &self,
+        device_code_hash: &str,
+    ) -> Result<Option<OAuthDeviceCode>>;
+    // 用户输入的 user_code 只能匹配尚未处理、尚未过期的设备授权
+    async fn get_pending_oauth_device_code(
+        &self,
+        user_code: &str,
+    ) -> Result<Option<OAuthDeviceCode>>;
+    async fn approve_oauth_device_code(
+        &self,
+        user_code: &str,
+        user_id: &Uuid,
+        amr: &[String],
+        auth_time: &DateTime<Utc>,
+    ) -> Result<bool>;
+    async fn deny_oauth_device_code(&self, user_code: &str) -> Result<bool>;
+    // 记录客户端轮询时间，轮询过快时同时增大间隔
+    async fn record_oauth_device_poll(
+        &self,
+        device_code_hash: &str,
+        interval_seconds: i32,
+    ) -> Result<()>;
+    // 删除已同意的设备授权，保证只能兑换一次令牌，返回是否成功
+    async fn take_approved_oauth_device_code(&self, device_code_hash: &str) -> Result<bool>;
 }
 
 // --- 2. "PostgreSQL 实现" ---
@@ -68,42 +258,865 @@ impl UserRepository for PostgresRepository {
         Ok(user)
     }
 
-    async fn update_user_otp(
+    async fn update_user_password(&self, user_id: &Uuid, password_hash: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET password_hash = $2, updated_at = NOW() WHERE id = $1",
+            user_id,
+            password_hash
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn create_password_reset_token(
+        &self,
+        user_id: &Uuid,
+        token_hash: &str,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM password_reset_tokens WHERE user_id = $1 OR expires_at <= NOW()",
+            user_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        sqlx::query!(
+            "INSERT INTO password_reset_tokens (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+            token_hash,
+            user_id,
+            expires_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn get_password_reset_user(&self, token_hash: &str) -> Result<Option<Uuid>> {
+        let user_id = sqlx::query_scalar!(
+            "SELECT user_id FROM password_reset_tokens WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()",
+            token_hash
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(user_id)
+    }
+
+    async fn reset_password_with_token(
+        &self,
+        token_hash: &str,
+        user_id: &Uuid,
+        password_hash: &str,
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query!(
+            "UPDATE password_reset_tokens SET used_at = NOW() WHERE token_hash = $1 AND user_id = $2 AND used_at IS NULL AND expires_at > NOW()",
+            token_hash,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() != 1 {
+            return Ok(false);
+        }
+        sqlx::query!(
+            "UPDATE users SET password_hash = $2, token_version = token_version + 1, updated_at = NOW() WHERE id = $1",
+            user_id,
+            password_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn set_pending_user_otp(
         &self,
         user_id: &Uuid,
         otp_base32: &str,
         otp_auth_url: &str,
+        expires_at: &DateTime<Utc>,
     ) -> Result<()> {
         sqlx::query!(
-            "UPDATE users SET otp_enabled = true, otp_base32 = $1, otp_auth_url = $2 WHERE id = $3",
+            "UPDATE users SET otp_pending_base32 = $1, otp_pending_auth_url = $2, otp_pending_expires_at = $3, updated_at = NOW() WHERE id = $4",
             otp_base32,
             otp_auth_url,
+            expires_at,
+            user_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn activate_pending_user_otp(
+        &self,
+        user_id: &Uuid,
+        otp_base32: &str,
+        step: i64,
+    ) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE users SET otp_enabled = true, otp_verified = true, otp_base32 = otp_pending_base32, otp_auth_url = otp_pending_auth_url, otp_pending_base32 = NULL, otp_pending_auth_url = NULL, otp_pending_expires_at = NULL, otp_last_used_step = $3, failed_otp_attempts = 0, otp_locked_until = NULL, updated_at = NOW() WHERE id = $1 AND otp_pending_base32 = $2 AND otp_pending_expires_at > NOW()",
+            user_id,
+            otp_base32,
+            step
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn disable_user_otp(&self, user_id: &Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            "UPDATE users SET otp_enabled = false, otp_verified = false, otp_base32 = NULL, otp_auth_url = NULL, otp_pending_base32 = NULL, otp_pending_auth_url = NULL, otp_pending_expires_at = NULL, otp_last_used_step = NULL, failed_otp_attempts = 0, otp_locked_until = NULL, updated_at = NOW() WHERE id = $1",
             user_id
         )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn accept_otp_step(&self, user_id: &Uuid, step: i64) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE users SET otp_last_used_step = $2, failed_otp_attempts = 0, otp_locked_until = NULL WHERE id = $1 AND (otp_last_used_step IS NULL OR otp_last_used_step < $2)",
+            user_id,
+            step
+        )
         .execute(self.pool.as_ref())
         .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn claim_otp_attempt(
+        &self,
+        user_id: &Uuid,
+        lockout: fn(i32) -> Option<Duration>,
+    ) -> Result<OtpAttempt> {
+        let mut tx = self.pool.begin().await?;
+        let row = sqlx::query!(
+            "SELECT failed_otp_attempts, otp_locked_until FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if let Some(locked_until) = row.otp_locked_until
+            && locked_until > Utc::now()
+        {
+            return Ok(OtpAttempt::Locked(locked_until));
+        }
+
+        let attempts = row.failed_otp_attempts + 1;
+        let locked_until = lockout(attempts).map(|lockout| Utc::now() + lockout);
+        sqlx::query!(
+            "UPDATE users SET failed_otp_attempts = $2, otp_locked_until = $3 WHERE id = $1",
+            user_id,
+            attempts,
+            locked_until
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(OtpAttempt::Claimed)
+    }
+
+    async fn reset_otp_attempts(&self, user_id: &Uuid) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET failed_otp_attempts = 0, otp_locked_until = NULL WHERE id = $1",
+            user_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn claim_mfa_token_attempt(
+        &self,
+        jti: &Uuid,
+        user_id: &Uuid,
+        expires_at: &DateTime<Utc>,
+        max_attempts: i32,
+    ) -> Result<bool> {
+        // 顺便清理已经过期的记录
+        sqlx::query!("DELETE FROM mfa_token_attempts WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        // 已达到上限时 ON CONFLICT 的 WHERE 不成立，不更新也不返回行
+        let attempts = sqlx::query_scalar!(
+            "INSERT INTO mfa_token_attempts (jti, user_id, failed_attempts, expires_at) VALUES ($1, $2, 1, $3) ON CONFLICT (jti) DO UPDATE SET failed_attempts = mfa_token_attempts.failed_attempts + 1 WHERE mfa_token_attempts.failed_attempts < $4 RETURNING failed_attempts",
+            jti,
+            user_id,
+            expires_at,
+            max_attempts
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(attempts.is_some())
+    }
+
+    async fn reset_mfa_token_attempts(&self, jti: &Uuid) -> Result<()> {
+        sqlx::query!("DELETE FROM mfa_token_attempts WHERE jti = $1", jti)
+            .execute(self.pool.as_ref())
+            .await?;
         Ok(())
     }
 
-    async fn verify_user_otp(&self, user_id: &Uuid) -> Result<()> {
+    async fn replace_recovery_codes(&self, user_id: &Uuid, code_hashes: &[String]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM mfa_recovery_codes WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
         sqlx::query!(
-            "UPDATE users SET otp_verified = true WHERE id = $1",
+            "INSERT INTO mfa_recovery_codes (user_id, code_hash) SELECT $1, * FROM UNNEST($2::TEXT[])",
+            user_id,
+            code_hashes
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn use_recovery_code(&self, user_id: &Uuid, code_hash: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE mfa_recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+            user_id,
+            code_hash
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn count_unused_recovery_codes(&self, user_id: &Uuid) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM mfa_recovery_codes WHERE user_id = $1 AND used_at IS NULL"#,
+            user_id
+        )
+        .fetch_one(self.pool.as_ref())
+        .await?;
+        Ok(count)
+    }
+
+    async fn create_webauthn_credential(
+        &self,
+        user_id: &Uuid,
+        credential_id: &str,
+        passkey: &serde_json::Value,
+        name: &str,
+        attested: bool,
+    ) -> Result<WebauthnCredential> {
+        let credential = sqlx::query_as!(
+            WebauthnCredential,
+            "INSERT INTO webauthn_credentials (user_id, credential_id, passkey, name, attested) VALUES ($1, $2, $3, $4, $5) RETURNING id, credential_id, passkey, name, attested, created_at, last_used_at",
+            user_id,
+            credential_id,
+            passkey,
+            name,
+            attested
+        )
+        .fetch_one(self.pool.as_ref())
+        .await?;
+        Ok(credential)
+    }
+
+    async fn list_webauthn_credentials(&self, user_id: &Uuid) -> Result<Vec<WebauthnCredential>> {
+        let credentials = sqlx::query_as!(
+            WebauthnCredential,
+            "SELECT id, credential_id, passkey, name, attested, created_at, last_used_at FROM webauthn_credentials WHERE user_id = $1 ORDER BY created_at",
             user_id
         )
+        .fetch_all(self.pool.as_ref())
+        .await?;
+        Ok(credentials)
+    }
+
+    async fn update_webauthn_credential(
+        &self,
+        id: &Uuid,
+        passkey: &serde_json::Value,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE webauthn_credentials SET passkey = $1, last_used_at = NOW() WHERE id = $2",
+            passkey,
+            id
+        )
         .execute(self.pool.as_ref())
         .await?;
         Ok(())
     }
 
-    async fn disable_user_otp(&self, user_id: &Uuid) -> Result<()> {
+    async fn delete_webauthn_credential(&self, user_id: &Uuid, id: &Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM webauthn_credentials WHERE id = $1 AND user_id = $2",
+            id,
+            user_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn create_webauthn_ceremony(
+        &self,
+        user_id: Option<&Uuid>,
+        kind: &str,
+        state: &serde_json::Value,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<Uuid> {
+        // 未完成的仪式不会被 take 删除，插入时顺便清理
+        sqlx::query!("DELETE FROM webauthn_ceremonies WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        let id = sqlx::query_scalar!(
+            "INSERT INTO webauthn_ceremonies (user_id, kind, state, expires_at) VALUES ($1, $2, $3, $4) RETURNING id",
+            user_id,
+            kind,
+            state,
+            expires_at
+        )
+        .fetch_one(self.pool.as_ref())
+        .await?;
+        Ok(id)
+    }
+
+    async fn take_webauthn_ceremony(
+        &self,
+        id: &Uuid,
+        kind: &str,
+    ) -> Result<Option<WebauthnCeremony>> {
+        let ceremony = sqlx::query_as!(
+            WebauthnCeremony,
+            "DELETE FROM webauthn_ceremonies WHERE id = $1 AND kind = $2 AND expires_at > NOW() RETURNING user_id, state",
+            id,
+            kind
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(ceremony)
+    }
+
+    async fn create_session(&self, session: &NewSession) -> Result<Session> {
+        let session = sqlx::query_as!(
+            Session,
+            "INSERT INTO sessions (user_id, family_id, jti, token_hash, device_info, expires_at, amr, auth_time, client_id, scope) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING *",
+            session.user_id,
+            session.family_id,
+            session.jti,
+            session.token_hash,
+            session.device_info,
+            session.expires_at,
+            &session.amr,
+            session.auth_time,
+            session.client_id,
+            session.scope
+        )
+        .fetch_one(self.pool.as_ref())
+        .await?;
+        Ok(session)
+    }
+
+    async fn get_session_by_jti(&self, jti: &Uuid) -> Result<Session> {
+        let session = sqlx::query_as!(Session, "SELECT * FROM sessions WHERE jti = $1", jti)
+            .fetch_one(self.pool.as_ref())
+            .await?;
+        Ok(session)
+    }
+
+    async fn rotate_session(&self, jti: &Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE sessions SET rotated_at = NOW() WHERE jti = $1 AND rotated_at IS NULL AND revoked_at IS NULL",
+            jti
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn revoke_session_family(&self, family_id: &Uuid) -> Result<()> {
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL",
+            family_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke_user_sessions(&self, user_id: &Uuid) -> Result<()> {
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn revoke_other_user_sessions(
+        &self,
+        user_id: &Uuid,
+        keep_family_id: &Uuid,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND family_id <> $2 AND revoked_at IS NULL",
+            user_id,
+            keep_family_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn is_session_family_active(&self, family_id: &Uuid) -> Result<bool> {
+        let active = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM sessions WHERE family_id = $1 AND revoked_at IS NULL AND expires_at > NOW()) AS "active!""#,
+            family_id
+        )
+        .fetch_one(self.pool.as_ref())
+        .await?;
+        Ok(active)
+    }
+
+    async fn revoke_token(&self, jti: &str, expires_at: &DateTime<Utc>) -> Result<()> {
+        sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        sqlx::query!(
+            "INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            jti,
+            expires_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool> {
+        let revoked = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1) AS "revoked!""#,
+            jti
+        )
+        .fetch_one(self.pool.as_ref())
+        .await?;
+        Ok(revoked)
+    }
+
+    async fn create_reference_token(
+        &self,
+        token_hash: &str,
+        claims: &serde_json::Value,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!("DELETE FROM reference_tokens WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        sqlx::query!(
+            "INSERT INTO reference_tokens (token_hash, claims, expires_at) VALUES ($1, $2, $3)",
+            token_hash,
+            claims,
+            expires_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn get_reference_token(&self, token_hash: &str) -> Result<Option<serde_json::Value>> {
+        let claims = sqlx::query_scalar!(
+            "SELECT claims FROM reference_tokens WHERE token_hash = $1 AND expires_at > NOW()",
+            token_hash
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(claims)
+    }
+
+    async fn increment_token_version(&self, user_id: &Uuid) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET token_version = token_version + 1, updated_at = NOW() WHERE id = $1",
+            user_id
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn list_signing_keys(&self) -> Result<Vec<SigningKeyRecord>> {
+        let keys = sqlx::query_as!(
+            SigningKeyRecord,
+            "SELECT kid, algorithm, private_key_pem, activated_at, retired_at FROM signing_keys WHERE retired_at IS NULL OR retired_at > NOW() ORDER BY activated_at"
+        )
+        .fetch_all(self.pool.as_ref())
+        .await?;
+        Ok(keys)
+    }
+
+    async fn create_signing_key(&self, key: &SigningKeyRecord) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO signing_keys (kid, algorithm, private_key_pem, activated_at, retired_at) VALUES ($1, $2, $3, $4, $5)",
+            key.kid,
+            key.algorithm,
+            key.private_key_pem,
+            key.activated_at,
+            key.retired_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn retire_signing_keys(
+        &self,
+        activated_before: &DateTime<Utc>,
+        retired_at: &DateTime<Utc>,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE signing_keys SET retired_at = $2 WHERE activated_at < $1 AND retired_at IS NULL",
+            activated_before,
+            retired_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_oauth_client(&self, client_id: &str) -> Result<Option<OAuthClient>> {
+        let client = sqlx::query_as!(
+            OAuthClient,
+            "SELECT client_id, name, redirect_uris, allowed_scopes, token_endpoint_auth_method, client_secret_hash, jwks, grant_types, allowed_audiences, token_format, access_token_ttl_seconds, refresh_token_ttl_seconds, required_acr, allowed_origins, created_at FROM oauth_clients WHERE client_id = $1",
+            client_id
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(client)
+    }
+
+    async fn list_oauth_clients(&self) -> Result<Vec<OAuthClient>> {
+        let clients = sqlx::query_as!(
+            OAuthClient,
+            "SELECT client_id, name, redirect_uris, allowed_scopes, token_endpoint_auth_method, client_secret_hash, jwks, grant_types, allowed_audiences, token_format, access_token_ttl_seconds, refresh_token_ttl_seconds, required_acr, allowed_origins, created_at FROM oauth_clients ORDER BY created_at"
+        )
+        .fetch_all(self.pool.as_ref())
+        .await?;
+        Ok(clients)
+    }
+
+    async fn create_oauth_client(&self, client: &OAuthClient) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO oauth_clients (client_id, name, redirect_uris, allowed_scopes, token_endpoint_auth_method, client_secret_hash, jwks, grant_types, allowed_audiences, token_format, access_token_ttl_seconds, refresh_token_ttl_seconds, required_acr, allowed_origins) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+            client.client_id,
+            client.name,
+            &client.redirect_uris,
+            &client.allowed_scopes,
+            client.token_endpoint_auth_method,
+            client.client_secret_hash,
+            client.jwks,
+            &client.grant_types,
+            &client.allowed_audiences,
+            client.token_format,
+            client.access_token_ttl_seconds,
+            client.refresh_token_ttl_seconds,
+            client.required_acr,
+            &client.allowed_origins
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn update_oauth_client(&self, client: &OAuthClient) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE oauth_clients SET name = $2, redirect_uris = $3, allowed_scopes = $4, token_endpoint_auth_method = $5, client_secret_hash = $6, jwks = $7, grant_types = $8, allowed_audiences = $9, token_format = $10, access_token_ttl_seconds = $11, refresh_token_ttl_seconds = $12, required_acr = $13, allowed_origins = $14, updated_at = NOW() WHERE client_id = $1",
+            client.client_id,
+            client.name,
+            &client.redirect_uris,
+            &client.allowed_scopes,
+            client.token_endpoint_auth_method,
+            client.client_secret_hash,
+            client.jwks,
+            &client.grant_types,
+            &client.allowed_audiences,
+            client.token_format,
+            client.access_token_ttl_seconds,
+            client.refresh_token_ttl_seconds,
+            client.required_acr,
+            &client.allowed_origins
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn delete_oauth_client(&self, client_id: &str) -> Result<bool> {
+        // 吊销会话和删除客户端要么都生效，要么都不生效
+        let mut tx = self.pool.begin().await?;
         sqlx::query!(
-            "UPDATE users SET otp_enabled = false, otp_verified = false, otp_base32 = NULL, otp_auth_url = NULL WHERE id = $1",
+            "UPDATE sessions SET revoked_at = NOW() WHERE client_id = $1 AND revoked_at IS NULL",
+            client_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query!("DELETE FROM oauth_clients WHERE client_id = $1", client_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn list_oauth_client_origins(&self) -> Result<Vec<String>> {
+        let origins = sqlx::query_scalar!(
+            r#"SELECT DISTINCT unnest(allowed_origins) AS "origin!" FROM oauth_clients"#
+        )
+        .fetch_all(self.pool.as_ref())
+        .await?;
+        Ok(origins)
+    }
+
+    async fn record_client_assertion(
+        &self,
+        client_id: &str,
+        jti: &str,
+        expires_at: &DateTime<Utc>,
+    ) -> Result<bool> {
+        sqlx::query!("DELETE FROM oauth_client_assertions WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        let result = sqlx::query!(
+            "INSERT INTO oauth_client_assertions (client_id, jti, expires_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            client_id,
+            jti,
+            expires_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn create_oauth_authorization_request(
+        &self,
+        request: &OAuthAuthorizationRequest,
+    ) -> Result<()> {
+        // 用户没有同意也没有拒绝的请求不会被 take 删除，插入时顺便清理
+        sqlx::query!("DELETE FROM oauth_authorization_requests WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        sqlx::query!(
+            "INSERT INTO oauth_authorization_requests (id, client_id, redirect_uri, scope, state, code_challenge, code_challenge_method, nonce, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            request.id,
+            request.client_id,
+            request.redirect_uri,
+            request.scope,
+            request.state,
+            request.code_challenge,
+            request.code_challenge_method,
+            request.nonce,
+            request.expires_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn get_oauth_authorization_request(
+        &self,
+        id: &Uuid,
+    ) -> Result<Option<OAuthAuthorizationRequest>> {
+        let request = sqlx::query_as!(
+            OAuthAuthorizationRequest,
+            "SELECT id, client_id, redirect_uri, scope, state, code_challenge, code_challenge_method, nonce, expires_at FROM oauth_authorization_requests WHERE id = $1 AND expires_at > NOW()",
+            id
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(request)
+    }
+
+    async fn bind_oauth_authorization_request(&self, id: &Uuid, user_id: &Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE oauth_authorization_requests SET user_id = $2 WHERE id = $1 AND expires_at > NOW() AND (user_id IS NULL OR user_id = $2)",
+            id,
             user_id
         )
         .execute(self.pool.as_ref())
         .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn take_oauth_authorization_request(
+        &self,
+        id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<Option<OAuthAuthorizationRequest>> {
+        let request = sqlx::query_as!(
+            OAuthAuthorizationRequest,
+            "DELETE FROM oauth_authorization_requests WHERE id = $1 AND user_id = $2 AND expires_at > NOW() RETURNING id, client_id, redirect_uri, scope, state, code_challenge, code_challenge_method, nonce, expires_at",
+            id,
+            user_id
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(request)
+    }
+
+    async fn create_oauth_authorization_code(&self, code: &OAuthAuthorizationCode) -> Result<()> {
+        // 清理过期的授权码（无论是否使用过）；已使用但未过期的保留，用于发现授权码重放
+        sqlx::query!("DELETE FROM oauth_authorization_codes WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        sqlx::query!(
+            "INSERT INTO oauth_authorization_codes (code_hash, client_id, user_id, redirect_uri, scope, code_challenge, code_challenge_method, amr, auth_time, family_id, nonce, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            code.code_hash,
+            code.client_id,
+            code.user_id,
+            code.redirect_uri,
+            code.scope,
+            code.code_challenge,
+            code.code_challenge_method,
+            &code.amr,
+            code.auth_time,
+            code.family_id,
+            code.nonce,
+            code.expires_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn get_oauth_authorization_code(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<OAuthAuthorizationCode>> {
+        let code = sqlx::query_as!(
+            OAuthAuthorizationCode,
+            "SELECT code_hash, client_id, user_id, redirect_uri, scope, code_challenge, code_challenge_method, amr, auth_time, family_id, nonce, expires_at, used_at FROM oauth_authorization_codes WHERE code_hash = $1",
+            code_hash
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(code)
+    }
+
+    async fn use_oauth_authorization_code(&self, code_hash: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE oauth_authorization_codes SET used_at = NOW() WHERE code_hash = $1 AND used_at IS NULL AND expires_at > NOW()",
+            code_hash
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn create_oauth_device_code(&self, device_code: &OAuthDeviceCode) -> Result<()> {
+        sqlx::query!("DELETE FROM oauth_device_codes WHERE expires_at <= NOW()")
+            .execute(self.pool.as_ref())
+            .await?;
+        sqlx::query!(
+            "INSERT INTO oauth_device_codes (device_code_hash, user_code, client_id, scope, interval_seconds, expires_at) VALUES ($1, $2, $3, $4, $5, $6)",
+            device_code.device_code_hash,
+            device_code.user_code,
+            device_code.client_id,
+            device_code.scope,
+            device_code.interval_seconds,
+            device_code.expires_at
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    async fn get_oauth_device_code(
+        &self,
+        device_code_hash: &str,
+    ) -> Result<Option<OAuthDeviceCode>> {
+        let device_code = sqlx::query_as!(
+            OAuthDeviceCode,
+            "SELECT device_code_hash, user_code, client_id, scope, status, user_id, amr, auth_time, interval_seconds, last_polled_at, expires_at FROM oauth_device_codes WHERE device_code_hash = $1",
+            device_code_hash
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(device_code)
+    }
+
+    async fn get_pending_oauth_device_code(
+        &self,
+        user_code: &str,
+    ) -> Result<Option<OAuthDeviceCode>> {
+        let device_code = sqlx::query_as!(
+            OAuthDeviceCode,
+            "SELECT device_code_hash, user_code, client_id, scope, status, user_id, amr, auth_time, interval_seconds, last_polled_at, expires_at FROM oauth_device_codes WHERE user_code = $1 AND status = 'pending' AND expires_at > NOW()",
+            user_code
+        )
+        .fetch_optional(self.pool.as_ref())
+        .await?;
+        Ok(device_code)
+    }
+
+    async fn approve_oauth_device_code(
+        &self,
+        user_code: &str,
+        user_id: &Uuid,
+        amr: &[String],
+        auth_time: &DateTime<Utc>,
+    ) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE oauth_device_codes SET status = 'approved', user_id = $2, amr = $3, auth_time = $4 WHERE user_code = $1 AND status = 'pending' AND expires_at > NOW()",
+            user_code,
+            user_id,
+            amr,
+            auth_time
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn deny_oauth_device_code(&self, user_code: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE oauth_device_codes SET status = 'denied' WHERE user_code = $1 AND status = 'pending' AND expires_at > NOW()",
+            user_code
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn record_oauth_device_poll(
+        &self,
+        device_code_hash: &str,
+        interval_seconds: i32,
+    ) -> Result<()> {
+        sqlx::query!(
+            "UPDATE oauth_device_codes SET last_polled_at = NOW(), interval_seconds = $2 WHERE device_code_hash = $1",
+            device_code_hash,
+            interval_seconds
+        )
+        .execute(self.pool.as_ref())
+        .await?;
         Ok(())
     }
+
+    async fn take_approved_oauth_device_code(&self, device_code_hash: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM oauth_device_codes WHERE device_code_hash = $1 AND status = 'approved'",
+            device_code_hash
+        )
+        .execute(self.pool.as_ref())
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
 }
 
 // --- 3. （未来）"DynamoDB 实现" ---
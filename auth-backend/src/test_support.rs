@@ -0,0 +1,48 @@
+// 测试共用的工具
+// 数据库相关的测试使用 #[sqlx::test]，每个测试运行在独立的临时数据库中，并执行全部迁移
+use std::sync::Arc;
+
+use sqlx::PgPool;
+
+use crate::{
+    keys,
+    models::{OAuthClient, RegisterRequest, User},
+    repositories::{PostgresRepository, UserRepository},
+};
+
+pub fn repository(pool: PgPool) -> Arc<dyn UserRepository> {
+    keys::init_for_tests();
+    Arc::new(PostgresRepository::new(Arc::new(pool)))
+}
+
+pub async fn create_user(repo: &dyn UserRepository, name: &str) -> User {
+    let request = RegisterRequest {
+        username: name.to_string(),
+        email: format!("{name}@example.com"),
+        password: "unused".to_string(),
+    };
+    repo.create_user(&request, "unused")
+        .await
+        .expect("Failed to create test user")
+}
+
+// 只在内存中使用的客户端，用来直接签发 client_credentials 令牌
+pub fn service_client(client_id: &str) -> OAuthClient {
+    OAuthClient {
+        client_id: client_id.to_string(),
+        name: client_id.to_string(),
+        redirect_uris: Vec::new(),
+        allowed_scopes: Vec::new(),
+        token_endpoint_auth_method: "client_secret_post".to_string(),
+        client_secret_hash: None,
+        jwks: None,
+        grant_types: vec!["client_credentials".to_string()],
+        allowed_audiences: Vec::new(),
+        token_format: None,
+        access_token_ttl_seconds: None,
+        refresh_token_ttl_seconds: None,
+        required_acr: None,
+        allowed_origins: Vec::new(),
+        created_at: None,
+    }
+}
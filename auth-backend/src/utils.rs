@@ -1,72 +1,483 @@
-use chrono::{Duration, Utc};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Header, TokenData, Validation, decode, decode_header, encode};
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::env;
+use totp_rs::{Algorithm, Secret, TOTP};
 use uuid::Uuid;
 
-use crate::models::Claims;
+use crate::{
+    keys::{signing_key, verification_key},
+    models::{Claims, IdTokenClaims, OAuthClient, Session, SessionGrant, User},
+    repositories::UserRepository,
+};
 
-fn get_jwt_secret() -> String {
-    env::var("JWT_SECRET").expect("JWT_SECRET must be set")
+// 单个 mfa token 允许的 OTP / 恢复码失败次数，超过后需要重新登录
+pub const MAX_MFA_TOKEN_FAILURES: i32 = 5;
+// 用户连续失败达到该次数后开始锁定 OTP 验证
+pub const MAX_OTP_FAILURES: i32 = 5;
+
+// 使用当前签名密钥签发令牌，header 中带上 kid
+fn encode_token<T: Serialize>(claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+    let key = signing_key();
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+    encode(&header, claims, &key.encoding)
+}
+
+// 按 header 中的 kid 选择验证密钥，算法固定为该密钥的算法，不信任 header 中的 alg
+// audience 为 None 时不校验 aud，由调用方根据 aud 判断令牌类型
+fn decode_token(
+    token: &str,
+    audience: Option<&str>,
+) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
+    let header = decode_header(token)?;
+    let key = verification_key(header.kid.as_deref()).ok_or_else(|| {
+        jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken)
+    })?;
+    let mut validation = Validation::new(key.algorithm);
+    match audience {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+    validation.set_issuer(&[token_issuer()]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+    validation.validate_nbf = true;
+    decode::<Claims>(token, &key.decoding, &validation)
+}
+
+// 引用令牌不含 "."，按哈希取出签发时保存的声明；其余按 JWT 验证签名
+// 取出声明后的检查（aud、exp 以及调用方的吊销检查）与 JWT 相同
+async fn resolve_token(
+    token: &str,
+    audience: Option<&str>,
+    repo: &dyn UserRepository,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    if token.contains('.') {
+        return decode_token(token, audience).map(|decoded| decoded.claims);
+    }
+
+    let invalid =
+        || jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken);
+    let value = repo
+        .get_reference_token(&hash_token(token))
+        .await
+        .map_err(|_| invalid())?
+        .ok_or_else(invalid)?;
+    let claims: Claims = serde_json::from_value(value).map_err(|_| invalid())?;
+    if audience.is_some_and(|audience| claims.aud.as_deref() != Some(audience)) {
+        return Err(invalid());
+    }
+    if claims.exp <= Utc::now().timestamp() as usize {
+        return Err(jsonwebtoken::errors::Error::from(
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature,
+        ));
+    }
+    Ok(claims)
+}
+
+// access token 和 refresh token 的格式：
+// - Jwt：自包含的签名令牌，资源服务器可以离线验证
+// - Reference：随机字符串，声明只保存在服务端，需要通过内省或本服务解析
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenFormat {
+    Jwt,
+    Reference,
+}
+
+impl TokenFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "jwt" => Some(TokenFormat::Jwt),
+            "reference" => Some(TokenFormat::Reference),
+            _ => None,
+        }
+    }
+
+    // 全局配置 TOKEN_FORMAT，默认 jwt
+    pub fn global() -> Self {
+        env::var("TOKEN_FORMAT")
+            .ok()
+            .and_then(|value| Self::parse(&value))
+            .unwrap_or(TokenFormat::Jwt)
+    }
+
+    // 客户端单独配置时优先于全局配置
+    pub fn for_client(client: &OAuthClient) -> Self {
+        client
+            .token_format
+            .as_deref()
+            .and_then(Self::parse)
+            .unwrap_or_else(Self::global)
+    }
+}
+
+// 签发 access token / refresh token 时使用的格式和有效期
+// 通过 OAuth 客户端签发时按客户端配置，其余使用全局配置
+#[derive(Debug, Clone, Copy)]
+pub struct TokenSettings {
+    pub format: TokenFormat,
+    pub access_ttl: Duration,
+    pub refresh_ttl: Duration,
+}
+
+impl TokenSettings {
+    pub fn global() -> Self {
+        Self {
+            format: TokenFormat::global(),
+            access_ttl: token_ttl(TokenType::Access),
+            refresh_ttl: token_ttl(TokenType::Refresh),
+        }
+    }
+
+    pub fn for_client(client: &OAuthClient) -> Self {
+        Self {
+            format: TokenFormat::for_client(client),
+            access_ttl: client_ttl(client.access_token_ttl_seconds, TokenType::Access),
+            refresh_ttl: client_ttl(client.refresh_token_ttl_seconds, TokenType::Refresh),
+        }
+    }
+}
+
+// 客户端配置的有效期（秒），为空时使用全局配置
+fn client_ttl(seconds: Option<i32>, token_type: TokenType) -> Duration {
+    seconds
+        .map(|seconds| Duration::seconds(seconds.into()))
+        .unwrap_or_else(|| token_ttl(token_type))
+}
+
+// client_credentials 令牌的有效期：客户端的 access_token_ttl_seconds 或 CLIENT_TOKEN_TTL_SECONDS
+pub fn client_token_ttl(client: &OAuthClient) -> Duration {
+    client_ttl(client.access_token_ttl_seconds, TokenType::Client)
+}
+
+// 按格式签发令牌；引用令牌把声明保存到数据库，只返回随机字符串
+async fn issue_token(
+    claims: &Claims,
+    format: TokenFormat,
+    repo: &dyn UserRepository,
+) -> anyhow::Result<String> {
+    match format {
+        TokenFormat::Jwt => Ok(encode_token(claims)?),
+        TokenFormat::Reference => {
+            let token = generate_random_token();
+            let expires_at = DateTime::from_timestamp(claims.exp as i64, 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid exp"))?;
+            repo.create_reference_token(
+                &hash_token(&token),
+                &serde_json::to_value(claims)?,
+                &expires_at,
+            )
+            .await?;
+            Ok(token)
+        }
+    }
+}
+
+// 令牌的签发者，下游服务据此确认令牌来自本服务
+pub fn token_issuer() -> String {
+    env::var("JWT_ISSUER").unwrap_or_else(|_| "http://localhost:8080".to_string())
+}
+
+pub enum TokenType {
+    Mfa,
+    Enrollment,
+    Access,
+    Refresh,
+    Id,
+    Client,
+    PasswordReset,
+}
+
+// 各类令牌的有效期，可以通过环境变量（单位：秒）覆盖
+pub fn token_ttl(token_type: TokenType) -> Duration {
+    let (name, default) = match token_type {
+        TokenType::Mfa => ("MFA_TOKEN_TTL_SECONDS", 5 * 60),
+        TokenType::Enrollment => ("ENROLLMENT_TOKEN_TTL_SECONDS", 15 * 60),
+        TokenType::Access => ("ACCESS_TOKEN_TTL_SECONDS", 24 * 60 * 60),
+        TokenType::Refresh => ("REFRESH_TOKEN_TTL_SECONDS", 7 * 24 * 60 * 60),
+        TokenType::Id => ("ID_TOKEN_TTL_SECONDS", 60 * 60),
+        TokenType::Client => ("CLIENT_TOKEN_TTL_SECONDS", 60 * 60),
+        TokenType::PasswordReset => ("PASSWORD_RESET_TTL_SECONDS", 30 * 60),
+    };
+    let seconds = env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default);
+    Duration::seconds(seconds)
+}
+
+// 由 amr 推导认证强度：
+// - "phrh"：使用不可导出的硬件密钥（hwk）完成认证，抗钓鱼
+// - "phr"：使用可同步的 passkey（swk）完成认证，抗钓鱼
+// - "mfa"：完成了其他第二因子（OTP / 恢复码）
+// - "pwd"：只验证了密码
+pub fn acr_for_amr(amr: &[String]) -> String {
+    let has = |method: &str| amr.iter().any(|value| value == method);
+    if has("hwk") {
+        "phrh"
+    } else if has("swk") {
+        "phr"
+    } else if has("mfa") {
+        "mfa"
+    } else {
+        "pwd"
+    }
+    .to_string()
+}
+
+// acr 从低到高的级别
+pub const ACR_LEVELS: [&str; 4] = ["pwd", "mfa", "phr", "phrh"];
+
+// acr 是否达到 required 要求的级别
+pub fn acr_satisfies(acr: &str, required: &str) -> bool {
+    let rank = |value: &str| ACR_LEVELS.iter().position(|level| *level == value);
+    matches!((rank(acr), rank(required)), (Some(acr), Some(required)) if acr >= required)
+}
+
+// 所有令牌共有的声明：iss / aud / iat / nbf / exp 以及唯一的 jti
+// sub 通常是用户 id，client_credentials 令牌中是客户端的 client_id
+fn base_claims(subject: &str, audience: &str, ttl: Duration) -> Claims {
+    let now = Utc::now();
+    Claims {
+        sub: subject.to_string(),
+        exp: (now + ttl).timestamp() as usize,
+        iss: Some(token_issuer()),
+        aud: Some(audience.to_string()),
+        iat: Some(now.timestamp() as usize),
+        nbf: Some(now.timestamp() as usize),
+        jti: Some(Uuid::new_v4().to_string()),
+        amr: None,
+        auth_time: None,
+        acr: None,
+        sid: None,
+        ver: None,
+        client_id: None,
+        scope: None,
+    }
+}
+
+// OAuth scope 是空格分隔的列表
+pub fn has_scope(scope: Option<&str>, value: &str) -> bool {
+    scope.is_some_and(|scope| scope.split_whitespace().any(|s| s == value))
+}
+
+// 是否强制所有用户启用 MFA，默认开启
+// 关闭后，未设置 OTP 的用户可以只凭密码登录（amr 为 ["pwd"]）
+pub fn mfa_required() -> bool {
+    env::var("MFA_REQUIRED")
+        .map(|value| !matches!(value.as_str(), "false" | "0"))
+        .unwrap_or(true)
+}
+
+// 高风险操作（如修改密码）要求 MFA 在这个时间窗口内完成，默认 5 分钟
+pub fn step_up_max_age() -> Duration {
+    let seconds = env::var("STEP_UP_MAX_AGE_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(5 * 60);
+    Duration::seconds(seconds)
 }
 
+// 服务端只保存 token 的 SHA-256 摘要，数据库泄露也无法直接拿来使用
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// 恢复码格式：xxxxx-xxxxx，去掉了容易混淆的字符
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    (0..count)
+        .map(|_| {
+            let mut code: String = (0..10)
+                .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+                .collect();
+            code.insert(5, '-');
+            code
+        })
+        .collect()
+}
+
+// 不透明的随机令牌（如 OAuth 授权码），数据库中只保存 hash_token 的结果
+pub fn generate_random_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().r#gen();
+    hex::encode(bytes)
+}
+
+// 用户输入的恢复码可能带空格或大写，统一后再计算哈希
+pub fn normalize_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if code.len() == 10 {
+        format!("{}-{}", &code[..5], &code[5..])
+    } else {
+        code
+    }
+}
+
+pub fn build_totp(otp_base32: &str) -> TOTP {
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        Secret::Encoded(otp_base32.to_string()).to_bytes().unwrap(),
+    )
+    .unwrap()
+}
+
+// 返回验证码所属的时间步（允许前后 skew 个时间步的时钟偏差），不匹配时返回 None
+// 调用方需要记录该时间步，拒绝重复使用同一个验证码
+pub fn match_totp_step(totp: &TOTP, token: &str) -> Option<i64> {
+    let current = Utc::now().timestamp() as u64 / totp.step;
+    let skew = totp.skew as u64;
+    (current.saturating_sub(skew)..=current + skew)
+        .find(|step| {
+            let expected = totp.generate(step * totp.step);
+            constant_time_eq(&expected, token)
+        })
+        .map(|step| step as i64)
+}
+
+// 逐字节比较全部内容，避免时序差异
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+// 连续失败达到阈值后按指数退避锁定：30 秒起，每多失败一次翻倍，最长 15 分钟
+pub fn otp_lockout(failures: i32) -> Option<Duration> {
+    if failures < MAX_OTP_FAILURES {
+        return None;
+    }
+    let exponent = (failures - MAX_OTP_FAILURES).min(5) as u32;
+    Some(Duration::seconds(30 * 2i64.pow(exponent)).min(Duration::minutes(15)))
+}
+
+// jti 还用于统计单个 mfa token 的失败次数
 pub fn generate_mfa_token(user_id: &Uuid) -> Result<String, jsonwebtoken::errors::Error> {
-    let exp = (Utc::now() + Duration::minutes(5)).timestamp() as usize;
     // "amr": ["pwd"]
     let claims = Claims {
-        sub: user_id.to_string(),
-        exp,
-        aud: Some("mfa-verification".to_string()),
         amr: Some(vec!["pwd".to_string()]),
+        ..base_claims(
+            &user_id.to_string(),
+            "mfa-verification",
+            token_ttl(TokenType::Mfa),
+        )
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(get_jwt_secret().as_bytes()),
-    )
+    encode_token(&claims)
 }
 
-pub fn generate_access_token(user_id: &Uuid) -> Result<String, jsonwebtoken::errors::Error> {
-    let exp = (Utc::now() + Duration::hours(24)).timestamp() as usize;
-    // "amr": ["pwd", "mfa"]
+// 注册 OTP 专用的令牌：密码已验证，但用户还没有可用的第二因子
+// 只能访问 /auth/otp/generate 和 /auth/otp/verify
+pub fn generate_enrollment_token(user_id: &Uuid) -> Result<String, jsonwebtoken::errors::Error> {
     let claims = Claims {
-        sub: user_id.to_string(),
-        exp,
-        aud: Some("urn:auth-center:api".to_string()),
-        amr: Some(vec!["pwd".to_string(), "mfa".to_string()]),
+        amr: Some(vec!["pwd".to_string()]),
+        ..base_claims(
+            &user_id.to_string(),
+            "mfa-enrollment",
+            token_ttl(TokenType::Enrollment),
+        )
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(get_jwt_secret().as_bytes()),
-    )
+    encode_token(&claims)
 }
 
-pub fn generate_refresh_token(user_id: &Uuid) -> Result<String, jsonwebtoken::errors::Error> {
-    let exp = (Utc::now() + Duration::days(7)).timestamp() as usize;
+// amr 由调用方决定：通常是 ["pwd", "mfa"]，MFA 非强制时可能只有 ["pwd"]
+pub async fn generate_access_token(
+    user_id: &Uuid,
+    token_version: i32,
+    grant: &SessionGrant,
+    settings: &TokenSettings,
+    repo: &dyn UserRepository,
+) -> anyhow::Result<String> {
     let claims = Claims {
-        sub: user_id.to_string(),
-        exp,
-        aud: Some("refresh-token".to_string()),
-        amr: None,
+        amr: Some(grant.amr.clone()),
+        auth_time: Some(grant.auth_time.timestamp() as usize),
+        acr: Some(acr_for_amr(&grant.amr)),
+        sid: Some(grant.family_id.to_string()),
+        ver: Some(token_version),
+        client_id: grant.client_id.clone(),
+        scope: grant.scope.clone(),
+        ..base_claims(
+            &user_id.to_string(),
+            "urn:auth-center:api",
+            settings.access_ttl,
+        )
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(get_jwt_secret().as_bytes()),
-    )
+    issue_token(&claims, settings.format, repo).await
+}
+
+// OpenID Connect id_token，供客户端确认登录的用户，不能用来访问接口
+pub fn generate_id_token(
+    user: &User,
+    client_id: &str,
+    grant: &SessionGrant,
+    nonce: Option<&str>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now();
+    let scope = grant.scope.as_deref();
+    let claims = IdTokenClaims {
+        iss: token_issuer(),
+        sub: user.id.to_string(),
+        aud: client_id.to_string(),
+        exp: (now + token_ttl(TokenType::Id)).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        auth_time: grant.auth_time.timestamp() as usize,
+        nonce: nonce.map(str::to_string),
+        amr: grant.amr.clone(),
+        acr: acr_for_amr(&grant.amr),
+        sid: grant.family_id.to_string(),
+        name: has_scope(scope, "profile").then(|| user.username.clone()),
+        email: has_scope(scope, "email").then(|| user.email.clone()),
+    };
+
+    encode_token(&claims)
+}
+
+// client_credentials 令牌：没有用户，sub 和 client_id 都是客户端
+pub async fn generate_client_access_token(
+    client: &OAuthClient,
+    audience: &str,
+    scope: &str,
+    repo: &dyn UserRepository,
+) -> anyhow::Result<String> {
+    let claims = Claims {
+        client_id: Some(client.client_id.clone()),
+        scope: Some(scope.to_string()),
+        ..base_claims(&client.client_id, audience, client_token_ttl(client))
+    };
+
+    issue_token(&claims, TokenFormat::for_client(client), repo).await
+}
+
+pub async fn generate_refresh_token(
+    user_id: &Uuid,
+    jti: &Uuid,
+    settings: &TokenSettings,
+    repo: &dyn UserRepository,
+) -> anyhow::Result<String> {
+    // jti 对应 sessions 表中的一行，用于轮换和吊销
+    let claims = Claims {
+        jti: Some(jti.to_string()),
+        ..base_claims(&user_id.to_string(), "refresh-token", settings.refresh_ttl)
+    };
+
+    issue_token(&claims, settings.format, repo).await
 }
 
 pub fn validate_mfa_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let mut validation = Validation::new(jsonwebtoken::Algorithm::HS256);
-    validation.set_audience(&["mfa-verification"]);
-    let decoded = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(get_jwt_secret().as_bytes()),
-        &validation,
-    )?;
+    let decoded = decode_token(token, Some("mfa-verification"))?;
     if let Some(amr) = &decoded.claims.amr {
         // 它必须由 "pwd" 生成，且尚未通过 "mfa"
         if amr.contains(&"pwd".to_string()) && !amr.contains(&"mfa".to_string()) {
@@ -85,26 +496,303 @@ pub fn validate_mfa_token(token: &str) -> Result<Claims, jsonwebtoken::errors::E
     }
 }
 
-pub fn validate_access_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let mut validation = Validation::new(jsonwebtoken::Algorithm::HS256);
-    validation.set_audience(&["urn:auth-center:api"]);
-    let decoded = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(get_jwt_secret().as_bytes()),
-        &validation,
-    )?;
+pub fn validate_enrollment_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let decoded = decode_token(token, Some("mfa-enrollment"))?;
+    Ok(decoded.claims)
+}
 
-    if let Some(amr) = &decoded.claims.amr {
-        if !amr.contains(&"mfa".to_string()) {
-            return Err(jsonwebtoken::errors::Error::from(
-                jsonwebtoken::errors::ErrorKind::InvalidToken,
-            ));
-        }
-    } else {
+// refresh token 必须对应 sessions 表中的一行，且 token_hash 一致
+// 是否已轮换、已吊销由调用方判断（轮换时需要据此检测重复使用）
+pub async fn validate_refresh_token(
+    token: &str,
+    repo: &dyn UserRepository,
+) -> Result<(Claims, Session), jsonwebtoken::errors::Error> {
+    let claims = resolve_token(token, Some("refresh-token"), repo).await?;
+    refresh_token_session(token, claims, repo).await
+}
+
+async fn refresh_token_session(
+    token: &str,
+    claims: Claims,
+    repo: &dyn UserRepository,
+) -> Result<(Claims, Session), jsonwebtoken::errors::Error> {
+    let invalid =
+        || jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken);
+    let jti = claims
+        .jti
+        .as_deref()
+        .and_then(|jti| Uuid::parse_str(jti).ok())
+        .ok_or_else(invalid)?;
+    let session = repo.get_session_by_jti(&jti).await.map_err(|_| invalid())?;
+    if session.token_hash != hash_token(token) {
+        return Err(invalid());
+    }
+    Ok((claims, session))
+}
+
+// 除了签名、有效期和 amr 之外，还要确认令牌没有被登出操作作废：
+// - ver 必须等于用户当前的 token_version（登出所有设备会使其加一）
+// - sid 对应的 session family 必须仍然有效（单个会话登出会吊销它）
+// - jti 没有通过 /oauth/revoke 吊销
+pub async fn validate_access_token(
+    token: &str,
+    repo: &dyn UserRepository,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let claims = resolve_token(token, Some("urn:auth-center:api"), repo).await?;
+    check_access_claims(claims, repo).await
+}
+
+async fn check_access_claims(
+    claims: Claims,
+    repo: &dyn UserRepository,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let invalid =
+        || jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken);
+
+    // client_credentials 签发的令牌没有对应的用户，也就没有 ver
+    if claims.ver.is_none() {
+        return Err(invalid());
+    }
+
+    // amr 中缺少 "mfa" 时返回 MissingRequiredClaim，调用方据此返回 403 而不是 401
+    // MFA 非强制时，只通过密码认证的令牌也可以访问普通接口
+    let Some(amr) = &claims.amr else {
         return Err(jsonwebtoken::errors::Error::from(
-            jsonwebtoken::errors::ErrorKind::InvalidToken,
+            jsonwebtoken::errors::ErrorKind::MissingRequiredClaim("amr".to_string()),
+        ));
+    };
+    if mfa_required() && !amr.contains(&"mfa".to_string()) {
+        return Err(jsonwebtoken::errors::Error::from(
+            jsonwebtoken::errors::ErrorKind::MissingRequiredClaim("amr".to_string()),
         ));
     }
 
-    Ok(decoded.claims)
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| invalid())?;
+    let user = repo.get_user_by_id(&user_id).await.map_err(|_| invalid())?;
+    if claims.ver != Some(user.token_version) {
+        return Err(invalid());
+    }
+
+    if let Some(sid) = &claims.sid {
+        let family_id = Uuid::parse_str(sid).map_err(|_| invalid())?;
+        let active = repo
+            .is_session_family_active(&family_id)
+            .await
+            .map_err(|_| invalid())?;
+        if !active {
+            return Err(invalid());
+        }
+    }
+
+    check_not_revoked(&claims, repo).await?;
+    Ok(claims)
+}
+
+// 通过 /oauth/revoke 吊销的 access token 按 jti 记录
+async fn check_not_revoked(
+    claims: &Claims,
+    repo: &dyn UserRepository,
+) -> Result<(), jsonwebtoken::errors::Error> {
+    let invalid =
+        || jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken);
+    let jti = claims.jti.as_deref().ok_or_else(invalid)?;
+    match repo.is_token_revoked(jti).await {
+        Ok(false) => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+// 令牌内省和吊销接受的令牌类型
+pub enum ValidatedToken {
+    Access(Claims),
+    // 只包含尚未轮换、尚未吊销的 refresh token
+    Refresh(Claims, Box<Session>),
+    // client_credentials 令牌，sub 为客户端
+    Client(Claims),
+}
+
+// 不限定 aud 验证本服务签发的令牌，按 aud 区分类型后做与各接口相同的检查
+// mfa token 和 enrollment token 只在登录过程中使用，不接受
+pub async fn validate_token(
+    token: &str,
+    repo: &dyn UserRepository,
+) -> Result<ValidatedToken, jsonwebtoken::errors::Error> {
+    let invalid =
+        || jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken);
+    let claims = resolve_token(token, None, repo).await?;
+
+    // client_credentials 令牌的 aud 由客户端配置决定，先按 sub 和 client_id 识别
+    let is_client_token =
+        claims.amr.is_none() && claims.client_id.as_deref() == Some(claims.sub.as_str());
+    match claims.aud.as_deref() {
+        Some("mfa-verification") | Some("mfa-enrollment") => Err(invalid()),
+        _ if is_client_token => {
+            check_not_revoked(&claims, repo).await?;
+            // 客户端被删除后，它的令牌随之失效
+            match repo.get_oauth_client(&claims.sub).await {
+                Ok(Some(_)) => {}
+                _ => return Err(invalid()),
+            }
+            Ok(ValidatedToken::Client(claims))
+        }
+        Some("urn:auth-center:api") => Ok(ValidatedToken::Access(
+            check_access_claims(claims, repo).await?,
+        )),
+        Some("refresh-token") => {
+            let (claims, session) = refresh_token_session(token, claims, repo).await?;
+            if session.rotated_at.is_some()
+                || session.revoked_at.is_some()
+                || session.expires_at <= Utc::now()
+            {
+                return Err(invalid());
+            }
+            Ok(ValidatedToken::Refresh(claims, Box::new(session)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        keys,
+        test_support::{create_user, repository},
+    };
+
+    #[sqlx::test]
+    async fn access_token_without_an_active_session_is_rejected(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let grant = SessionGrant::new(vec!["pwd".to_string(), "mfa".to_string()]);
+        let access_token = generate_access_token(
+            &user.id,
+            user.token_version,
+            &grant,
+            &TokenSettings::global(),
+            repo.as_ref(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            validate_access_token(&access_token, repo.as_ref())
+                .await
+                .is_err()
+        );
+    }
+
+    #[sqlx::test]
+    async fn mfa_and_refresh_tokens_are_not_access_tokens(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "bob").await;
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        let refresh_token = generate_refresh_token(
+            &user.id,
+            &Uuid::new_v4(),
+            &TokenSettings::global(),
+            repo.as_ref(),
+        )
+        .await
+        .unwrap();
+
+        assert!(
+            validate_access_token(&mfa_token, repo.as_ref())
+                .await
+                .is_err()
+        );
+        assert!(
+            validate_access_token(&refresh_token, repo.as_ref())
+                .await
+                .is_err()
+        );
+    }
+
+    #[sqlx::test]
+    async fn password_only_token_is_rejected(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "carol").await;
+        let grant = SessionGrant::new(vec!["pwd".to_string()]);
+        let access_token = generate_access_token(
+            &user.id,
+            user.token_version,
+            &grant,
+            &TokenSettings::global(),
+            repo.as_ref(),
+        )
+        .await
+        .unwrap();
+
+        let error = validate_access_token(&access_token, repo.as_ref())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            jsonwebtoken::errors::ErrorKind::MissingRequiredClaim(claim) if claim == "amr"
+        ));
+    }
+
+    fn access_claims() -> Claims {
+        base_claims(
+            &Uuid::new_v4().to_string(),
+            "urn:auth-center:api",
+            Duration::minutes(5),
+        )
+    }
+
+    #[test]
+    fn token_from_another_issuer_is_rejected() {
+        keys::init_for_tests();
+        let token = encode_token(&access_claims()).unwrap();
+        assert!(decode_token(&token, Some("urn:auth-center:api")).is_ok());
+
+        let claims = Claims {
+            iss: Some("https://attacker.example".to_string()),
+            ..access_claims()
+        };
+        let token = encode_token(&claims).unwrap();
+        let error = decode_token(&token, Some("urn:auth-center:api")).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            jsonwebtoken::errors::ErrorKind::InvalidIssuer
+        ));
+    }
+
+    #[test]
+    fn token_used_before_nbf_is_rejected() {
+        keys::init_for_tests();
+        let claims = Claims {
+            nbf: Some((Utc::now() + Duration::minutes(10)).timestamp() as usize),
+            ..access_claims()
+        };
+        let token = encode_token(&claims).unwrap();
+        let error = decode_token(&token, Some("urn:auth-center:api")).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            jsonwebtoken::errors::ErrorKind::ImmatureSignature
+        ));
+    }
+
+    #[test]
+    fn token_without_kid_is_rejected() {
+        keys::init_for_tests();
+        let key = signing_key();
+        let token = encode(&Header::new(key.algorithm), &access_claims(), &key.encoding).unwrap();
+        assert!(decode_token(&token, Some("urn:auth-center:api")).is_err());
+    }
+
+    #[test]
+    fn every_token_gets_a_unique_jti() {
+        assert_ne!(access_claims().jti, access_claims().jti);
+    }
+
+    #[test]
+    fn acr_reflects_the_strongest_method() {
+        let amr = |methods: &[&str]| methods.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+        assert_eq!(acr_for_amr(&amr(&["pwd"])), "pwd");
+        assert_eq!(acr_for_amr(&amr(&["pwd", "mfa"])), "mfa");
+        assert_eq!(acr_for_amr(&amr(&["pwd", "swk", "mfa"])), "phr");
+        assert_eq!(acr_for_amr(&amr(&["pwd", "hwk", "mfa"])), "phrh");
+    }
 }
@@ -0,0 +1,827 @@
+use std::{env, fs};
+
+use actix_web::{HttpRequest, HttpResponse, Responder, ResponseError, delete, get, post, web};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    AttestationCaList, AttestedPasskeyRegistration, AuthenticatorAttachment,
+    DiscoverableAuthentication, DiscoverableKey, Passkey, PasskeyAuthentication,
+    PasskeyRegistration, Url, Webauthn, WebauthnBuilder,
+};
+
+use crate::{
+    extractors::{AuthenticatedUser, MfaPendingUser, MfaSetupUser},
+    handlers::{device_info, issue_session_tokens},
+    models::{
+        ApiResponse, OtpSueecessData, SessionGrant, User, UserData,
+        WebauthnAuthenticateFinishSchema, WebauthnChallengeData, WebauthnCredential,
+        WebauthnRegisterFinishSchema, WebauthnRegisterStartSchema, WebauthnRegisteredData,
+    },
+    repositories::UserRepository,
+};
+
+// challenge 的有效期
+const CEREMONY_MINUTES: i64 = 5;
+
+const CEREMONY_REGISTRATION: &str = "registration";
+const CEREMONY_AUTHENTICATION: &str = "authentication";
+const CEREMONY_DISCOVERABLE: &str = "discoverable";
+
+// RP 配置：rp_id 必须是前端页面所在域名（或其父域名）
+pub fn build_webauthn() -> Webauthn {
+    let rp_id = env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string());
+    let rp_origin =
+        env::var("WEBAUTHN_RP_ORIGIN").unwrap_or_else(|_| "http://localhost:5173".to_string());
+    let rp_origin = Url::parse(&rp_origin).expect("WEBAUTHN_RP_ORIGIN must be a valid URL");
+
+    WebauthnBuilder::new(&rp_id, &rp_origin)
+        .expect("Invalid WebAuthn relying party configuration")
+        .rp_name("AuthApp")
+        .build()
+        .expect("Invalid WebAuthn relying party configuration")
+}
+
+// 安全密钥厂商的 attestation 根证书，未配置时不支持注册安全密钥
+// 配置错误时直接 panic，启动即失败
+pub fn build_attestation_ca_list() -> AttestationCaList {
+    let mut ca_list = AttestationCaList::default();
+    let Ok(path) = env::var("WEBAUTHN_ATTESTATION_CA_FILE") else {
+        return ca_list;
+    };
+
+    let pem = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {path}: {e}"));
+    // 文件中可以有多个证书，逐个解析后合并
+    for block in pem
+        .split_inclusive("-----END CERTIFICATE-----")
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+    {
+        let ca = AttestationCaList::try_from(block.as_bytes())
+            .unwrap_or_else(|e| panic!("Invalid attestation CA in {path}: {e}"));
+        ca_list.union(&ca);
+    }
+    assert!(!ca_list.is_empty(), "No certificates found in {path}");
+    ca_list
+}
+
+// 注册仪式的状态：
+// - 普通 passkey 不校验 attestation
+// - 安全密钥的 attestation 必须由受信任的厂商 CA 签发
+#[derive(Serialize, Deserialize)]
+enum RegistrationState {
+    Passkey(PasskeyRegistration),
+    SecurityKey(AttestedPasskeyRegistration),
+}
+
+// 凭证 ID 以十六进制保存，便于在数据库中唯一索引
+fn credential_key(cred_id: &[u8]) -> String {
+    hex::encode(cred_id)
+}
+
+// 按 RFC 8176 区分密钥类型：
+// - 注册时 attestation 经受信任的厂商 CA 验证过的安全密钥，私钥确认封存在硬件中，记为 "hwk"
+// - 其余凭证记为 "swk"：BE（可备份）等标志由验证器自报，没有 attestation 时无法证明私钥不可导出
+fn key_amr(credential: &WebauthnCredential) -> String {
+    if credential.attested {
+        "hwk".to_string()
+    } else {
+        "swk".to_string()
+    }
+}
+
+fn parse_passkeys(credentials: &[WebauthnCredential]) -> Vec<(WebauthnCredential, Passkey)> {
+    credentials
+        .iter()
+        .filter_map(|credential| {
+            serde_json::from_value::<Passkey>(credential.passkey.clone())
+                .ok()
+                .map(|passkey| (credential.clone(), passkey))
+        })
+        .collect()
+}
+
+fn ceremony_error(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(ApiResponse::<()> {
+        status: "error".to_string(),
+        message: message.to_string(),
+        data: None,
+    })
+}
+
+fn internal_error(message: &str) -> HttpResponse {
+    HttpResponse::InternalServerError().json(ApiResponse::<()> {
+        status: "error".to_string(),
+        message: message.to_string(),
+        data: None,
+    })
+}
+
+async fn save_ceremony<T: serde::Serialize>(
+    repo: &dyn UserRepository,
+    user_id: Option<&Uuid>,
+    kind: &str,
+    state: &T,
+) -> Result<Uuid, HttpResponse> {
+    let state =
+        serde_json::to_value(state).map_err(|_| internal_error("Failed to save WebAuthn state"))?;
+    let expires_at = Utc::now() + Duration::minutes(CEREMONY_MINUTES);
+    repo.create_webauthn_ceremony(user_id, kind, &state, &expires_at)
+        .await
+        .map_err(|_| internal_error("Failed to save WebAuthn state"))
+}
+
+// 取出仪式状态；user_id 不为空时还要确认是同一个用户发起的
+async fn load_ceremony<T: serde::de::DeserializeOwned>(
+    repo: &dyn UserRepository,
+    id: &Uuid,
+    kind: &str,
+    user_id: Option<&Uuid>,
+) -> Result<T, HttpResponse> {
+    let ceremony = match repo.take_webauthn_ceremony(id, kind).await {
+        Ok(Some(ceremony)) => ceremony,
+        Ok(None) => return Err(ceremony_error("WebAuthn challenge expired or not found")),
+        Err(_) => return Err(internal_error("Failed to load WebAuthn state")),
+    };
+
+    if user_id.is_some() && ceremony.user_id.as_ref() != user_id {
+        return Err(ceremony_error("WebAuthn challenge expired or not found"));
+    }
+
+    serde_json::from_value(ceremony.state).map_err(|_| internal_error("Invalid WebAuthn state"))
+}
+
+// 保存认证后更新过的凭证（签名计数器、备份状态）
+async fn save_passkey(
+    repo: &dyn UserRepository,
+    credential: &WebauthnCredential,
+    passkey: &Passkey,
+) -> Result<(), HttpResponse> {
+    let passkey_json = serde_json::to_value(passkey)
+        .map_err(|_| internal_error("Failed to update WebAuthn credential"))?;
+    repo.update_webauthn_credential(&credential.id, &passkey_json)
+        .await
+        .map_err(|_| internal_error("Failed to update WebAuthn credential"))
+}
+
+async fn issue_tokens_response(
+    req: &HttpRequest,
+    repo: &dyn UserRepository,
+    user: User,
+    amr: Vec<String>,
+    message: &str,
+) -> HttpResponse {
+    let (access_token, refresh_token) =
+        match issue_session_tokens(repo, &user, SessionGrant::new(amr), device_info(req)).await {
+            Ok(tokens) => tokens,
+            Err(resp) => return resp,
+        };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: message.to_string(),
+        data: Some(OtpSueecessData {
+            access_token,
+            refresh_token,
+            user: UserData {
+                id: user.id.to_string(),
+                email: user.email,
+                name: user.username,
+            },
+        }),
+    })
+}
+
+#[post("/auth/webauthn/register/start")]
+async fn register_start(
+    auth: MfaSetupUser,
+    data: Option<web::Json<WebauthnRegisterStartSchema>>,
+    repo: web::Data<dyn UserRepository>,
+    webauthn: web::Data<Webauthn>,
+    attestation_cas: web::Data<AttestationCaList>,
+) -> impl Responder {
+    let user = auth.user;
+
+    let credentials = match repo.list_webauthn_credentials(&user.id).await {
+        Ok(credentials) => credentials,
+        Err(_) => return internal_error("Failed to load WebAuthn credentials"),
+    };
+    // 已注册的验证器不允许重复注册
+    let exclude_credentials = parse_passkeys(&credentials)
+        .iter()
+        .map(|(_, passkey)| passkey.cred_id().clone())
+        .collect();
+
+    let security_key = data.is_some_and(|data| data.security_key);
+    let result = if security_key {
+        if attestation_cas.is_empty() {
+            return ceremony_error("Security key registration is not enabled");
+        }
+        webauthn
+            .start_attested_passkey_registration(
+                user.id,
+                &user.email,
+                &user.username,
+                Some(exclude_credentials),
+                attestation_cas.get_ref().clone(),
+                Some(AuthenticatorAttachment::CrossPlatform),
+            )
+            .map(|(options, state)| (options, RegistrationState::SecurityKey(state)))
+    } else {
+        webauthn
+            .start_passkey_registration(
+                user.id,
+                &user.email,
+                &user.username,
+                Some(exclude_credentials),
+            )
+            .map(|(options, state)| (options, RegistrationState::Passkey(state)))
+    };
+    let (options, state) = match result {
+        Ok(result) => result,
+        Err(_) => return internal_error("Failed to start WebAuthn registration"),
+    };
+
+    let ceremony_id = match save_ceremony(
+        repo.get_ref(),
+        Some(&user.id),
+        CEREMONY_REGISTRATION,
+        &state,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "WebAuthn registration started".to_string(),
+        data: Some(WebauthnChallengeData {
+            ceremony_id,
+            options,
+        }),
+    })
+}
+
+#[post("/auth/webauthn/register/finish")]
+async fn register_finish(
+    req: HttpRequest,
+    auth: MfaSetupUser,
+    data: web::Json<WebauthnRegisterFinishSchema>,
+    repo: web::Data<dyn UserRepository>,
+    webauthn: web::Data<Webauthn>,
+) -> impl Responder {
+    let user = auth.user;
+
+    let state: RegistrationState = match load_ceremony(
+        repo.get_ref(),
+        &data.ceremony_id,
+        CEREMONY_REGISTRATION,
+        Some(&user.id),
+    )
+    .await
+    {
+        Ok(state) => state,
+        Err(resp) => return resp,
+    };
+
+    let result = match &state {
+        RegistrationState::Passkey(state) => webauthn
+            .finish_passkey_registration(&data.credential, state)
+            .map(|passkey| (passkey, false)),
+        RegistrationState::SecurityKey(state) => webauthn
+            .finish_attested_passkey_registration(&data.credential, state)
+            .map(|passkey| (Passkey::from(passkey), true)),
+    };
+    let (passkey, attested) = match result {
+        Ok(result) => result,
+        Err(_) => return ceremony_error("WebAuthn registration failed"),
+    };
+
+    let passkey_json = match serde_json::to_value(&passkey) {
+        Ok(value) => value,
+        Err(_) => return internal_error("Failed to save WebAuthn credential"),
+    };
+    let name = data.name.clone().unwrap_or_else(|| "Passkey".to_string());
+
+    let credential = match repo
+        .create_webauthn_credential(
+            &user.id,
+            &credential_key(passkey.cred_id()),
+            &passkey_json,
+            &name,
+            attested,
+        )
+        .await
+    {
+        Ok(credential) => credential,
+        Err(_) => {
+            return HttpResponse::Conflict().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "WebAuthn credential already registered".to_string(),
+                data: None,
+            });
+        }
+    };
+
+    // 首次注册第二因子：用户刚刚同时证明了密码和验证器，直接完成登录
+    let tokens = if auth.enrollment {
+        let amr = vec!["pwd".to_string(), key_amr(&credential), "mfa".to_string()];
+        let (access_token, refresh_token) = match issue_session_tokens(
+            repo.get_ref(),
+            &user,
+            SessionGrant::new(amr),
+            device_info(&req),
+        )
+        .await
+        {
+            Ok(tokens) => tokens,
+            Err(resp) => return resp,
+        };
+
+        Some(OtpSueecessData {
+            access_token,
+            refresh_token,
+            user: UserData {
+                id: user.id.to_string(),
+                email: user.email,
+                name: user.username,
+            },
+        })
+    } else {
+        None
+    };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "WebAuthn credential registered successfully".to_string(),
+        data: Some(WebauthnRegisteredData { credential, tokens }),
+    })
+}
+
+// 作为登录第二步：Bearer 为 /auth/login 返回的 mfa token
+#[post("/auth/webauthn/authenticate/start")]
+async fn authenticate_start(
+    auth: MfaPendingUser,
+    repo: web::Data<dyn UserRepository>,
+    webauthn: web::Data<Webauthn>,
+) -> impl Responder {
+    let user = auth.user;
+
+    let credentials = match repo.list_webauthn_credentials(&user.id).await {
+        Ok(credentials) => credentials,
+        Err(_) => return internal_error("Failed to load WebAuthn credentials"),
+    };
+    let passkeys: Vec<Passkey> = parse_passkeys(&credentials)
+        .into_iter()
+        .map(|(_, passkey)| passkey)
+        .collect();
+
+    if passkeys.is_empty() {
+        return ceremony_error("No WebAuthn credentials registered for this user");
+    }
+
+    let (options, state) = match webauthn.start_passkey_authentication(&passkeys) {
+        Ok(result) => result,
+        Err(_) => return internal_error("Failed to start WebAuthn authentication"),
+    };
+
+    let ceremony_id = match save_ceremony(
+        repo.get_ref(),
+        Some(&user.id),
+        CEREMONY_AUTHENTICATION,
+        &state,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "WebAuthn authentication started".to_string(),
+        data: Some(WebauthnChallengeData {
+            ceremony_id,
+            options,
+        }),
+    })
+}
+
+#[post("/auth/webauthn/authenticate/finish")]
+async fn authenticate_finish(
+    req: HttpRequest,
+    auth: MfaPendingUser,
+    data: web::Json<WebauthnAuthenticateFinishSchema>,
+    repo: web::Data<dyn UserRepository>,
+    webauthn: web::Data<Webauthn>,
+) -> impl Responder {
+    let user = auth.user;
+
+    let state: PasskeyAuthentication = match load_ceremony(
+        repo.get_ref(),
+        &data.ceremony_id,
+        CEREMONY_AUTHENTICATION,
+        Some(&user.id),
+    )
+    .await
+    {
+        Ok(state) => state,
+        Err(resp) => return resp,
+    };
+
+    let result = match webauthn.finish_passkey_authentication(&data.credential, &state) {
+        Ok(result) => result,
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(ApiResponse::<()> {
+                status: "error".to_string(),
+                message: "WebAuthn authentication failed".to_string(),
+                data: None,
+            });
+        }
+    };
+
+    let credentials = match repo.list_webauthn_credentials(&user.id).await {
+        Ok(credentials) => credentials,
+        Err(_) => return internal_error("Failed to load WebAuthn credentials"),
+    };
+    let Some((credential, mut passkey)) = parse_passkeys(&credentials)
+        .into_iter()
+        .find(|(credential, _)| credential.credential_id == credential_key(result.cred_id()))
+    else {
+        return ceremony_error("Unknown WebAuthn credential");
+    };
+
+    // 保存新的签名计数器，用于发现被克隆的验证器
+    passkey.update_credential(&result);
+    if let Err(resp) = save_passkey(repo.get_ref(), &credential, &passkey).await {
+        return resp;
+    }
+
+    let amr = vec!["pwd".to_string(), key_amr(&credential), "mfa".to_string()];
+    issue_tokens_response(
+        &req,
+        repo.get_ref(),
+        user,
+        amr,
+        "WebAuthn validated successfully",
+    )
+    .await
+}
+
+// 无密码登录：使用可发现凭证（passkey），不需要先提交邮箱和密码
+#[post("/auth/webauthn/login/start")]
+async fn login_start(
+    repo: web::Data<dyn UserRepository>,
+    webauthn: web::Data<Webauthn>,
+) -> impl Responder {
+    let (options, state) = match webauthn.start_discoverable_authentication() {
+        Ok(result) => result,
+        Err(_) => return internal_error("Failed to start WebAuthn login"),
+    };
+
+    let ceremony_id = match save_ceremony(repo.get_ref(), None, CEREMONY_DISCOVERABLE, &state).await
+    {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+
+    HttpResponse::Ok().json(ApiResponse {
+        status: "success".to_string(),
+        message: "WebAuthn login started".to_string(),
+        data: Some(WebauthnChallengeData {
+            ceremony_id,
+            options,
+        }),
+    })
+}
+
+#[post("/auth/webauthn/login/finish")]
+async fn login_finish(
+    req: HttpRequest,
+    data: web::Json<WebauthnAuthenticateFinishSchema>,
+    repo: web::Data<dyn UserRepository>,
+    webauthn: web::Data<Webauthn>,
+) -> impl Responder {
+    let state: DiscoverableAuthentication = match load_ceremony(
+        repo.get_ref(),
+        &data.ceremony_id,
+        CEREMONY_DISCOVERABLE,
+        None,
+    )
+    .await
+    {
+        Ok(state) => state,
+        Err(resp) => return resp,
+    };
+
+    let unauthorized = || {
+        HttpResponse::Unauthorized().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "WebAuthn authentication failed".to_string(),
+            data: None,
+        })
+    };
+
+    // 注册时使用 users.id 作为 user handle
+    let (user_id, cred_id) = match webauthn.identify_discoverable_authentication(&data.credential) {
+        Ok(identity) => identity,
+        Err(_) => return unauthorized(),
+    };
+
+    let user = match repo.get_user_by_id(&user_id).await {
+        Ok(user) => user,
+        Err(_) => return unauthorized(),
+    };
+
+    let credentials = match repo.list_webauthn_credentials(&user.id).await {
+        Ok(credentials) => credentials,
+        Err(_) => return internal_error("Failed to load WebAuthn credentials"),
+    };
+    let Some((credential, mut passkey)) = parse_passkeys(&credentials)
+        .into_iter()
+        .find(|(credential, _)| credential.credential_id == credential_key(cred_id))
+    else {
+        return unauthorized();
+    };
+
+    let result = match webauthn.finish_discoverable_authentication(
+        &data.credential,
+        state,
+        &[DiscoverableKey::from(&passkey)],
+    ) {
+        Ok(result) => result,
+        Err(_) => return unauthorized(),
+    };
+
+    passkey.update_credential(&result);
+    if let Err(resp) = save_passkey(repo.get_ref(), &credential, &passkey).await {
+        return resp;
+    }
+
+    // 要求了用户验证（PIN / 生物识别），passkey 本身即满足多因素
+    let amr = vec![key_amr(&credential), "mfa".to_string()];
+    issue_tokens_response(&req, repo.get_ref(), user, amr, "Login successful").await
+}
+
+#[get("/auth/webauthn/credentials")]
+async fn list_credentials(
+    auth: AuthenticatedUser,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    match repo.list_webauthn_credentials(&auth.user.id).await {
+        Ok(credentials) => HttpResponse::Ok().json(ApiResponse {
+            status: "success".to_string(),
+            message: "WebAuthn credentials fetched successfully".to_string(),
+            data: Some(json!({ "credentials": credentials })),
+        }),
+        Err(_) => internal_error("Failed to load WebAuthn credentials"),
+    }
+}
+
+#[delete("/auth/webauthn/credentials/{id}")]
+async fn delete_credential(
+    auth: AuthenticatedUser,
+    path: web::Path<Uuid>,
+    repo: web::Data<dyn UserRepository>,
+) -> impl Responder {
+    // 删除第二因子会降低账户安全级别，与新增因子一样要求最近完成过 MFA
+    if let Err(e) = auth.require_recent_mfa() {
+        return e.error_response();
+    }
+
+    match repo
+        .delete_webauthn_credential(&auth.user.id, &path.into_inner())
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(ApiResponse::<()> {
+            status: "success".to_string(),
+            message: "WebAuthn credential deleted successfully".to_string(),
+            data: None,
+        }),
+        Ok(false) => HttpResponse::NotFound().json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: "WebAuthn credential not found".to_string(),
+            data: None,
+        }),
+        Err(_) => internal_error("Failed to delete WebAuthn credential"),
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(register_start)
+        .service(register_finish)
+        .service(authenticate_start)
+        .service(authenticate_finish)
+        .service(login_start)
+        .service(login_finish)
+        .service(list_credentials)
+        .service(delete_credential);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test};
+    use serde_json::Value;
+    use sqlx::PgPool;
+    use webauthn_authenticator_rs::{
+        AuthenticatorBackend, WebauthnAuthenticator, softpasskey::SoftPasskey, softtoken::SoftToken,
+    };
+    use webauthn_rs::prelude::{CreationChallengeResponse, RequestChallengeResponse};
+
+    use super::*;
+    use crate::{
+        test_support::{create_user, repository},
+        utils::{generate_enrollment_token, generate_mfa_token, validate_access_token},
+    };
+
+    fn origin() -> Url {
+        Url::parse("http://localhost:5173").unwrap()
+    }
+
+    fn relying_party() -> Webauthn {
+        WebauthnBuilder::new("localhost", &origin())
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    fn post(uri: &str, token: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {token}")))
+    }
+
+    fn create_credential<T: AuthenticatorBackend>(
+        authenticator: &mut WebauthnAuthenticator<T>,
+        options: &Value,
+    ) -> Value {
+        let options: CreationChallengeResponse = serde_json::from_value(options.clone()).unwrap();
+        let credential = authenticator
+            .do_registration(origin(), options)
+            .expect("Registration ceremony failed");
+        serde_json::to_value(credential).unwrap()
+    }
+
+    fn get_assertion<T: AuthenticatorBackend>(
+        authenticator: &mut WebauthnAuthenticator<T>,
+        options: &Value,
+    ) -> Value {
+        let options: RequestChallengeResponse = serde_json::from_value(options.clone()).unwrap();
+        let credential = authenticator
+            .do_authentication(origin(), options)
+            .expect("Authentication ceremony failed");
+        serde_json::to_value(credential).unwrap()
+    }
+
+    async fn amr_of(body: &Value, repo: &dyn UserRepository) -> Vec<String> {
+        let token = body["access_token"]
+            .as_str()
+            .expect("No access token issued");
+        let claims = validate_access_token(token, repo)
+            .await
+            .expect("Issued access token is invalid");
+        claims.amr.unwrap_or_default()
+    }
+
+    #[sqlx::test]
+    async fn passkey_enrollment_and_second_factor_login(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(web::Data::new(relying_party()))
+                .app_data(web::Data::new(AttestationCaList::default()))
+                .configure(config),
+        )
+        .await;
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+        // 首次设置：使用 enrollment token 注册，完成后直接登录
+        let enrollment_token = generate_enrollment_token(&user.id).unwrap();
+        let req = post("/auth/webauthn/register/start", &enrollment_token).to_request();
+        let start: Value = test::call_and_read_body_json(&app, req).await;
+        let credential = create_credential(&mut authenticator, &start["options"]);
+        let req = post("/auth/webauthn/register/finish", &enrollment_token)
+            .set_json(json!({
+                "ceremony_id": start["ceremony_id"],
+                "name": "Laptop",
+                "credential": credential,
+            }))
+            .to_request();
+        let finish: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(finish["status"], "success");
+        assert_eq!(finish["credential"]["attested"], false);
+        assert_eq!(amr_of(&finish, repo.as_ref()).await, ["pwd", "swk", "mfa"]);
+
+        // 之后的登录：密码之后使用 passkey 作为第二因子
+        let mfa_token = generate_mfa_token(&user.id).unwrap();
+        let req = post("/auth/webauthn/authenticate/start", &mfa_token).to_request();
+        let start: Value = test::call_and_read_body_json(&app, req).await;
+        let assertion = get_assertion(&mut authenticator, &start["options"]);
+        let body = json!({ "ceremony_id": start["ceremony_id"], "credential": assertion });
+        let req = post("/auth/webauthn/authenticate/finish", &mfa_token)
+            .set_json(&body)
+            .to_request();
+        let login: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(login["status"], "success");
+        assert_eq!(amr_of(&login, repo.as_ref()).await, ["pwd", "swk", "mfa"]);
+
+        // challenge 只能使用一次
+        let req = post("/auth/webauthn/authenticate/finish", &mfa_token)
+            .set_json(&body)
+            .to_request();
+        let replay: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(replay["status"], "error");
+    }
+
+    #[sqlx::test]
+    async fn attested_security_key_is_recorded_as_hwk(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (token, ca) = SoftToken::new(true).unwrap();
+        let cas = AttestationCaList::try_from(ca.to_pem().unwrap().as_slice()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(web::Data::new(relying_party()))
+                .app_data(web::Data::new(cas))
+                .configure(config),
+        )
+        .await;
+        let mut authenticator = WebauthnAuthenticator::new(token);
+
+        let enrollment_token = generate_enrollment_token(&user.id).unwrap();
+        let req = post("/auth/webauthn/register/start", &enrollment_token)
+            .set_json(json!({ "security_key": true }))
+            .to_request();
+        let start: Value = test::call_and_read_body_json(&app, req).await;
+        let credential = create_credential(&mut authenticator, &start["options"]);
+        let req = post("/auth/webauthn/register/finish", &enrollment_token)
+            .set_json(json!({ "ceremony_id": start["ceremony_id"], "credential": credential }))
+            .to_request();
+        let finish: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(finish["status"], "success");
+        assert_eq!(finish["credential"]["attested"], true);
+        assert_eq!(amr_of(&finish, repo.as_ref()).await, ["pwd", "hwk", "mfa"]);
+    }
+
+    #[sqlx::test]
+    async fn security_key_from_untrusted_vendor_is_rejected(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let (token, _) = SoftToken::new(true).unwrap();
+        let (_, other_ca) = SoftToken::new(true).unwrap();
+        let cas = AttestationCaList::try_from(other_ca.to_pem().unwrap().as_slice()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(web::Data::new(relying_party()))
+                .app_data(web::Data::new(cas))
+                .configure(config),
+        )
+        .await;
+        let mut authenticator = WebauthnAuthenticator::new(token);
+
+        let enrollment_token = generate_enrollment_token(&user.id).unwrap();
+        let req = post("/auth/webauthn/register/start", &enrollment_token)
+            .set_json(json!({ "security_key": true }))
+            .to_request();
+        let start: Value = test::call_and_read_body_json(&app, req).await;
+        let credential = create_credential(&mut authenticator, &start["options"]);
+        let req = post("/auth/webauthn/register/finish", &enrollment_token)
+            .set_json(json!({ "ceremony_id": start["ceremony_id"], "credential": credential }))
+            .to_request();
+        let finish: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(finish["status"], "error");
+        assert!(
+            repo.list_webauthn_credentials(&user.id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[sqlx::test]
+    async fn security_key_registration_requires_attestation_cas(pool: PgPool) {
+        let repo = repository(pool);
+        let user = create_user(repo.as_ref(), "alice").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repo.clone()))
+                .app_data(web::Data::new(relying_party()))
+                .app_data(web::Data::new(AttestationCaList::default()))
+                .configure(config),
+        )
+        .await;
+
+        let enrollment_token = generate_enrollment_token(&user.id).unwrap();
+        let req = post("/auth/webauthn/register/start", &enrollment_token)
+            .set_json(json!({ "security_key": true }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+}
@@ -37,7 +37,6 @@ export async function login(credentials: LoginRequest): Promise<LoginResponse> {
 
 export async function verifyMfa(data: MfaRequest): Promise<MfaResponse> {
   const body = {
-    user_id: "", // Placeholder, actual user_id should be extracted from mfa_token if needed
     token: data.mfa_code,
   };
   const response = await fetch(`${API_BASE_URL}/api/auth/otp/validate`, {
@@ -51,3 +50,83 @@ export async function verifyMfa(data: MfaRequest): Promise<MfaResponse> {
   const responseData: MfaResponse = await response.json();
   return responseData;
 }
+
+interface OAuthApproveResponse {
+  status: string;
+  message?: string;
+  redirect_to?: string;
+}
+
+// 第三方应用通过 /oauth/authorize 跳转过来时，登录完成后同意授权并跳回应用
+export async function approveOAuthRequest(
+  requestId: string,
+  accessToken: string,
+): Promise<OAuthApproveResponse> {
+  const response = await fetch(`${API_BASE_URL}/api/oauth/requests/${requestId}/approve`, {
+    method: "POST",
+    headers: {
+      Authorization: `Bearer ${accessToken}`,
+    },
+  });
+  const responseData: OAuthApproveResponse = await response.json();
+  return responseData;
+}
+
+interface DeviceApproveResponse {
+  status: string;
+  message?: string;
+}
+
+// CLI 设备授权：登录完成后用 access token 同意设备上显示的代码
+export async function approveDevice(
+  userCode: string,
+  accessToken: string,
+): Promise<DeviceApproveResponse> {
+  const response = await fetch(`${API_BASE_URL}/api/oauth/device/approve`, {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+      Authorization: `Bearer ${accessToken}`,
+    },
+    body: JSON.stringify({ user_code: userCode }),
+  });
+  const responseData: DeviceApproveResponse = await response.json();
+  return responseData;
+}
+
+interface PasswordResetResponse {
+  status: string;
+  message?: string;
+  // 新密码不满足密码策略时的具体原因
+  violations?: string[];
+}
+
+// 找回密码：无论邮箱是否注册都返回成功
+export async function forgotPassword(email: string): Promise<PasswordResetResponse> {
+  const response = await fetch(`${API_BASE_URL}/api/auth/password/forgot`, {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({ email }),
+  });
+  const responseData: PasswordResetResponse = await response.json();
+  return responseData;
+}
+
+// 用邮件中的重置令牌设置新密码，启用了 OTP 的账户还需要验证码
+export async function resetPassword(
+  token: string,
+  newPassword: string,
+  otpToken?: string,
+): Promise<PasswordResetResponse> {
+  const response = await fetch(`${API_BASE_URL}/api/auth/password/reset`, {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({ token, new_password: newPassword, otp_token: otpToken || undefined }),
+  });
+  const responseData: PasswordResetResponse = await response.json();
+  return responseData;
+}